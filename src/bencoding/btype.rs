@@ -119,6 +119,17 @@ impl BDict {
             None => None,
         }
     }
+
+    /// Returns the value without downcasting, e.g. to re-encode it as is.
+    pub fn get_value(&self, key: &str) -> Option<&dyn BType> {
+        self.0
+            .get(&BString::new(&to_vec(key.as_bytes())))
+            .map(|value| value.as_ref())
+    }
+
+    pub fn iter(&self) -> std::collections::btree_map::Iter<'_, BString, Box<dyn BType>> {
+        self.0.iter()
+    }
 }
 
 impl BType for BDict {
@@ -7,6 +7,8 @@ use std::str;
 
 use crate::utility::to_vec;
 
+const MAX_DEPTH: usize = 64;
+
 pub fn decode(input: &Vec<u8>) -> (Box<dyn BType>, u32) {
     let cur = input[0];
     if cur.is_ascii_digit() {
@@ -31,6 +33,56 @@ pub fn decode(input: &Vec<u8>) -> (Box<dyn BType>, u32) {
     }
 }
 
+/// Decodes untrusted input, returns None instead of panicking when the
+/// input is not valid bencoding. Trailing bytes are ignored.
+pub fn try_decode(input: &[u8]) -> Option<(Box<dyn BType>, u32)> {
+    let length = validate(input, 0)?;
+    Some(decode(&to_vec(&input[..length])))
+}
+
+fn validate(input: &[u8], depth: usize) -> Option<usize> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+
+    match *input.first()? {
+        b'0'..=b'9' => {
+            let delimiter_pos = input.iter().position(|x| x == &b':')?;
+            let length: usize = str::from_utf8(&input[..delimiter_pos]).ok()?.parse().ok()?;
+            let end = (delimiter_pos + 1).checked_add(length)?;
+            if end > input.len() {
+                None
+            } else {
+                Some(end)
+            }
+        }
+        b'i' => {
+            let delimiter_pos = input.iter().position(|x| x == &b'e')?;
+            str::from_utf8(&input[1..delimiter_pos])
+                .ok()?
+                .parse::<i64>()
+                .ok()?;
+            Some(delimiter_pos + 1)
+        }
+        kind @ b'l' | kind @ b'd' => {
+            let mut pos = 1;
+            loop {
+                if *input.get(pos)? == b'e' {
+                    return Some(pos + 1);
+                }
+                if kind == b'd' {
+                    if !input[pos].is_ascii_digit() {
+                        return None;
+                    }
+                    pos += validate(&input[pos..], depth + 1)?;
+                }
+                pos += validate(&input[pos..], depth + 1)?;
+            }
+        }
+        _ => None,
+    }
+}
+
 fn decode_string(input: &Vec<u8>) -> (BString, u32) {
     let mut delimiter_pos = input.iter().position(|x| x == &b':').unwrap();
     let length: usize = str::from_utf8(&input[0..delimiter_pos])
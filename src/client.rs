@@ -1,10 +1,14 @@
 use crate::message;
 use crate::message::MessageId;
+use crate::peer_pool::PeerPool;
+use crate::pex::{self, PexMessage, PexState};
 use crate::tracker::Peer;
 use crate::utility::encode_hex;
 
+use std::collections::BTreeMap;
 use std::io::prelude::*;
 use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
 
 #[allow(non_upper_case_globals)]
 const pstr: &str = "BitTorrent protocol";
@@ -12,8 +16,12 @@ const pstr: &str = "BitTorrent protocol";
 pub enum MessageReturn {
     Have(u32),
     Piece((u32, u32, Vec<u8>)),
+    Extended((u8, Vec<u8>)),
 }
 
+/// A connection to one peer. Once a peer pool is set with `set_peer_pool`
+/// the connection exchanges peers with the remote over ut_pex while
+/// messages are received.
 #[derive(Debug)]
 pub struct Client {
     info_hash: Vec<u8>,
@@ -22,6 +30,25 @@ pub struct Client {
     choked: bool,
     connection: TcpStream,
     bitfield: Option<Vec<u8>>,
+    supports_extensions: bool,
+    extensions: BTreeMap<String, u8>,
+    pex: Option<Arc<PexLink>>,
+}
+
+/// The peer pool a connection and its clones share, with the ut_pex state
+/// of the remote peer. The peer counts as connected until the last clone
+/// of the connection is dropped.
+#[derive(Debug)]
+struct PexLink {
+    pool: Arc<Mutex<PeerPool>>,
+    peer: Peer,
+    state: Mutex<PexState>,
+}
+
+impl Drop for PexLink {
+    fn drop(&mut self) {
+        self.pool.lock().unwrap().set_connected(&self.peer, false);
+    }
 }
 
 impl Client {
@@ -38,11 +65,15 @@ impl Client {
             peer,
             connection,
             bitfield: None,
+            supports_extensions: false,
+            extensions: BTreeMap::new(),
+            pex: None,
         };
 
         match client.handshake() {
             Ok(_) => {
                 client.bitfield = client.receive_bitfield();
+                client.send_extension_handshake();
                 Some(client)
             }
             Err(error) => {
@@ -52,6 +83,19 @@ impl Client {
         }
     }
 
+    /// Shares the torrent's peer pool with this connection, peers learned
+    /// over ut_pex go into it and its connected peers are sent back to the
+    /// peer every `pex::PEX_INTERVAL`, checked whenever a message is
+    /// received. Clones made with `try_clone` share the pool.
+    pub fn set_peer_pool(&mut self, pool: Arc<Mutex<PeerPool>>) {
+        pool.lock().unwrap().set_connected(&self.peer, true);
+        self.pex = Some(Arc::new(PexLink {
+            pool,
+            peer: self.peer.clone(),
+            state: Mutex::new(PexState::new()),
+        }));
+    }
+
     pub fn is_choked(&mut self) -> bool {
         self.choked
     }
@@ -64,6 +108,7 @@ impl Client {
     }
 
     pub fn receive_message(&mut self) -> (MessageId, Option<MessageReturn>) {
+        self.maybe_send_pex();
         let mut length = [0u8; 4];
         self.connection.read(&mut length).unwrap();
         let length = u32::from_be_bytes(length);
@@ -90,6 +135,27 @@ impl Client {
                 MessageId::Piece,
                 Some(MessageReturn::Piece(self.receive_piece(length))),
             ),
+            MessageId::Extended => {
+                let (extended_id, payload) = match self.receive_extended(length) {
+                    Some(extended) => extended,
+                    None => return (MessageId::Invalid, None),
+                };
+                if extended_id == 0 {
+                    self.extensions = message::parse_extension_handshake(&payload);
+                } else if extended_id == pex::LOCAL_PEX_ID {
+                    if let Some(pex) = &self.pex {
+                        let mut pool = pex.pool.lock().unwrap();
+                        pex.state
+                            .lock()
+                            .unwrap()
+                            .handle_message(&payload, &mut pool);
+                    }
+                }
+                (
+                    MessageId::Extended,
+                    Some(MessageReturn::Extended((extended_id, payload))),
+                )
+            }
             _ => (MessageId::Invalid, None),
         }
     }
@@ -126,6 +192,59 @@ impl Client {
             .unwrap()
     }
 
+    pub fn supports_extensions(&self) -> bool {
+        self.supports_extensions
+    }
+
+    pub fn supports_pex(&self) -> bool {
+        self.extensions.contains_key(pex::EXTENSION_NAME)
+    }
+
+    pub fn send_extension_handshake(&mut self) {
+        if !self.supports_extensions {
+            return;
+        }
+        let handshake = message::extension_handshake(&[(pex::EXTENSION_NAME, pex::LOCAL_PEX_ID)]);
+        self.connection
+            .write_all(&message::serialize_extended(0, handshake))
+            .unwrap();
+    }
+
+    pub fn send_pex(&mut self, pex_message: &PexMessage) {
+        let pex_id = match self.extensions.get(pex::EXTENSION_NAME) {
+            Some(&pex_id) if pex_id != 0 => pex_id,
+            _ => return,
+        };
+        self.connection
+            .write_all(&message::serialize_extended(pex_id, pex_message.encode()))
+            .unwrap();
+    }
+
+    /// Sends the connected peers of the pool when the PEX interval has
+    /// passed since the last message.
+    pub fn maybe_send_pex(&mut self) {
+        if !self.supports_pex() {
+            return;
+        }
+        let pex_message = match &self.pex {
+            Some(pex) => {
+                let connected: Vec<(Peer, u8)> = pex
+                    .pool
+                    .lock()
+                    .unwrap()
+                    .get_connected()
+                    .into_iter()
+                    .filter(|(peer, _)| *peer != self.peer)
+                    .collect();
+                pex.state.lock().unwrap().next_message(&connected)
+            }
+            None => return,
+        };
+        if let Some(pex_message) = pex_message {
+            self.send_pex(&pex_message);
+        }
+    }
+
     pub fn try_clone(&self) -> Self {
         Client {
             info_hash: self.info_hash.clone(),
@@ -134,6 +253,9 @@ impl Client {
             choked: self.choked.clone(),
             connection: self.connection.try_clone().unwrap(),
             bitfield: self.bitfield.clone(),
+            supports_extensions: self.supports_extensions,
+            extensions: self.extensions.clone(),
+            pex: self.pex.clone(),
         }
     }
 
@@ -145,6 +267,7 @@ impl Client {
         &mut self.connection.read(&mut response).unwrap();
 
         let received_pstr_len = response[0];
+        self.supports_extensions = response[received_pstr_len as usize + 1 + 5] & 0x10 != 0;
         let offset = (received_pstr_len + 9) as usize;
         let received_info_hash = response[offset..offset + 20].to_vec();
 
@@ -161,7 +284,9 @@ impl Client {
 
             message.push(pstr.len() as u8);
             message.append(&mut pstr.as_bytes().to_vec());
-            message.append(&mut vec![0; 8]); //reserved
+            let mut reserved = vec![0; 8];
+            reserved[5] |= 0x10; //extension protocol
+            message.append(&mut reserved);
             message.append(&mut client.info_hash.to_vec());
             message.append(&mut client.peer_id.as_bytes().to_vec());
             message
@@ -223,6 +348,18 @@ impl Client {
         (index, begin, block)
     }
 
+    /// None when the message is too short to hold an extended id.
+    fn receive_extended(&mut self, length: u32) -> Option<(u8, Vec<u8>)> {
+        if length < 2 {
+            return None;
+        }
+        let mut extended_id = [0; 1];
+        self.connection.read_exact(&mut extended_id).unwrap();
+        let mut payload = vec![0u8; (length - 2) as usize];
+        self.connection.read_exact(&mut payload).unwrap();
+        Some((extended_id[0], payload))
+    }
+
     fn set_choked(&mut self, state: bool) {
         self.choked = state;
     }
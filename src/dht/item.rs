@@ -0,0 +1,197 @@
+use super::routing::NodeId;
+
+use crate::bencoding;
+use crate::utility::hash;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+
+use std::convert::TryInto;
+use std::fmt;
+
+pub const MAX_VALUE_SIZE: usize = 1000;
+pub const MAX_SALT_SIZE: usize = 64;
+
+pub const ERROR_MESSAGE_TOO_BIG: i64 = 205;
+pub const ERROR_INVALID_SIGNATURE: i64 = 206;
+pub const ERROR_SALT_TOO_BIG: i64 = 207;
+pub const ERROR_CAS_MISMATCH: i64 = 301;
+pub const ERROR_SEQ_TOO_LOW: i64 = 302;
+
+#[derive(Debug, PartialEq)]
+pub enum ItemError {
+    ValueTooBig,
+    SaltTooBig,
+    InvalidValue,
+    InvalidSignature,
+    CasMismatch,
+    SeqTooLow,
+    NoNodes,
+}
+
+impl ItemError {
+    pub fn code(&self) -> i64 {
+        match self {
+            ItemError::ValueTooBig => ERROR_MESSAGE_TOO_BIG,
+            ItemError::SaltTooBig => ERROR_SALT_TOO_BIG,
+            ItemError::InvalidSignature => ERROR_INVALID_SIGNATURE,
+            ItemError::CasMismatch => ERROR_CAS_MISMATCH,
+            ItemError::SeqTooLow => ERROR_SEQ_TOO_LOW,
+            ItemError::InvalidValue | ItemError::NoNodes => super::krpc::ERROR_PROTOCOL,
+        }
+    }
+}
+
+impl fmt::Display for ItemError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            ItemError::ValueTooBig => "Message (v field) too big",
+            ItemError::SaltTooBig => "Salt (salt field) too big",
+            ItemError::InvalidValue => "Value is not valid bencoding",
+            ItemError::InvalidSignature => "Invalid signature",
+            ItemError::CasMismatch => "CAS hash mismatched",
+            ItemError::SeqTooLow => "Sequence number less than current",
+            ItemError::NoNodes => "No node accepted the item",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+/// Target of an immutable item is the SHA-1 of its bencoded value.
+pub fn immutable_target(value: &[u8]) -> NodeId {
+    NodeId::from_slice(&hash(value.to_vec())).unwrap()
+}
+
+/// Target of a mutable item is the SHA-1 of its public key and salt.
+pub fn mutable_target(public_key: &[u8; 32], salt: &[u8]) -> NodeId {
+    let mut input = public_key.to_vec();
+    input.extend_from_slice(salt);
+    NodeId::from_slice(&hash(input)).unwrap()
+}
+
+pub fn validate_value(value: &[u8]) -> Result<(), ItemError> {
+    if value.len() > MAX_VALUE_SIZE {
+        return Err(ItemError::ValueTooBig);
+    }
+    match bencoding::try_decode(value) {
+        Some((_, length)) if length as usize == value.len() => Ok(()),
+        _ => Err(ItemError::InvalidValue),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MutableItem {
+    public_key: [u8; 32],
+    salt: Vec<u8>,
+    seq: i64,
+    value: Vec<u8>,
+    signature: [u8; 64],
+}
+
+impl MutableItem {
+    /// Signs a bencoded `value` with the given key.
+    pub fn sign(
+        signing_key: &SigningKey,
+        salt: &[u8],
+        seq: i64,
+        value: Vec<u8>,
+    ) -> Result<MutableItem, ItemError> {
+        validate_value(&value)?;
+        if salt.len() > MAX_SALT_SIZE {
+            return Err(ItemError::SaltTooBig);
+        }
+
+        let signature = signing_key.sign(&signable(salt, seq, &value));
+        Ok(MutableItem {
+            public_key: signing_key.verifying_key().to_bytes(),
+            salt: salt.to_vec(),
+            seq,
+            value,
+            signature: signature.to_bytes(),
+        })
+    }
+
+    /// Builds an item received from the network, checking its signature.
+    pub fn from_parts(
+        public_key: &[u8],
+        salt: &[u8],
+        seq: i64,
+        value: Vec<u8>,
+        signature: &[u8],
+    ) -> Result<MutableItem, ItemError> {
+        validate_value(&value)?;
+        if salt.len() > MAX_SALT_SIZE {
+            return Err(ItemError::SaltTooBig);
+        }
+
+        let item = MutableItem {
+            public_key: public_key
+                .try_into()
+                .map_err(|_| ItemError::InvalidSignature)?,
+            salt: salt.to_vec(),
+            seq,
+            value,
+            signature: signature
+                .try_into()
+                .map_err(|_| ItemError::InvalidSignature)?,
+        };
+        if item.verify() {
+            Ok(item)
+        } else {
+            Err(ItemError::InvalidSignature)
+        }
+    }
+
+    pub fn verify(&self) -> bool {
+        let public_key = match VerifyingKey::from_bytes(&self.public_key) {
+            Ok(public_key) => public_key,
+            Err(_) => return false,
+        };
+        let signature = Signature::from_bytes(&self.signature);
+        public_key
+            .verify(&signable(&self.salt, self.seq, &self.value), &signature)
+            .is_ok()
+    }
+
+    pub fn target(&self) -> NodeId {
+        mutable_target(&self.public_key, &self.salt)
+    }
+
+    pub fn get_public_key(&self) -> &[u8; 32] {
+        &self.public_key
+    }
+
+    pub fn get_salt(&self) -> &[u8] {
+        &self.salt
+    }
+
+    pub fn get_seq(&self) -> i64 {
+        self.seq
+    }
+
+    pub fn get_value(&self) -> &[u8] {
+        &self.value
+    }
+
+    pub fn get_signature(&self) -> &[u8; 64] {
+        &self.signature
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Item {
+    Immutable(Vec<u8>),
+    Mutable(MutableItem),
+}
+
+/// The buffer that is signed: the salt (if any), seq and value as they
+/// would appear in a bencoded dictionary.
+fn signable(salt: &[u8], seq: i64, value: &[u8]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    if !salt.is_empty() {
+        buffer.extend_from_slice(format!("4:salt{}:", salt.len()).as_bytes());
+        buffer.extend_from_slice(salt);
+    }
+    buffer.extend_from_slice(format!("3:seqi{}e1:v", seq).as_bytes());
+    buffer.extend_from_slice(value);
+    buffer
+}
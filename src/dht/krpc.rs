@@ -0,0 +1,375 @@
+use super::routing::{encode_compact_nodes, parse_compact_nodes, NodeId, NodeInfo};
+
+use crate::bencoding;
+use crate::bencoding::{BDict, BInt, BList, BString, BType};
+use crate::tracker::{parse_compact_peers, Peer};
+use crate::utility::to_vec;
+
+use std::collections::BTreeMap;
+
+pub const ERROR_GENERIC: i64 = 201;
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+#[derive(Clone, Debug)]
+pub enum Query {
+    Ping,
+    FindNode(NodeId),
+    GetPeers(NodeId),
+    AnnouncePeer {
+        info_hash: NodeId,
+        port: u16,
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+    Get {
+        target: NodeId,
+        seq: Option<i64>,
+    },
+    Put(PutArguments),
+}
+
+/// Arguments of a BEP 44 `put`, `value` is the bencoded item.
+#[derive(Clone, Debug)]
+pub struct PutArguments {
+    pub token: Vec<u8>,
+    pub value: Vec<u8>,
+    pub key: Option<Vec<u8>>,
+    pub signature: Option<Vec<u8>>,
+    pub seq: Option<i64>,
+    pub salt: Vec<u8>,
+    pub cas: Option<i64>,
+}
+
+impl Query {
+    fn method(&self) -> &'static str {
+        match self {
+            Query::Ping => "ping",
+            Query::FindNode(_) => "find_node",
+            Query::GetPeers(_) => "get_peers",
+            Query::AnnouncePeer { .. } => "announce_peer",
+            Query::Get { .. } => "get",
+            Query::Put(_) => "put",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Response {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>,
+    pub values: Vec<Peer>,
+    pub token: Option<Vec<u8>>,
+    pub value: Option<Vec<u8>>,
+    pub key: Option<Vec<u8>>,
+    pub signature: Option<Vec<u8>>,
+    pub seq: Option<i64>,
+}
+
+impl Response {
+    pub fn new(id: NodeId) -> Response {
+        Response {
+            id,
+            nodes: Vec::new(),
+            values: Vec::new(),
+            token: None,
+            value: None,
+            key: None,
+            signature: None,
+            seq: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Body {
+    Query { id: NodeId, query: Query },
+    Response(Response),
+    Error(i64, String),
+}
+
+#[derive(Clone, Debug)]
+pub struct Message {
+    pub transaction_id: Vec<u8>,
+    pub body: Body,
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut message = BDict::new(BTreeMap::new());
+        message.insert(key("t"), bytes(&self.transaction_id));
+
+        match &self.body {
+            Body::Query { id, query } => {
+                let mut arguments = BDict::new(BTreeMap::new());
+                arguments.insert(key("id"), bytes(id.as_bytes()));
+                match query {
+                    Query::Ping => (),
+                    Query::FindNode(target) => {
+                        arguments.insert(key("target"), bytes(target.as_bytes()))
+                    }
+                    Query::GetPeers(info_hash) => {
+                        arguments.insert(key("info_hash"), bytes(info_hash.as_bytes()))
+                    }
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        implied_port,
+                        token,
+                    } => {
+                        arguments.insert(key("info_hash"), bytes(info_hash.as_bytes()));
+                        arguments.insert(key("port"), Box::new(BInt::new(*port as i64)));
+                        arguments.insert(
+                            key("implied_port"),
+                            Box::new(BInt::new(*implied_port as i64)),
+                        );
+                        arguments.insert(key("token"), bytes(token));
+                    }
+                    Query::Get { target, seq } => {
+                        arguments.insert(key("target"), bytes(target.as_bytes()));
+                        if let Some(seq) = seq {
+                            arguments.insert(key("seq"), Box::new(BInt::new(*seq)));
+                        }
+                    }
+                    Query::Put(put) => {
+                        arguments.insert(key("token"), bytes(&put.token));
+                        arguments.insert(key("v"), raw(&put.value));
+                        if let Some(public_key) = &put.key {
+                            arguments.insert(key("k"), bytes(public_key));
+                        }
+                        if let Some(signature) = &put.signature {
+                            arguments.insert(key("sig"), bytes(signature));
+                        }
+                        if let Some(seq) = put.seq {
+                            arguments.insert(key("seq"), Box::new(BInt::new(seq)));
+                        }
+                        if !put.salt.is_empty() {
+                            arguments.insert(key("salt"), bytes(&put.salt));
+                        }
+                        if let Some(cas) = put.cas {
+                            arguments.insert(key("cas"), Box::new(BInt::new(cas)));
+                        }
+                    }
+                }
+                message.insert(key("y"), bytes(b"q"));
+                message.insert(key("q"), bytes(query.method().as_bytes()));
+                message.insert(key("a"), Box::new(arguments));
+            }
+            Body::Response(response) => {
+                let mut values = BDict::new(BTreeMap::new());
+                values.insert(key("id"), bytes(response.id.as_bytes()));
+                if !response.nodes.is_empty() {
+                    values.insert(key("nodes"), bytes(&encode_compact_nodes(&response.nodes)));
+                }
+                if !response.values.is_empty() {
+                    let mut peers = BList::new(Vec::new());
+                    for peer in &response.values {
+                        peers.push(bytes(&peer.to_compact()));
+                    }
+                    values.insert(key("values"), Box::new(peers));
+                }
+                if let Some(token) = &response.token {
+                    values.insert(key("token"), bytes(token));
+                }
+                if let Some(value) = &response.value {
+                    values.insert(key("v"), raw(value));
+                }
+                if let Some(public_key) = &response.key {
+                    values.insert(key("k"), bytes(public_key));
+                }
+                if let Some(signature) = &response.signature {
+                    values.insert(key("sig"), bytes(signature));
+                }
+                if let Some(seq) = response.seq {
+                    values.insert(key("seq"), Box::new(BInt::new(seq)));
+                }
+                message.insert(key("y"), bytes(b"r"));
+                message.insert(key("r"), Box::new(values));
+            }
+            Body::Error(code, reason) => {
+                let error = BList::new(vec![Box::new(BInt::new(*code)), bytes(reason.as_bytes())]);
+                message.insert(key("y"), bytes(b"e"));
+                message.insert(key("e"), Box::new(error));
+            }
+        }
+
+        message.encode()
+    }
+
+    /// Decodes a KRPC packet. Bad queries that still carry a transaction
+    /// id are reported with it so an error can be sent back.
+    pub fn decode(packet: &[u8]) -> Result<Message, DecodeError> {
+        let (message, _) = bencoding::try_decode(packet).ok_or(DecodeError::Invalid)?;
+        let message = message
+            .as_any()
+            .downcast_ref::<BDict>()
+            .ok_or(DecodeError::Invalid)?;
+        let transaction_id = message
+            .get::<BString>("t")
+            .ok_or(DecodeError::Invalid)?
+            .to_vec();
+        let kind = message
+            .get::<BString>("y")
+            .ok_or(DecodeError::Invalid)?
+            .to_vec();
+
+        let body = match &kind[..] {
+            b"q" => {
+                let method = message.get::<BString>("q");
+                let arguments = message.get::<BDict>("a");
+                match decode_query(method, arguments) {
+                    Ok(body) => body,
+                    Err(QueryError::MethodUnknown) => {
+                        return Err(DecodeError::MethodUnknown(transaction_id))
+                    }
+                    Err(QueryError::Protocol) => return Err(DecodeError::Protocol(transaction_id)),
+                }
+            }
+            b"r" => {
+                let values = message.get::<BDict>("r").ok_or(DecodeError::Invalid)?;
+                Body::Response(decode_response(values).ok_or(DecodeError::Invalid)?)
+            }
+            b"e" => {
+                let error = message.get::<BList>("e").ok_or(DecodeError::Invalid)?.get();
+                let code = match error.first() {
+                    Some(code) => code.as_any().downcast_ref::<BInt>().map(|c| c.into_int()),
+                    None => None,
+                };
+                let reason = match error.get(1) {
+                    Some(reason) => reason
+                        .as_any()
+                        .downcast_ref::<BString>()
+                        .and_then(|r| r.into_string()),
+                    None => None,
+                };
+                Body::Error(code.unwrap_or(ERROR_GENERIC), reason.unwrap_or_default())
+            }
+            _ => return Err(DecodeError::Invalid),
+        };
+
+        Ok(Message {
+            transaction_id,
+            body,
+        })
+    }
+}
+
+/// Why a packet could not be decoded, queries for unknown methods and
+/// queries with missing or invalid arguments keep their transaction id.
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    Invalid,
+    MethodUnknown(Vec<u8>),
+    Protocol(Vec<u8>),
+}
+
+impl DecodeError {
+    /// The error message to answer with, None when there is nobody to
+    /// answer.
+    pub fn to_message(&self) -> Option<Message> {
+        let (transaction_id, code, reason) = match self {
+            DecodeError::Invalid => return None,
+            DecodeError::MethodUnknown(transaction_id) => {
+                (transaction_id, ERROR_METHOD_UNKNOWN, "Method Unknown")
+            }
+            DecodeError::Protocol(transaction_id) => {
+                (transaction_id, ERROR_PROTOCOL, "Protocol Error")
+            }
+        };
+        Some(Message {
+            transaction_id: transaction_id.clone(),
+            body: Body::Error(code, reason.to_string()),
+        })
+    }
+}
+
+enum QueryError {
+    MethodUnknown,
+    Protocol,
+}
+
+fn decode_query(method: Option<&BString>, arguments: Option<&BDict>) -> Result<Body, QueryError> {
+    let method = method.ok_or(QueryError::Protocol)?;
+    match &method[..] {
+        b"ping" | b"find_node" | b"get_peers" | b"announce_peer" | b"get" | b"put" => (),
+        _ => return Err(QueryError::MethodUnknown),
+    }
+    decode_arguments(method, arguments).ok_or(QueryError::Protocol)
+}
+
+fn decode_arguments(method: &BString, arguments: Option<&BDict>) -> Option<Body> {
+    let arguments = arguments?;
+    let id = NodeId::from_slice(arguments.get::<BString>("id")?)?;
+    let query = match &method[..] {
+        b"ping" => Query::Ping,
+        b"find_node" => Query::FindNode(NodeId::from_slice(arguments.get::<BString>("target")?)?),
+        b"get_peers" => {
+            Query::GetPeers(NodeId::from_slice(arguments.get::<BString>("info_hash")?)?)
+        }
+        b"announce_peer" => Query::AnnouncePeer {
+            info_hash: NodeId::from_slice(arguments.get::<BString>("info_hash")?)?,
+            port: arguments.get::<BInt>("port")?.into_int() as u16,
+            implied_port: match arguments.get::<BInt>("implied_port") {
+                Some(implied_port) => implied_port.into_int() != 0,
+                None => false,
+            },
+            token: arguments.get::<BString>("token")?.to_vec(),
+        },
+        b"get" => Query::Get {
+            target: NodeId::from_slice(arguments.get::<BString>("target")?)?,
+            seq: arguments.get::<BInt>("seq").map(|seq| seq.into_int()),
+        },
+        b"put" => Query::Put(PutArguments {
+            token: arguments.get::<BString>("token")?.to_vec(),
+            value: arguments.get_value("v")?.encode(),
+            key: arguments.get::<BString>("k").map(|k| k.to_vec()),
+            signature: arguments.get::<BString>("sig").map(|sig| sig.to_vec()),
+            seq: arguments.get::<BInt>("seq").map(|seq| seq.into_int()),
+            salt: match arguments.get::<BString>("salt") {
+                Some(salt) => salt.to_vec(),
+                None => Vec::new(),
+            },
+            cas: arguments.get::<BInt>("cas").map(|cas| cas.into_int()),
+        }),
+        _ => return None,
+    };
+    Some(Body::Query { id, query })
+}
+
+fn decode_response(values: &BDict) -> Option<Response> {
+    let mut response = Response::new(NodeId::from_slice(values.get::<BString>("id")?)?);
+    if let Some(nodes) = values.get::<BString>("nodes") {
+        response.nodes = parse_compact_nodes(nodes);
+    }
+    if let Some(peers) = values.get::<BList>("values") {
+        for peer in peers.get() {
+            if let Some(peer) = peer.as_any().downcast_ref::<BString>() {
+                if peer.len() == 6 {
+                    response
+                        .values
+                        .append(&mut parse_compact_peers(peer, false));
+                }
+            }
+        }
+    }
+    response.token = values.get::<BString>("token").map(|token| token.to_vec());
+    response.value = values.get_value("v").map(|value| value.encode());
+    response.key = values.get::<BString>("k").map(|k| k.to_vec());
+    response.signature = values.get::<BString>("sig").map(|sig| sig.to_vec());
+    response.seq = values.get::<BInt>("seq").map(|seq| seq.into_int());
+    Some(response)
+}
+
+fn key(name: &str) -> BString {
+    BString::new(&to_vec(name.as_bytes()))
+}
+
+/// Wraps an already bencoded value, callers validate it beforehand.
+fn raw(value: &[u8]) -> Box<dyn BType> {
+    let (value, _) = bencoding::decode(&to_vec(value));
+    value
+}
+
+fn bytes(data: &[u8]) -> Box<dyn BType> {
+    Box::new(BString::new(&to_vec(data)))
+}
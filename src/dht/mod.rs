@@ -0,0 +1,1328 @@
+mod item;
+mod krpc;
+mod routing;
+
+pub use item::{immutable_target, mutable_target, validate_value, Item, ItemError, MutableItem};
+pub use routing::{NodeId, NodeInfo, RoutingTable, K};
+
+use krpc::{Body, Message, PutArguments, Query, Response};
+use routing::{encode_compact_nodes, parse_compact_nodes};
+
+use crate::bencoding;
+use crate::bencoding::{BDict, BString, BType};
+use crate::tracker::Peer;
+use crate::utility::{hash, to_vec};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_PORT: u16 = 6881;
+pub const DEFAULT_BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "router.utorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+];
+
+/// Number of queries in flight at once during a lookup.
+const ALPHA: usize = 3;
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+const MAX_VALUES: usize = 50;
+/// Announced peers are kept for at most this many info hashes, and this many
+/// peers each. The oldest are dropped first.
+const MAX_TORRENTS: usize = 2000;
+const MAX_PEERS_PER_TORRENT: usize = 100;
+const ITEM_TTL: Duration = Duration::from_secs(2 * 60 * 60);
+/// Number of BEP 44 items stored for other nodes, the oldest is dropped first.
+const MAX_ITEMS: usize = 700;
+/// How often expired peers and items are dropped.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60);
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Debug)]
+pub struct DhtConfig {
+    pub bind_addr: SocketAddr,
+    pub bootstrap_nodes: Vec<String>,
+    /// Where the routing table is loaded from on start and saved to on drop.
+    pub routing_table_path: Option<PathBuf>,
+    pub query_timeout: Duration,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        DhtConfig {
+            bind_addr: SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT)),
+            bootstrap_nodes: DEFAULT_BOOTSTRAP_NODES
+                .iter()
+                .map(|node| node.to_string())
+                .collect(),
+            routing_table_path: None,
+            query_timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+/// A mainline DHT node, answers queries on a background thread until dropped.
+/// Start one with `Dht::start`, `bootstrap` it and look up peers for a
+/// torrent with `get_peers` or `announce_peer`. BEP 44 items are stored
+/// and fetched with `put_immutable`/`get_immutable` and
+/// `put_mutable`/`get_mutable`.
+pub struct Dht {
+    node: Arc<Node>,
+    receiver: Option<JoinHandle<()>>,
+}
+
+impl Dht {
+    pub fn start(config: DhtConfig) -> io::Result<Dht> {
+        let socket = UdpSocket::bind(config.bind_addr)?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+
+        let (own_id, nodes) = match &config.routing_table_path {
+            Some(path) => load_routing_table(path).unwrap_or((NodeId::random(), Vec::new())),
+            None => (NodeId::random(), Vec::new()),
+        };
+        let mut table = RoutingTable::new(own_id);
+        for node in nodes {
+            table.insert(node);
+        }
+
+        let node = Arc::new(Node {
+            socket,
+            config,
+            table: Mutex::new(table),
+            pending: Mutex::new(HashMap::new()),
+            pings: Mutex::new(HashMap::new()),
+            peers: Mutex::new(HashMap::new()),
+            items: Mutex::new(HashMap::new()),
+            secrets: Mutex::new(Secrets::new()),
+            running: AtomicBool::new(true),
+        });
+
+        let receiver_node = Arc::clone(&node);
+        let receiver = thread::spawn(move || receiver_node.receive_loop());
+
+        Ok(Dht {
+            node,
+            receiver: Some(receiver),
+        })
+    }
+
+    pub fn get_id(&self) -> NodeId {
+        *self.node.table.lock().unwrap().get_own_id()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.node.socket.local_addr()
+    }
+
+    pub fn get_nodes(&self) -> Vec<NodeInfo> {
+        self.node.table.lock().unwrap().get_nodes()
+    }
+
+    /// Contacts the configured bootstrap nodes and the nodes loaded from the
+    /// saved routing table, then looks up our own id to fill the table.
+    /// Returns the number of nodes in the routing table afterwards.
+    pub fn bootstrap(&self) -> usize {
+        let own_id = self.get_id();
+        let mut addrs: Vec<SocketAddr> = Vec::new();
+        for node in &self.node.config.bootstrap_nodes {
+            if let Ok(resolved) = node.to_socket_addrs() {
+                addrs.extend(resolved.filter(|addr| addr.is_ipv4()));
+            }
+        }
+
+        let queries: Vec<PendingQuery> = addrs
+            .into_iter()
+            .map(|addr| self.node.send_query(addr, Query::FindNode(own_id)))
+            .collect();
+        for query in queries {
+            self.node.wait_response(query);
+        }
+
+        self.node.lookup(own_id, Query::FindNode(own_id));
+        self.node.table.lock().unwrap().len()
+    }
+
+    pub fn ping(&self, addr: SocketAddr) -> Option<NodeId> {
+        let query = self.node.send_query(addr, Query::Ping);
+        self.node.wait_response(query).map(|response| response.id)
+    }
+
+    /// Iteratively finds the nodes closest to `target`.
+    pub fn find_node(&self, target: NodeId) -> Vec<NodeInfo> {
+        self.node.lookup(target, Query::FindNode(target)).closest
+    }
+
+    pub fn get_peers(&self, info_hash: &[u8]) -> Vec<Peer> {
+        match NodeId::from_slice(info_hash) {
+            Some(info_hash) => {
+                self.node
+                    .lookup(info_hash, Query::GetPeers(info_hash))
+                    .peers
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Looks up the torrent and announces ourselves to the closest nodes that
+    /// handed out a token. Returns the peers found along the way.
+    pub fn announce_peer(&self, info_hash: &[u8], port: u16) -> Vec<Peer> {
+        let info_hash = match NodeId::from_slice(info_hash) {
+            Some(info_hash) => info_hash,
+            None => return Vec::new(),
+        };
+
+        let lookup = self.node.lookup(info_hash, Query::GetPeers(info_hash));
+        let queries: Vec<PendingQuery> = lookup
+            .tokens
+            .into_iter()
+            .map(|(node, token)| {
+                let query = Query::AnnouncePeer {
+                    info_hash,
+                    port,
+                    implied_port: false,
+                    token,
+                };
+                self.node.send_query(SocketAddr::V4(node.get_addr()), query)
+            })
+            .collect();
+        for query in queries {
+            self.node.wait_response(query);
+        }
+
+        lookup.peers
+    }
+
+    /// Fetches an immutable item, the returned value is checked against
+    /// the target hash.
+    pub fn get_immutable(&self, target: NodeId) -> Option<Vec<u8>> {
+        let query = Query::Get { target, seq: None };
+        self.node
+            .lookup(target, query)
+            .items
+            .into_iter()
+            .filter_map(|response| response.value)
+            .find(|value| immutable_target(value) == target)
+    }
+
+    /// Stores a bencoded value as an immutable item, returns its target.
+    pub fn put_immutable(&self, value: Vec<u8>) -> Result<NodeId, ItemError> {
+        item::validate_value(&value)?;
+        let target = immutable_target(&value);
+        self.node.put(
+            target,
+            PutArguments {
+                token: Vec::new(),
+                value,
+                key: None,
+                signature: None,
+                seq: None,
+                salt: Vec::new(),
+                cas: None,
+            },
+        )?;
+        Ok(target)
+    }
+
+    /// Fetches the mutable item with the highest sequence number that has a
+    /// valid signature.
+    pub fn get_mutable(&self, public_key: &[u8; 32], salt: &[u8]) -> Option<MutableItem> {
+        let target = mutable_target(public_key, salt);
+        let query = Query::Get { target, seq: None };
+        self.node
+            .lookup(target, query)
+            .items
+            .into_iter()
+            .filter_map(|response| {
+                if response.key.as_deref() != Some(&public_key[..]) {
+                    return None;
+                }
+                MutableItem::from_parts(
+                    &response.key?,
+                    salt,
+                    response.seq?,
+                    response.value?,
+                    &response.signature?,
+                )
+                .ok()
+            })
+            .max_by_key(|item| item.get_seq())
+    }
+
+    /// Stores a signed mutable item. With `cas` the put only succeeds on
+    /// nodes whose current sequence number matches.
+    pub fn put_mutable(&self, item: &MutableItem, cas: Option<i64>) -> Result<NodeId, ItemError> {
+        if !item.verify() {
+            return Err(ItemError::InvalidSignature);
+        }
+        let target = item.target();
+        self.node.put(
+            target,
+            PutArguments {
+                token: Vec::new(),
+                value: item.get_value().to_vec(),
+                key: Some(item.get_public_key().to_vec()),
+                signature: Some(item.get_signature().to_vec()),
+                seq: Some(item.get_seq()),
+                salt: item.get_salt().to_vec(),
+                cas,
+            },
+        )?;
+        Ok(target)
+    }
+
+    pub fn save_routing_table(&self) -> io::Result<()> {
+        match &self.node.config.routing_table_path {
+            Some(path) => save_routing_table(path, &self.node.table.lock().unwrap()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        self.node.running.store(false, Ordering::SeqCst);
+        if let Err(error) = self.save_routing_table() {
+            eprintln!("Error: could not save DHT routing table: {}", error);
+        }
+        if let Some(receiver) = self.receiver.take() {
+            receiver.join().unwrap();
+        }
+    }
+}
+
+struct Node {
+    socket: UdpSocket,
+    config: DhtConfig,
+    table: Mutex<RoutingTable>,
+    /// Queries waiting for a response, by the queried address and the
+    /// random transaction id so replies cannot be spoofed easily.
+    pending: Mutex<HashMap<QueryKey, Sender<Received>>>,
+    /// Questionable nodes pinged because their bucket is full, by id with
+    /// the time of the ping.
+    pings: Mutex<HashMap<NodeId, (NodeInfo, PendingQuery, Instant)>>,
+    peers: Mutex<HashMap<NodeId, Vec<(Peer, Instant)>>>,
+    items: Mutex<HashMap<NodeId, (Item, Instant)>>,
+    secrets: Mutex<Secrets>,
+    running: AtomicBool,
+}
+
+type Received = (SocketAddr, Body);
+type QueryKey = (SocketAddr, Vec<u8>);
+
+struct PendingQuery {
+    addr: SocketAddr,
+    transaction_id: Vec<u8>,
+    receiver: Receiver<Received>,
+}
+
+impl PendingQuery {
+    /// Stops waiting for the response.
+    fn forget(&self, node: &Node) {
+        node.pending
+            .lock()
+            .unwrap()
+            .remove(&(self.addr, self.transaction_id.clone()));
+    }
+}
+
+#[derive(Default)]
+struct Lookup {
+    peers: Vec<Peer>,
+    tokens: Vec<(NodeInfo, Vec<u8>)>,
+    closest: Vec<NodeInfo>,
+    items: Vec<Response>,
+}
+
+impl Node {
+    fn own_id(&self) -> NodeId {
+        *self.table.lock().unwrap().get_own_id()
+    }
+
+    fn send_query(&self, addr: SocketAddr, query: Query) -> PendingQuery {
+        let (sender, receiver) = mpsc::channel();
+        let transaction_id = {
+            let mut pending = self.pending.lock().unwrap();
+            let transaction_id = loop {
+                let transaction_id = rand::random::<[u8; 4]>().to_vec();
+                if !pending.contains_key(&(addr, transaction_id.clone())) {
+                    break transaction_id;
+                }
+            };
+            pending.insert((addr, transaction_id.clone()), sender);
+            transaction_id
+        };
+        let pending = PendingQuery {
+            addr,
+            transaction_id: transaction_id.clone(),
+            receiver,
+        };
+
+        let message = Message {
+            transaction_id,
+            body: Body::Query {
+                id: self.own_id(),
+                query,
+            },
+        };
+        self.send(addr, &message);
+        pending
+    }
+
+    fn wait_response(&self, pending: PendingQuery) -> Option<Response> {
+        let received = pending.receiver.recv_timeout(self.config.query_timeout);
+        pending.forget(self);
+        match received {
+            Ok((addr, Body::Response(response))) => {
+                if let SocketAddr::V4(addr) = addr {
+                    self.add_node(NodeInfo::new(response.id, addr));
+                }
+                Some(response)
+            }
+            _ => None,
+        }
+    }
+
+    /// Adds a node we heard from. When its bucket is full the least recently
+    /// seen questionable node there is pinged, it is replaced once it fails
+    /// to answer often enough.
+    fn add_node(&self, node: NodeInfo) {
+        let questionable = {
+            let mut table = self.table.lock().unwrap();
+            if table.insert(node) {
+                return;
+            }
+            match table.questionable_node(node.get_id()) {
+                Some(questionable) => questionable,
+                None => return,
+            }
+        };
+        let mut pings = self.pings.lock().unwrap();
+        if !pings.contains_key(questionable.get_id()) {
+            let pending = self.send_query(SocketAddr::V4(questionable.get_addr()), Query::Ping);
+            pings.insert(
+                *questionable.get_id(),
+                (questionable, pending, Instant::now()),
+            );
+        }
+    }
+
+    /// Refreshes the pinged nodes that answered and counts a failure for the
+    /// ones that timed out.
+    fn check_pings(&self) {
+        let mut answered = Vec::new();
+        let mut failed = Vec::new();
+        let mut pings = self.pings.lock().unwrap();
+        for (id, (node, pending, sent)) in pings.iter() {
+            match pending.receiver.try_recv() {
+                // an error still shows the node is alive
+                Ok(_) => answered.push(*node),
+                Err(mpsc::TryRecvError::Empty) if sent.elapsed() < self.config.query_timeout => (),
+                _ => failed.push(*id),
+            }
+        }
+        for node in &answered {
+            pings.remove(node.get_id());
+        }
+        for id in &failed {
+            if let Some((_, pending, _)) = pings.remove(id) {
+                pending.forget(self);
+            }
+        }
+        drop(pings);
+
+        let mut table = self.table.lock().unwrap();
+        for node in answered {
+            table.insert(node);
+        }
+        for id in failed {
+            table.failed(&id);
+        }
+    }
+
+    fn send(&self, addr: SocketAddr, message: &Message) {
+        if let Err(error) = self.socket.send_to(&message.encode(), addr) {
+            eprintln!("Error: DHT send to {} failed: {}", addr, error);
+        }
+    }
+
+    /// Iterative lookup towards `target`, sending `query` to every node.
+    fn lookup(&self, target: NodeId, query: Query) -> Lookup {
+        let mut lookup = Lookup::default();
+        let mut shortlist = self.table.lock().unwrap().closest(&target, K);
+        let mut queried: HashSet<NodeId> = HashSet::new();
+        let mut seen_peers: HashSet<Peer> = HashSet::new();
+
+        loop {
+            let candidates: Vec<NodeInfo> = shortlist
+                .iter()
+                .filter(|node| !queried.contains(node.get_id()))
+                .take(ALPHA)
+                .cloned()
+                .collect();
+            if candidates.is_empty() {
+                break;
+            }
+
+            let queries: Vec<(NodeInfo, PendingQuery)> = candidates
+                .into_iter()
+                .map(|node| {
+                    queried.insert(*node.get_id());
+                    let pending = self.send_query(SocketAddr::V4(node.get_addr()), query.clone());
+                    (node, pending)
+                })
+                .collect();
+
+            for (node, pending) in queries {
+                match self.wait_response(pending) {
+                    Some(mut response) => {
+                        for peer in response.values.drain(..) {
+                            if seen_peers.insert(peer.clone()) {
+                                lookup.peers.push(peer);
+                            }
+                        }
+                        if let Some(token) = response.token.clone() {
+                            lookup.tokens.push((node, token));
+                        }
+                        for found in response.nodes.drain(..) {
+                            if !shortlist.iter().any(|n| n.get_id() == found.get_id()) {
+                                shortlist.push(found);
+                            }
+                        }
+                        if response.value.is_some() {
+                            lookup.items.push(response);
+                        }
+                    }
+                    None => {
+                        self.table.lock().unwrap().failed(node.get_id());
+                        shortlist.retain(|n| n.get_id() != node.get_id());
+                    }
+                }
+            }
+
+            shortlist.sort_by_key(|node| node.get_id().distance(&target));
+            shortlist.truncate(K);
+        }
+
+        lookup
+            .tokens
+            .sort_by_key(|(node, _)| node.get_id().distance(&target));
+        lookup.tokens.truncate(K);
+        lookup.closest = shortlist;
+        lookup
+    }
+
+    fn receive_loop(&self) {
+        let mut buffer = [0u8; 65535];
+        let mut expired_at = Instant::now();
+        while self.running.load(Ordering::SeqCst) {
+            self.check_pings();
+            if expired_at.elapsed() >= EXPIRE_INTERVAL {
+                self.expire();
+                expired_at = Instant::now();
+            }
+            let (length, addr) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(_) => continue,
+            };
+
+            match Message::decode(&buffer[..length]) {
+                Ok(Message {
+                    transaction_id,
+                    body: Body::Query { id, query },
+                }) => self.handle_query(addr, transaction_id, id, query),
+                Ok(Message {
+                    transaction_id,
+                    body,
+                }) => {
+                    let sender = self.pending.lock().unwrap().remove(&(addr, transaction_id));
+                    if let Some(sender) = sender {
+                        sender.send((addr, body)).unwrap_or(());
+                    }
+                }
+                Err(error) => {
+                    if let Some(message) = error.to_message() {
+                        self.send(addr, &message);
+                    }
+                }
+            }
+        }
+    }
+
+    fn handle_query(&self, addr: SocketAddr, transaction_id: Vec<u8>, id: NodeId, query: Query) {
+        let addr = match addr {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => return,
+        };
+        self.add_node(NodeInfo::new(id, addr));
+
+        let mut response = Response::new(self.own_id());
+        let body = match query {
+            Query::Ping => Body::Response(response),
+            Query::FindNode(target) => {
+                response.nodes = self.table.lock().unwrap().closest(&target, K);
+                Body::Response(response)
+            }
+            Query::GetPeers(info_hash) => {
+                response.values = self.get_stored_peers(&info_hash);
+                if response.values.is_empty() {
+                    response.nodes = self.table.lock().unwrap().closest(&info_hash, K);
+                }
+                response.token = Some(self.secrets.lock().unwrap().token(&addr));
+                Body::Response(response)
+            }
+            Query::Get { target, seq } => {
+                self.handle_get(&target, seq, &mut response);
+                response.nodes = self.table.lock().unwrap().closest(&target, K);
+                response.token = Some(self.secrets.lock().unwrap().token(&addr));
+                Body::Response(response)
+            }
+            Query::Put(put) => {
+                if !self.secrets.lock().unwrap().is_valid(&put.token, &addr) {
+                    Body::Error(krpc::ERROR_PROTOCOL, "Bad token".to_string())
+                } else {
+                    match self.handle_put(put) {
+                        Ok(()) => Body::Response(response),
+                        Err(error) => Body::Error(error.code(), error.to_string()),
+                    }
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+            } => {
+                if self.secrets.lock().unwrap().is_valid(&token, &addr) {
+                    let port = if implied_port { addr.port() } else { port };
+                    self.store_peer(
+                        info_hash,
+                        Peer::new(SocketAddr::new(IpAddr::V4(*addr.ip()), port)),
+                    );
+                    Body::Response(response)
+                } else {
+                    Body::Error(krpc::ERROR_PROTOCOL, "Bad token".to_string())
+                }
+            }
+        };
+
+        self.send(
+            SocketAddr::V4(addr),
+            &Message {
+                transaction_id,
+                body,
+            },
+        );
+    }
+
+    /// Sends `put` to the closest nodes that handed out a write token.
+    fn put(&self, target: NodeId, arguments: PutArguments) -> Result<(), ItemError> {
+        let lookup = self.lookup(target, Query::Get { target, seq: None });
+        let queries: Vec<PendingQuery> = lookup
+            .tokens
+            .into_iter()
+            .map(|(node, token)| {
+                let mut arguments = arguments.clone();
+                arguments.token = token;
+                self.send_query(SocketAddr::V4(node.get_addr()), Query::Put(arguments))
+            })
+            .collect();
+
+        let mut stored = 0;
+        for query in queries {
+            if self.wait_response(query).is_some() {
+                stored += 1;
+            }
+        }
+        if stored == 0 {
+            return Err(ItemError::NoNodes);
+        }
+        Ok(())
+    }
+
+    fn handle_get(&self, target: &NodeId, seq: Option<i64>, response: &mut Response) {
+        let items = self.items.lock().unwrap();
+        let item = match items.get(target) {
+            Some((item, stored)) if stored.elapsed() < ITEM_TTL => item,
+            _ => return,
+        };
+        match item {
+            Item::Immutable(value) => response.value = Some(value.clone()),
+            Item::Mutable(item) => {
+                response.seq = Some(item.get_seq());
+                if seq.is_none_or(|seq| item.get_seq() > seq) {
+                    response.value = Some(item.get_value().to_vec());
+                    response.key = Some(item.get_public_key().to_vec());
+                    response.signature = Some(item.get_signature().to_vec());
+                }
+            }
+        }
+    }
+
+    fn handle_put(&self, put: PutArguments) -> Result<(), ItemError> {
+        item::validate_value(&put.value)?;
+        let item = match (put.key, put.signature, put.seq) {
+            (Some(public_key), Some(signature), Some(seq)) => Item::Mutable(
+                MutableItem::from_parts(&public_key, &put.salt, seq, put.value, &signature)?,
+            ),
+            (None, None, None) => Item::Immutable(put.value),
+            _ => return Err(ItemError::InvalidSignature),
+        };
+
+        let target = match &item {
+            Item::Immutable(value) => immutable_target(value),
+            Item::Mutable(item) => item.target(),
+        };
+
+        let mut items = self.items.lock().unwrap();
+        if items
+            .get(&target)
+            .is_some_and(|(_, stored)| stored.elapsed() >= ITEM_TTL)
+        {
+            items.remove(&target);
+        }
+        if let (Item::Mutable(new), Some((Item::Mutable(current), _))) = (&item, items.get(&target))
+        {
+            if let Some(cas) = put.cas {
+                if cas != current.get_seq() {
+                    return Err(ItemError::CasMismatch);
+                }
+            }
+            if new.get_seq() < current.get_seq() {
+                return Err(ItemError::SeqTooLow);
+            }
+        }
+        if !items.contains_key(&target) && items.len() >= MAX_ITEMS {
+            let oldest = items
+                .iter()
+                .min_by_key(|(_, (_, stored))| *stored)
+                .map(|(target, _)| *target);
+            if let Some(oldest) = oldest {
+                items.remove(&oldest);
+            }
+        }
+        items.insert(target, (item, Instant::now()));
+        Ok(())
+    }
+
+    /// Peers are kept oldest first, when the table is full the info hash
+    /// whose newest peer is the oldest makes room.
+    fn store_peer(&self, info_hash: NodeId, peer: Peer) {
+        let mut peers = self.peers.lock().unwrap();
+        if !peers.contains_key(&info_hash) && peers.len() >= MAX_TORRENTS {
+            let oldest = peers
+                .iter()
+                .min_by_key(|(_, stored)| stored.last().map(|(_, added)| *added))
+                .map(|(info_hash, _)| *info_hash);
+            if let Some(oldest) = oldest {
+                peers.remove(&oldest);
+            }
+        }
+        let stored = peers.entry(info_hash).or_default();
+        stored.retain(|(stored_peer, added)| stored_peer != &peer && added.elapsed() < PEER_TTL);
+        if stored.len() >= MAX_PEERS_PER_TORRENT {
+            stored.remove(0);
+        }
+        stored.push((peer, Instant::now()));
+    }
+
+    /// Drops the peers and items that outlived their TTL.
+    fn expire(&self) {
+        let mut peers = self.peers.lock().unwrap();
+        for stored in peers.values_mut() {
+            stored.retain(|(_, added)| added.elapsed() < PEER_TTL);
+        }
+        peers.retain(|_, stored| !stored.is_empty());
+        drop(peers);
+        self.items
+            .lock()
+            .unwrap()
+            .retain(|_, (_, stored)| stored.elapsed() < ITEM_TTL);
+    }
+
+    fn get_stored_peers(&self, info_hash: &NodeId) -> Vec<Peer> {
+        match self.peers.lock().unwrap().get(info_hash) {
+            Some(stored) => stored
+                .iter()
+                .filter(|(_, added)| added.elapsed() < PEER_TTL)
+                .rev()
+                .take(MAX_VALUES)
+                .map(|(peer, _)| peer.clone())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Write tokens are a hash of the querying ip and a secret which is rotated
+/// every five minutes, tokens from the previous secret are still accepted.
+struct Secrets {
+    current: [u8; 20],
+    previous: [u8; 20],
+    rotated_at: Instant,
+}
+
+impl Secrets {
+    fn new() -> Secrets {
+        let current = rand::random();
+        Secrets {
+            current,
+            previous: current,
+            rotated_at: Instant::now(),
+        }
+    }
+
+    fn rotate(&mut self) {
+        if self.rotated_at.elapsed() >= TOKEN_ROTATION {
+            self.previous = self.current;
+            self.current = rand::random();
+            self.rotated_at = Instant::now();
+        }
+    }
+
+    fn token(&mut self, addr: &SocketAddrV4) -> Vec<u8> {
+        self.rotate();
+        make_token(&self.current, addr)
+    }
+
+    fn is_valid(&mut self, token: &[u8], addr: &SocketAddrV4) -> bool {
+        self.rotate();
+        token == &make_token(&self.current, addr)[..]
+            || token == &make_token(&self.previous, addr)[..]
+    }
+}
+
+fn make_token(secret: &[u8; 20], addr: &SocketAddrV4) -> Vec<u8> {
+    let mut input = secret.to_vec();
+    input.append(&mut addr.ip().octets().to_vec());
+    hash(input)
+}
+
+fn save_routing_table(path: &PathBuf, table: &RoutingTable) -> io::Result<()> {
+    let mut saved = BDict::new(BTreeMap::new());
+    saved.insert(
+        BString::new(&b"id".to_vec()),
+        Box::new(BString::new(&to_vec(table.get_own_id().as_bytes()))),
+    );
+    saved.insert(
+        BString::new(&b"nodes".to_vec()),
+        Box::new(BString::new(&encode_compact_nodes(&table.get_nodes()))),
+    );
+    fs::write(path, saved.encode())
+}
+
+fn load_routing_table(path: &PathBuf) -> Option<(NodeId, Vec<NodeInfo>)> {
+    let saved = fs::read(path).ok()?;
+    let (saved, _) = bencoding::try_decode(&saved)?;
+    let saved = saved.as_any().downcast_ref::<BDict>()?;
+    let own_id = NodeId::from_slice(saved.get::<BString>("id")?)?;
+    let nodes = match saved.get::<BString>("nodes") {
+        Some(nodes) => parse_compact_nodes(nodes),
+        None => Vec::new(),
+    };
+    Some((own_id, nodes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ed25519_dalek::SigningKey;
+
+    fn start_node(bootstrap: Option<&Dht>) -> Dht {
+        let bootstrap_nodes = match bootstrap {
+            Some(node) => vec![node.local_addr().unwrap().to_string()],
+            None => Vec::new(),
+        };
+        Dht::start(DhtConfig {
+            bind_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            bootstrap_nodes,
+            routing_table_path: None,
+            query_timeout: Duration::from_millis(500),
+        })
+        .unwrap()
+    }
+
+    /// Sends a raw packet to `node` and decodes the reply.
+    fn exchange(node: &Dht, packet: &[u8]) -> Message {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        socket.send_to(packet, node.local_addr().unwrap()).unwrap();
+        let mut buffer = [0u8; 1500];
+        let (length, _) = socket.recv_from(&mut buffer).unwrap();
+        Message::decode(&buffer[..length]).unwrap()
+    }
+
+    #[test]
+    fn nodes_find_each_other() {
+        let first = start_node(None);
+        let second = start_node(Some(&first));
+        let third = start_node(Some(&first));
+
+        assert_eq!(second.bootstrap(), 1);
+        assert!(third.bootstrap() >= 2);
+        let ids: Vec<NodeId> = third
+            .get_nodes()
+            .iter()
+            .map(|node| *node.get_id())
+            .collect();
+        assert!(ids.contains(&first.get_id()));
+        assert!(ids.contains(&second.get_id()));
+        assert_eq!(
+            third.ping(first.local_addr().unwrap()),
+            Some(first.get_id())
+        );
+    }
+
+    #[test]
+    fn announced_peers_are_found() {
+        let first = start_node(None);
+        let second = start_node(Some(&first));
+        let third = start_node(Some(&first));
+        second.bootstrap();
+        third.bootstrap();
+
+        let info_hash = [7u8; 20];
+        second.announce_peer(&info_hash, 6889);
+        let peers = third.get_peers(&info_hash);
+        assert_eq!(
+            peers,
+            vec![Peer::new(SocketAddr::from(([127, 0, 0, 1], 6889)))]
+        );
+    }
+
+    #[test]
+    fn items_are_stored_and_fetched() {
+        let first = start_node(None);
+        let second = start_node(Some(&first));
+        let third = start_node(Some(&first));
+        second.bootstrap();
+        third.bootstrap();
+
+        let target = second.put_immutable(b"12:Hello World!".to_vec()).unwrap();
+        assert_eq!(
+            third.get_immutable(target),
+            Some(b"12:Hello World!".to_vec())
+        );
+    }
+
+    #[test]
+    fn malformed_queries_get_protocol_error() {
+        let node = start_node(None);
+        let id = [b'a'; 20];
+
+        let mut missing_target = b"d1:ad2:id20:".to_vec();
+        missing_target.extend_from_slice(&id);
+        missing_target.extend_from_slice(b"e1:q9:find_node1:t2:aa1:y1:qe");
+        let reply = exchange(&node, &missing_target);
+        assert_eq!(reply.transaction_id, b"aa".to_vec());
+        match reply.body {
+            Body::Error(code, _) => assert_eq!(code, krpc::ERROR_PROTOCOL),
+            _ => panic!("expected an error"),
+        }
+
+        let mut unknown = b"d1:ad2:id20:".to_vec();
+        unknown.extend_from_slice(&id);
+        unknown.extend_from_slice(b"e1:q3:foo1:t2:bb1:y1:qe");
+        let reply = exchange(&node, &unknown);
+        assert_eq!(reply.transaction_id, b"bb".to_vec());
+        match reply.body {
+            Body::Error(code, _) => assert_eq!(code, krpc::ERROR_METHOD_UNKNOWN),
+            _ => panic!("expected an error"),
+        }
+    }
+
+    #[test]
+    fn nodes_are_evicted_after_repeated_failures() {
+        let mut table = RoutingTable::new(NodeId::new([0; 20]));
+        let node = NodeInfo::new(
+            NodeId::new([1; 20]),
+            SocketAddrV4::new([127, 0, 0, 1].into(), 6881),
+        );
+        table.insert(node);
+
+        for _ in 1..routing::MAX_FAILURES {
+            assert!(!table.failed(node.get_id()));
+            assert_eq!(table.len(), 1);
+        }
+        assert!(table.failed(node.get_id()));
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn answering_resets_failures() {
+        let mut table = RoutingTable::new(NodeId::new([0; 20]));
+        let node = NodeInfo::new(
+            NodeId::new([1; 20]),
+            SocketAddrV4::new([127, 0, 0, 1].into(), 6881),
+        );
+        table.insert(node);
+
+        for _ in 0..routing::MAX_FAILURES * 2 {
+            table.failed(node.get_id());
+            table.insert(node);
+        }
+        assert_eq!(table.len(), 1);
+    }
+
+    /// `count` nodes in the bucket furthest from `own_id`, at `addr`.
+    fn far_nodes(own_id: &NodeId, count: u8, addr: SocketAddrV4) -> Vec<NodeInfo> {
+        (0..count)
+            .map(|i| {
+                let mut id = *own_id.as_bytes();
+                id[0] ^= 0x80;
+                id[19] = id[19].wrapping_add(i);
+                NodeInfo::new(NodeId::new(id), addr)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn full_buckets_keep_good_nodes() {
+        let own_id = NodeId::new([0; 20]);
+        let mut table = RoutingTable::new(own_id);
+        let addr = SocketAddrV4::new([127, 0, 0, 1].into(), 6881);
+        let nodes = far_nodes(&own_id, K as u8 + 1, addr);
+        for node in &nodes[..K] {
+            assert!(table.insert(*node));
+        }
+
+        assert!(!table.insert(nodes[K]));
+        assert_eq!(table.questionable_node(nodes[K].get_id()), None);
+        assert_eq!(table.len(), K);
+    }
+
+    #[test]
+    fn questionable_nodes_are_replaced_once_bad() {
+        let own_id = NodeId::new([0; 20]);
+        let mut table = RoutingTable::new(own_id);
+        let addr = SocketAddrV4::new([127, 0, 0, 1].into(), 6881);
+        let nodes = far_nodes(&own_id, K as u8 + 1, addr);
+        for node in &nodes[..K] {
+            table.insert(*node);
+        }
+
+        table.failed(nodes[2].get_id());
+        // questionable nodes stay until they failed often enough
+        assert!(!table.insert(nodes[K]));
+        assert_eq!(table.questionable_node(nodes[K].get_id()), Some(nodes[2]));
+        for _ in 1..routing::MAX_FAILURES {
+            table.failed(nodes[2].get_id());
+        }
+
+        let ids: Vec<NodeId> = table.get_nodes().iter().map(|n| *n.get_id()).collect();
+        assert!(!ids.contains(nodes[2].get_id()));
+        assert!(ids.contains(nodes[K].get_id()));
+        assert_eq!(table.len(), K);
+    }
+
+    #[test]
+    fn questionable_nodes_are_pinged_before_replacing() {
+        let node = start_node(None);
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        silent
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let silent_addr = match silent.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+
+        let nodes = far_nodes(&node.get_id(), K as u8 + 1, silent_addr);
+        {
+            let mut table = node.node.table.lock().unwrap();
+            for far in &nodes[..K] {
+                table.insert(*far);
+            }
+            for _ in 1..routing::MAX_FAILURES {
+                table.failed(nodes[0].get_id());
+            }
+        }
+        node.node.add_node(nodes[K]);
+
+        let mut buffer = [0u8; 1500];
+        let (length, _) = silent.recv_from(&mut buffer).unwrap();
+        match Message::decode(&buffer[..length]).unwrap().body {
+            Body::Query {
+                query: Query::Ping, ..
+            } => (),
+            _ => panic!("expected a ping"),
+        }
+
+        let has_node = |id: &NodeId| node.get_nodes().iter().any(|far| far.get_id() == id);
+        let start = Instant::now();
+        while !has_node(nodes[K].get_id()) {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert!(!has_node(nodes[0].get_id()));
+    }
+
+    fn peer(port: u16) -> Peer {
+        Peer::new(SocketAddr::from(([10, 0, 0, 1], port)))
+    }
+
+    fn info_hash(index: usize) -> NodeId {
+        let mut id = [0u8; 20];
+        id[..8].copy_from_slice(&(index as u64).to_be_bytes());
+        NodeId::new(id)
+    }
+
+    #[test]
+    fn stored_peers_are_capped() {
+        let node = start_node(None);
+        for port in 0..MAX_PEERS_PER_TORRENT as u16 + 10 {
+            node.node.store_peer(info_hash(0), peer(port + 1));
+        }
+        let stored = node.node.get_stored_peers(&info_hash(0));
+        assert_eq!(stored.len(), MAX_VALUES);
+        assert_eq!(
+            node.node.peers.lock().unwrap()[&info_hash(0)].len(),
+            MAX_PEERS_PER_TORRENT
+        );
+        assert!(!node.node.peers.lock().unwrap()[&info_hash(0)]
+            .iter()
+            .any(|(stored, _)| stored == &peer(1)));
+
+        // the info hash announced longest ago makes room for a new one
+        for index in 1..MAX_TORRENTS {
+            node.node.store_peer(info_hash(index), peer(1));
+        }
+        node.node.store_peer(info_hash(0), peer(1));
+        node.node.store_peer(info_hash(MAX_TORRENTS), peer(1));
+        let peers = node.node.peers.lock().unwrap();
+        assert_eq!(peers.len(), MAX_TORRENTS);
+        assert!(!peers.contains_key(&info_hash(1)));
+        assert!(peers.contains_key(&info_hash(0)));
+        assert!(peers.contains_key(&info_hash(MAX_TORRENTS)));
+    }
+
+    #[test]
+    fn stored_items_are_capped() {
+        let node = start_node(None);
+        let value = |index: usize| {
+            let index = index.to_string();
+            format!("{}:{}", index.len(), index).into_bytes()
+        };
+        let put = |value: Vec<u8>| {
+            node.node.handle_put(PutArguments {
+                token: Vec::new(),
+                value,
+                key: None,
+                signature: None,
+                salt: Vec::new(),
+                seq: None,
+                cas: None,
+            })
+        };
+        for index in 0..MAX_ITEMS + 1 {
+            put(value(index)).unwrap();
+        }
+
+        let items = node.node.items.lock().unwrap();
+        assert_eq!(items.len(), MAX_ITEMS);
+        assert!(!items.contains_key(&immutable_target(&value(0))));
+        assert!(items.contains_key(&immutable_target(&value(MAX_ITEMS))));
+    }
+
+    #[test]
+    fn expired_entries_are_dropped() {
+        let node = start_node(None);
+        let expired = Instant::now() - PEER_TTL.max(ITEM_TTL) - Duration::from_secs(1);
+        node.node.store_peer(info_hash(0), peer(1));
+        node.node
+            .peers
+            .lock()
+            .unwrap()
+            .insert(info_hash(1), vec![(peer(1), expired)]);
+        node.node
+            .peers
+            .lock()
+            .unwrap()
+            .get_mut(&info_hash(0))
+            .unwrap()
+            .insert(0, (peer(2), expired));
+        let value = b"1:a".to_vec();
+        node.node
+            .items
+            .lock()
+            .unwrap()
+            .insert(immutable_target(&value), (Item::Immutable(value), expired));
+
+        node.node.expire();
+        let peers = node.node.peers.lock().unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[&info_hash(0)].len(), 1);
+        assert_eq!(peers[&info_hash(0)][0].0, peer(1));
+        assert!(node.node.items.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn replies_must_come_from_the_queried_node() {
+        let node = start_node(None);
+        let remote = UdpSocket::bind("127.0.0.1:0").unwrap();
+        remote
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let spoofer = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let pending = node
+            .node
+            .send_query(remote.local_addr().unwrap(), Query::Ping);
+        let mut buffer = [0u8; 1500];
+        let (length, _) = remote.recv_from(&mut buffer).unwrap();
+        let transaction_id = Message::decode(&buffer[..length]).unwrap().transaction_id;
+        let reply = |id: NodeId| {
+            Message {
+                transaction_id: transaction_id.clone(),
+                body: Body::Response(Response::new(id)),
+            }
+            .encode()
+        };
+
+        let spoofed_id = NodeId::random();
+        spoofer
+            .send_to(&reply(spoofed_id), node.local_addr().unwrap())
+            .unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert!(pending.receiver.try_recv().is_err());
+
+        let remote_id = NodeId::random();
+        remote
+            .send_to(&reply(remote_id), node.local_addr().unwrap())
+            .unwrap();
+        let response = node.node.wait_response(pending).unwrap();
+        assert_eq!(response.id, remote_id);
+        assert!(node.node.pending.lock().unwrap().is_empty());
+    }
+
+    /// A node and a second node that bootstrapped from it, items put by the
+    /// second node are stored on the first.
+    fn two_nodes() -> (Dht, Dht) {
+        let first = start_node(None);
+        let second = start_node(Some(&first));
+        second.bootstrap();
+        (first, second)
+    }
+
+    fn signing_key() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    #[test]
+    fn mutable_items_are_stored_and_fetched() {
+        let (_first, second) = two_nodes();
+        let key = signing_key();
+        let public_key = key.verifying_key().to_bytes();
+        let item = MutableItem::sign(&key, b"", 1, b"5:first".to_vec()).unwrap();
+        assert!(item.verify());
+        let salted = MutableItem::sign(&key, b"salt", 1, b"6:salted".to_vec()).unwrap();
+        assert_ne!(item.target(), salted.target());
+
+        assert_eq!(second.put_mutable(&item, None), Ok(item.target()));
+        assert_eq!(second.put_mutable(&salted, None), Ok(salted.target()));
+        assert_eq!(second.get_mutable(&public_key, b""), Some(item));
+        assert_eq!(second.get_mutable(&public_key, b"salt"), Some(salted));
+        assert_eq!(second.get_mutable(&public_key, b"other"), None);
+    }
+
+    #[test]
+    fn bad_signatures_are_rejected() {
+        let (first, second) = two_nodes();
+        let key = signing_key();
+        let public_key = key.verifying_key().to_bytes();
+        let item = MutableItem::sign(&key, b"a", 1, b"5:first".to_vec()).unwrap();
+        let mut signature = item.get_signature().to_vec();
+        signature[0] ^= 1;
+
+        assert_eq!(
+            MutableItem::from_parts(&public_key, b"a", 1, b"5:first".to_vec(), &signature),
+            Err(ItemError::InvalidSignature)
+        );
+        assert_eq!(
+            first.node.handle_put(PutArguments {
+                token: Vec::new(),
+                value: b"5:first".to_vec(),
+                key: Some(public_key.to_vec()),
+                signature: Some(signature),
+                seq: Some(1),
+                salt: b"a".to_vec(),
+                cas: None,
+            }),
+            Err(ItemError::InvalidSignature)
+        );
+
+        // an item served under another salt does not verify for that salt
+        first.node.items.lock().unwrap().insert(
+            mutable_target(&public_key, b"b"),
+            (Item::Mutable(item), Instant::now()),
+        );
+        assert_eq!(second.get_mutable(&public_key, b"b"), None);
+    }
+
+    #[test]
+    fn lower_sequence_numbers_are_rejected() {
+        let (first, second) = two_nodes();
+        let key = signing_key();
+        let public_key = key.verifying_key().to_bytes();
+        let newer = MutableItem::sign(&key, b"", 2, b"5:newer".to_vec()).unwrap();
+        let older = MutableItem::sign(&key, b"", 1, b"5:older".to_vec()).unwrap();
+
+        second.put_mutable(&newer, None).unwrap();
+        assert_eq!(second.put_mutable(&older, None), Err(ItemError::NoNodes));
+        assert_eq!(
+            first.node.handle_put(PutArguments {
+                token: Vec::new(),
+                value: older.get_value().to_vec(),
+                key: Some(public_key.to_vec()),
+                signature: Some(older.get_signature().to_vec()),
+                seq: Some(1),
+                salt: Vec::new(),
+                cas: None,
+            }),
+            Err(ItemError::SeqTooLow)
+        );
+        assert_eq!(second.get_mutable(&public_key, b""), Some(newer));
+    }
+
+    #[test]
+    fn cas_mismatch_is_rejected() {
+        let (first, second) = two_nodes();
+        let key = signing_key();
+        let public_key = key.verifying_key().to_bytes();
+        let item = MutableItem::sign(&key, b"", 1, b"5:first".to_vec()).unwrap();
+        let update = MutableItem::sign(&key, b"", 2, b"6:second".to_vec()).unwrap();
+
+        second.put_mutable(&item, None).unwrap();
+        assert_eq!(
+            second.put_mutable(&update, Some(5)),
+            Err(ItemError::NoNodes)
+        );
+        assert_eq!(
+            first.node.handle_put(PutArguments {
+                token: Vec::new(),
+                value: update.get_value().to_vec(),
+                key: Some(public_key.to_vec()),
+                signature: Some(update.get_signature().to_vec()),
+                seq: Some(2),
+                salt: Vec::new(),
+                cas: Some(5),
+            }),
+            Err(ItemError::CasMismatch)
+        );
+        assert_eq!(second.get_mutable(&public_key, b""), Some(item));
+
+        assert!(second.put_mutable(&update, Some(1)).is_ok());
+        assert_eq!(second.get_mutable(&public_key, b""), Some(update));
+    }
+}
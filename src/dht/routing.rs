@@ -0,0 +1,248 @@
+use rand::Rng;
+
+use std::convert::TryInto;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+/// Maximum number of nodes in a bucket.
+pub const K: usize = 8;
+/// Nodes not heard from within this time are questionable and may be replaced.
+pub const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+/// Nodes that failed to answer this many queries in a row are bad and dropped.
+pub const MAX_FAILURES: u32 = 3;
+
+const ID_BITS: usize = 160;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId([u8; 20]);
+
+impl NodeId {
+    pub fn new(id: [u8; 20]) -> NodeId {
+        NodeId(id)
+    }
+
+    pub fn random() -> NodeId {
+        NodeId(rand::thread_rng().gen())
+    }
+
+    pub fn from_slice(id: &[u8]) -> Option<NodeId> {
+        Some(NodeId(id.try_into().ok()?))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
+    pub fn distance(&self, other: &NodeId) -> NodeId {
+        let mut distance = [0u8; 20];
+        for (i, byte) in distance.iter_mut().enumerate() {
+            *byte = self.0[i] ^ other.0[i];
+        }
+        NodeId(distance)
+    }
+
+    fn leading_zeros(&self) -> usize {
+        let mut zeros = 0;
+        for byte in self.0.iter() {
+            if *byte == 0 {
+                zeros += 8;
+            } else {
+                zeros += byte.leading_zeros() as usize;
+                break;
+            }
+        }
+        zeros
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NodeInfo {
+    id: NodeId,
+    addr: SocketAddrV4,
+}
+
+impl NodeInfo {
+    pub fn new(id: NodeId, addr: SocketAddrV4) -> NodeInfo {
+        NodeInfo { id, addr }
+    }
+
+    pub fn get_id(&self) -> &NodeId {
+        &self.id
+    }
+
+    pub fn get_addr(&self) -> SocketAddrV4 {
+        self.addr
+    }
+
+    /// 26 byte "compact node info" used in `nodes` values.
+    pub fn to_compact(self) -> Vec<u8> {
+        let mut compact = self.id.0.to_vec();
+        compact.append(&mut self.addr.ip().octets().to_vec());
+        compact.append(&mut self.addr.port().to_be_bytes().to_vec());
+        compact
+    }
+}
+
+pub fn parse_compact_nodes(nodes: &[u8]) -> Vec<NodeInfo> {
+    let mut node_list = Vec::new();
+    for node in nodes.chunks_exact(26) {
+        let id = NodeId::from_slice(&node[0..20]).unwrap();
+        let ip: [u8; 4] = node[20..24].try_into().unwrap();
+        let port = u16::from_be_bytes(node[24..26].try_into().unwrap());
+        if port == 0 {
+            continue;
+        }
+        node_list.push(NodeInfo::new(
+            id,
+            SocketAddrV4::new(Ipv4Addr::from(ip), port),
+        ));
+    }
+    node_list
+}
+
+pub fn encode_compact_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    let mut compact = Vec::new();
+    for node in nodes {
+        compact.append(&mut node.to_compact());
+    }
+    compact
+}
+
+#[derive(Debug)]
+struct Entry {
+    node: NodeInfo,
+    last_seen: Instant,
+    failures: u32,
+}
+
+impl Entry {
+    fn is_questionable(&self) -> bool {
+        self.failures > 0 || self.last_seen.elapsed() > QUESTIONABLE_AFTER
+    }
+}
+
+/// Kademlia routing table with one bucket per bit of distance from our own id.
+/// A node that finds its bucket full waits as the bucket's replacement until
+/// one of the questionable nodes fails to answer `MAX_FAILURES` times, as
+/// BEP 5 asks. Pinging those nodes is up to the caller, see
+/// `questionable_node`.
+#[derive(Debug)]
+pub struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Vec<Entry>>,
+    replacements: Vec<Option<NodeInfo>>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> RoutingTable {
+        let mut buckets = Vec::with_capacity(ID_BITS);
+        buckets.resize_with(ID_BITS, Vec::new);
+        RoutingTable {
+            own_id,
+            buckets,
+            replacements: vec![None; ID_BITS],
+        }
+    }
+
+    pub fn get_own_id(&self) -> &NodeId {
+        &self.own_id
+    }
+
+    /// Inserts or refreshes a node, returns false if its bucket is full. The
+    /// node then replaces the next node of the bucket to go bad.
+    pub fn insert(&mut self, node: NodeInfo) -> bool {
+        if node.id == self.own_id {
+            return false;
+        }
+        let index = self.bucket_index(&node.id);
+        let bucket = &mut self.buckets[index];
+
+        if let Some(position) = bucket.iter().position(|entry| entry.node.id == node.id) {
+            let mut entry = bucket.remove(position);
+            entry.node = node;
+            entry.last_seen = Instant::now();
+            entry.failures = 0;
+            bucket.push(entry);
+            return true;
+        }
+
+        if bucket.len() >= K {
+            self.replacements[index] = Some(node);
+            return false;
+        }
+
+        bucket.push(Entry {
+            node,
+            last_seen: Instant::now(),
+            failures: 0,
+        });
+        true
+    }
+
+    /// The least recently seen questionable node of the full bucket `id`
+    /// belongs in, it has to be pinged before anything can take its place.
+    pub fn questionable_node(&self, id: &NodeId) -> Option<NodeInfo> {
+        let bucket = &self.buckets[self.bucket_index(id)];
+        if bucket.len() < K {
+            return None;
+        }
+        bucket
+            .iter()
+            .filter(|entry| entry.is_questionable())
+            .min_by_key(|entry| entry.last_seen)
+            .map(|entry| entry.node)
+    }
+
+    /// Records a query the node did not answer, the node is removed once it
+    /// failed `MAX_FAILURES` times in a row and the bucket's replacement
+    /// takes its place. Returns true if it was removed.
+    pub fn failed(&mut self, id: &NodeId) -> bool {
+        let index = self.bucket_index(id);
+        let bucket = &mut self.buckets[index];
+        let position = match bucket.iter().position(|entry| &entry.node.id == id) {
+            Some(position) => position,
+            None => return false,
+        };
+        bucket[position].failures += 1;
+        if bucket[position].failures < MAX_FAILURES {
+            return false;
+        }
+        bucket.remove(position);
+        if let Some(replacement) = self.replacements[index].take() {
+            self.insert(replacement);
+        }
+        true
+    }
+
+    pub fn remove(&mut self, id: &NodeId) {
+        let index = self.bucket_index(id);
+        self.buckets[index].retain(|entry| &entry.node.id != id);
+    }
+
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes = self.get_nodes();
+        nodes.sort_by_key(|node| node.id.distance(target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn get_nodes(&self) -> Vec<NodeInfo> {
+        self.buckets
+            .iter()
+            .flat_map(|bucket| bucket.iter().map(|entry| entry.node))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> usize {
+        let zeros = self.own_id.distance(id).leading_zeros();
+        (ID_BITS - 1).saturating_sub(zeros)
+    }
+}
@@ -1,77 +1,754 @@
-use crate::torrent::{File, Torrent};
+use crate::storage::{move_file, PartFile, Storage};
+use crate::torrent::{FilePriority, FileSpan, Torrent};
 
+use std::collections::HashMap;
 use std::fs::{self, OpenOptions};
 use std::io::prelude::*;
 use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
 
-pub fn write_piece(piece: Vec<u8>, index: u32, meta_data: &Torrent) {
-    match &meta_data {
-        Torrent::SingleFileTorrent(single_data) => write_piece_single_file(
-            piece,
-            index,
-            meta_data.get_piece_length(),
-            single_data.get_name(),
-            meta_data.get_length(),
-        ),
-        Torrent::MultiFileTorrent(multi_data) => write_piece_multi_file(
-            piece,
-            index,
-            meta_data.get_piece_length(),
-            multi_data.get_name(),
-            multi_data.get_files(index),
+/// How `FileStorage` lays out a file on disk when it is first written to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AllocationMode {
+    /// Set the full length up front without writing anything, the file
+    /// system only backs the ranges that get written.
+    #[default]
+    Sparse,
+    /// Reserve every block of the file when it is created.
+    Full,
+    /// Start empty and let the file grow as pieces are written.
+    Compact,
+}
+
+/// Stores a torrent as regular files under a save path, a single file
+/// torrent as `<save path>/<name>` and a multi file torrent as
+/// `<save path>/<name>/<path...>`. Skipped files that don't exist yet are
+/// never created, their share of pieces goes to `<save path>/.<name>.parts`.
+pub struct FileStorage {
+    torrent: Torrent,
+    save_path: PathBuf,
+    complete_path: Option<PathBuf>,
+    allocation: AllocationMode,
+    handles: HashMap<usize, fs::File>,
+    /// Whether a skipped file is missing from disk, so its data goes to
+    /// the part file. Saves a stat for every span written or read.
+    parted: HashMap<usize, bool>,
+    part_file: Option<PartFile>,
+}
+
+impl FileStorage {
+    pub fn new(torrent: &Torrent, save_path: &Path) -> FileStorage {
+        FileStorage::with_allocation(torrent, save_path, AllocationMode::default())
+    }
+
+    pub fn with_allocation(
+        torrent: &Torrent,
+        save_path: &Path,
+        allocation: AllocationMode,
+    ) -> FileStorage {
+        FileStorage {
+            torrent: torrent.clone(),
+            save_path: save_path.to_path_buf(),
+            complete_path: None,
+            allocation,
+            handles: HashMap::new(),
+            parted: HashMap::new(),
+            part_file: None,
+        }
+    }
+
+    /// Downloads into `incomplete_path` and moves the data to `save_path`
+    /// once the torrent is finished. Data already under `save_path` is used
+    /// where it is.
+    pub fn with_incomplete_path(
+        torrent: &Torrent,
+        save_path: &Path,
+        incomplete_path: &Path,
+    ) -> FileStorage {
+        let mut storage = FileStorage::new(torrent, save_path);
+        let in_save_path = storage
+            .get_files()
+            .iter()
+            .chain(std::iter::once(&(storage.get_part_file_path(), 0)))
+            .any(|(path, _)| path.exists());
+        if !in_save_path {
+            storage.save_path = incomplete_path.to_path_buf();
+            storage.complete_path = Some(save_path.to_path_buf());
+        }
+        storage
+    }
+
+    /// Where the data currently is.
+    pub fn get_save_path(&self) -> &Path {
+        &self.save_path
+    }
+
+    /// Where `finish` moves the data to, None when it stays in place.
+    pub fn get_complete_path(&self) -> Option<&Path> {
+        self.complete_path.as_deref()
+    }
+
+    pub fn set_complete_path(&mut self, complete_path: Option<&Path>) {
+        self.complete_path = complete_path.map(Path::to_path_buf);
+    }
+
+    /// Moves the data to the complete path once every wanted piece is
+    /// downloaded. Returns whether the data was moved.
+    pub fn finish(&mut self) -> io::Result<bool> {
+        if !self.torrent.is_finished() {
+            return Ok(false);
+        }
+        let complete_path = match self.complete_path.take() {
+            Some(complete_path) => complete_path,
+            None => return Ok(false),
+        };
+        if let Err(err) = self.move_to(&complete_path) {
+            self.complete_path = Some(complete_path);
+            return Err(err);
+        }
+        Ok(true)
+    }
+
+    pub fn get_allocation(&self) -> AllocationMode {
+        self.allocation
+    }
+
+    /// Only affects files created after the call.
+    pub fn set_allocation(&mut self, allocation: AllocationMode) {
+        self.allocation = allocation;
+    }
+
+    /// Changes the priority of a file in the torrent. A file that stops
+    /// being skipped gets the data kept for it in the part file.
+    pub fn set_file_priority(
+        &mut self,
+        file_index: usize,
+        priority: FilePriority,
+    ) -> io::Result<()> {
+        let was_parted = self.is_parted(file_index);
+        self.torrent.set_file_priority(file_index, priority);
+        self.parted.remove(&file_index);
+        if !was_parted || priority == FilePriority::Skip {
+            return Ok(());
+        }
+
+        for piece_index in self.part_file()?.get_pieces() {
+            let spans = self.piece_spans(piece_index);
+            for (piece_offset, span) in &spans {
+                if span.file_index != file_index {
+                    continue;
+                }
+                let data = match self
+                    .part_file()?
+                    .read(piece_index, *piece_offset, span.length)
+                {
+                    Ok(data) => data,
+                    // only part of the piece was written
+                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => continue,
+                    Err(err) => return Err(err),
+                };
+                let file = self.open(file_index, true)?;
+                file.seek(SeekFrom::Start(span.file_offset))?;
+                file.write_all(&data)?;
+            }
+            if !spans
+                .iter()
+                .any(|(_, span)| self.is_parted(span.file_index))
+            {
+                self.part_file()?.free(piece_index);
+            }
+        }
+        Ok(())
+    }
+
+    /// Every file of the torrent with its length, in torrent order.
+    pub fn get_files(&self) -> Vec<(PathBuf, u64)> {
+        let file_count = match &self.torrent {
+            Torrent::SingleFileTorrent(_) => 1,
+            Torrent::MultiFileTorrent(multi_data) => multi_data.get_file_list().len(),
+        };
+        (0..file_count).map(|i| self.file_path(i)).collect()
+    }
+
+    fn file_path(&self, file_index: usize) -> (PathBuf, u64) {
+        file_path(&self.torrent, &self.save_path, file_index)
+    }
+
+    /// Where the pieces of skipped files that are not on disk are kept.
+    pub fn get_part_file_path(&self) -> PathBuf {
+        part_file_path(&self.torrent, &self.save_path)
+    }
+
+    fn part_file(&mut self) -> io::Result<&mut PartFile> {
+        if self.part_file.is_none() {
+            self.part_file = Some(PartFile::open(
+                &self.get_part_file_path(),
+                self.torrent.get_piece_count(),
+                self.torrent.get_piece_length() as u64,
+            )?);
+        }
+        Ok(self.part_file.as_mut().unwrap())
+    }
+
+    /// Whether the data of the file goes to the part file, true for skipped
+    /// files not on disk.
+    fn is_parted(&mut self, file_index: usize) -> bool {
+        if self.torrent.get_file_priority(file_index) != FilePriority::Skip
+            || self.handles.contains_key(&file_index)
+        {
+            return false;
+        }
+        if let Some(parted) = self.parted.get(&file_index) {
+            return *parted;
+        }
+        let parted = !self.file_path(file_index).0.exists();
+        self.parted.insert(file_index, parted);
+        parted
+    }
+
+    /// Spans of a block with the offset in the piece each one starts at.
+    fn block_spans(&self, piece_index: u32, begin: u64, length: u64) -> Vec<(u64, FileSpan)> {
+        let mut piece_offset = begin;
+        let mut spans = Vec::new();
+        for span in self.torrent.map_block(piece_index, begin, length) {
+            spans.push((piece_offset, span));
+            piece_offset += span.length;
+        }
+        spans
+    }
+
+    fn piece_spans(&self, piece_index: u32) -> Vec<(u64, FileSpan)> {
+        self.block_spans(piece_index, 0, self.torrent.get_piece_size(piece_index))
+    }
+
+    fn open(&mut self, file_index: usize, create: bool) -> io::Result<&mut fs::File> {
+        if !self.handles.contains_key(&file_index) {
+            let (path, length) = self.file_path(file_index);
+            let file = open_file(&path, length, self.allocation, create)?;
+            self.handles.insert(file_index, file);
+        }
+        Ok(self.handles.get_mut(&file_index).unwrap())
+    }
+}
+
+fn file_path(torrent: &Torrent, save_path: &Path, file_index: usize) -> (PathBuf, u64) {
+    match torrent {
+        Torrent::SingleFileTorrent(single_data) => (
+            save_path.join(single_data.get_name()),
+            torrent.get_length() as u64,
         ),
+        Torrent::MultiFileTorrent(multi_data) => {
+            let file_data = &multi_data.get_file_list()[file_index];
+            let mut path = save_path.join(multi_data.get_name());
+            path.extend(file_data.get_path());
+            (path, file_data.get_length() as u64)
+        }
     }
 }
 
-fn write_piece_single_file(piece: Vec<u8>, index: u32, piece_length: i64, name: &str, length: i64) {
-    let mut file = match OpenOptions::new().write(true).open(name) {
-        Ok(file) => file,
-        Err(err) => {
-            if let io::ErrorKind::NotFound = err.kind() {
-                fs::write(name, vec![0u8; length as usize]).unwrap();
+fn part_file_path(torrent: &Torrent, save_path: &Path) -> PathBuf {
+    let name = match torrent {
+        Torrent::SingleFileTorrent(single_data) => single_data.get_name(),
+        Torrent::MultiFileTorrent(multi_data) => multi_data.get_name(),
+    };
+    save_path.join(format!(".{}.parts", name))
+}
+
+/// Opens a file of the torrent, creating it laid out as `allocation` says
+/// when `create` is set.
+fn open_file(
+    path: &Path,
+    length: u64,
+    allocation: AllocationMode,
+    create: bool,
+) -> io::Result<fs::File> {
+    match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(file) => Ok(file),
+        Err(err) if err.kind() == io::ErrorKind::NotFound && create => {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
             }
-            OpenOptions::new().write(true).open(name).unwrap()
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            match allocation {
+                AllocationMode::Sparse => file.set_len(length)?,
+                AllocationMode::Full => allocate(&file, length)?,
+                AllocationMode::Compact => (),
+            }
+            Ok(file)
         }
-    };
-    file.seek(SeekFrom::Start((index as i64 * piece_length) as u64))
-        .unwrap();
-    file.write_all(&piece).unwrap();
+        Err(err) => Err(err),
+    }
 }
 
-fn write_piece_multi_file(
-    piece: Vec<u8>,
-    index: u32,
-    piece_length: i64,
-    name: &str,
-    files: Vec<File>,
-) {
-    let mut piece = piece.clone();
-    for file_data in files {
-        let start = (index - file_data.get_start_index()) as i64 * piece_length;
-        let length = file_data.get_length() - start;
-
-        let path = file_data.get_path();
-        fs::create_dir_all(format!("{}/{}", name, path[..path.len() - 1].join("/"))).unwrap();
-        let filename = &format!("{}/{}", name, path.join("/"));
-
-        let mut file = match OpenOptions::new().write(true).open(filename) {
-            Ok(file) => file,
-            Err(err) => {
-                if let io::ErrorKind::NotFound = err.kind() {
-                    fs::write(filename, vec![0u8; file_data.get_length() as usize]).unwrap();
+impl Storage for FileStorage {
+    fn get_torrent(&self) -> &Torrent {
+        &self.torrent
+    }
+
+    fn get_torrent_mut(&mut self) -> &mut Torrent {
+        &mut self.torrent
+    }
+
+    fn read_block(&mut self, piece_index: u32, begin: u64, length: u64) -> io::Result<Vec<u8>> {
+        let mut block = vec![0u8; length as usize];
+        let mut read = 0usize;
+        for (piece_offset, span) in self.block_spans(piece_index, begin, length) {
+            let target = &mut block[read..read + span.length as usize];
+            if self.is_parted(span.file_index) {
+                let data = self
+                    .part_file()?
+                    .read(piece_index, piece_offset, span.length)?;
+                target.copy_from_slice(&data);
+            } else {
+                let file = self.open(span.file_index, false)?;
+                file.seek(SeekFrom::Start(span.file_offset))?;
+                file.read_exact(target)?;
+            }
+            read += span.length as usize;
+        }
+        block.truncate(read);
+        Ok(block)
+    }
+
+    fn write_block(&mut self, piece_index: u32, begin: u64, data: &[u8]) -> io::Result<()> {
+        let mut written = 0usize;
+        for (piece_offset, span) in self.block_spans(piece_index, begin, data.len() as u64) {
+            let source = &data[written..written + span.length as usize];
+            if self.is_parted(span.file_index) {
+                self.part_file()?.write(piece_index, piece_offset, source)?;
+            } else {
+                let file = self.open(span.file_index, true)?;
+                file.seek(SeekFrom::Start(span.file_offset))?;
+                file.write_all(source)?;
+            }
+            written += span.length as usize;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for file in self.handles.values_mut() {
+            file.sync_data()?;
+        }
+        match &mut self.part_file {
+            Some(part_file) => part_file.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn move_to(&mut self, save_path: &Path) -> io::Result<()> {
+        if save_path == self.save_path {
+            return Ok(());
+        }
+        self.flush()?;
+        self.handles.clear();
+        self.parted.clear();
+        // open the part file while it can still be found
+        self.part_file()?;
+
+        let old_files = self.get_files();
+        let old_part_file_path = self.get_part_file_path();
+        let old_save_path = std::mem::replace(&mut self.save_path, save_path.to_path_buf());
+        let new_files = self.get_files();
+        let moves: Vec<(PathBuf, PathBuf)> = old_files
+            .iter()
+            .zip(&new_files)
+            .filter(|((from, _), _)| from.exists())
+            .map(|((from, _), (to, _))| (from.clone(), to.clone()))
+            .collect();
+        if let Some((_, to)) = moves.iter().find(|(_, to)| to.exists()) {
+            self.save_path = old_save_path;
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} already exists", to),
+            ));
+        }
+
+        // either every file ends up under the new save path or none does
+        let mut moved = 0;
+        let mut result = moves
+            .iter()
+            .try_for_each(|(from, to)| move_file(from, to).map(|_| moved += 1));
+        if result.is_ok() {
+            let part_file_path = self.get_part_file_path();
+            result = self
+                .part_file()
+                .and_then(|part_file| part_file.move_to(&part_file_path));
+        }
+        if let Err(err) = result {
+            for (from, to) in moves[..moved].iter().rev() {
+                if let Err(err) = move_file(to, from) {
+                    eprintln!("Error: Failed to move {:?} back: {}", to, err);
                 }
-                OpenOptions::new().write(true).open(filename).unwrap()
             }
-        };
+            remove_empty_dirs(&new_files, save_path);
+            self.save_path = old_save_path;
+            if let Some(part_file) = &mut self.part_file {
+                if part_file.get_path() != old_part_file_path {
+                    part_file.move_to(&old_part_file_path)?;
+                }
+            }
+            return Err(err);
+        }
+        remove_empty_dirs(&old_files, &old_save_path);
+        Ok(())
+    }
+
+    fn delete(&mut self) -> io::Result<()> {
+        self.handles.clear();
+        self.parted.clear();
+        self.part_file()?.delete()?;
+        let files = self.get_files();
+        for (path, _) in &files {
+            match fs::remove_file(path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => (),
+            }
+        }
+        remove_empty_dirs(&files, &self.save_path);
+        Ok(())
+    }
+}
+
+/// Reserves `length` bytes on disk for a freshly created file.
+#[cfg(target_os = "linux")]
+fn allocate(file: &fs::File, length: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    if length == 0 {
+        return Ok(());
+    }
+    match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, length as libc::off_t) } {
+        0 => Ok(()),
+        // the file system can't reserve space itself
+        libc::EOPNOTSUPP | libc::EINVAL => write_zeros(file, length),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allocate(file: &fs::File, length: u64) -> io::Result<()> {
+    write_zeros(file, length)
+}
 
-        if length >= piece_length {
-            file.seek(SeekFrom::Start(start as u64)).unwrap();
-            file.write_all(&piece).unwrap();
-        } else {
-            let length = length as usize;
-            file.seek(SeekFrom::Start(start as u64)).unwrap();
-            let to_be_write: Vec<u8> = piece.drain(..length as usize).collect();
-            file.write_all(&to_be_write);
+/// Fills the file with zeros a chunk at a time so large files never have
+/// to fit in memory.
+fn write_zeros(mut file: &fs::File, length: u64) -> io::Result<()> {
+    const CHUNK_SIZE: u64 = 1 << 20;
+
+    let chunk = vec![0u8; CHUNK_SIZE.min(length) as usize];
+    file.seek(SeekFrom::Start(0))?;
+    let mut remaining = length;
+    while remaining > 0 {
+        let size = CHUNK_SIZE.min(remaining);
+        file.write_all(&chunk[..size as usize])?;
+        remaining -= size;
+    }
+    Ok(())
+}
+
+/// Removes the directories the files were in once they are empty, up to
+/// the save path.
+fn remove_empty_dirs(files: &[(PathBuf, u64)], save_path: &Path) {
+    for (path, _) in files {
+        let mut dir = path.parent();
+        while let Some(current) = dir {
+            if current == save_path || fs::remove_dir(current).is_err() {
+                break;
+            }
+            dir = current.parent();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencoding;
+    use crate::bencoding::BDict;
+    use crate::torrent::parse_torrent_data;
+
+    /// Files `a` (6 bytes) and `b` (10 bytes) in pieces of 8 bytes.
+    fn torrent() -> Torrent {
+        let mut metainfo = b"d4:infod5:filesld6:lengthi6e4:pathl1:aeed6:lengthi10e4:pathl1:bee\
+                             e4:name4:root12:piece lengthi8e6:pieces40:"
+            .to_vec();
+        metainfo.extend(vec![0u8; 40]);
+        metainfo.extend_from_slice(b"ee");
+        let (metainfo, _) = bencoding::try_decode(&metainfo).unwrap();
+        parse_torrent_data(metainfo.as_any().downcast_ref::<BDict>().unwrap())
+    }
+
+    fn save_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("file-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&path);
+        path
+    }
+
+    #[test]
+    fn pieces_are_written_across_files() {
+        let save_path = save_path("write-piece");
+        let mut storage = FileStorage::new(&torrent(), &save_path);
+        storage
+            .write_block(0, 0, &(0..8).collect::<Vec<u8>>())
+            .unwrap();
+        storage
+            .write_block(1, 0, &(8..16).collect::<Vec<u8>>())
+            .unwrap();
+        storage.flush().unwrap();
+
+        assert_eq!(
+            fs::read(save_path.join("root/a")).unwrap(),
+            (0..6).collect::<Vec<u8>>()
+        );
+        assert_eq!(
+            fs::read(save_path.join("root/b")).unwrap(),
+            (6..16).collect::<Vec<u8>>()
+        );
+        fs::remove_dir_all(&save_path).unwrap();
+    }
+
+    #[test]
+    fn skipped_files_are_kept_in_part_file() {
+        let save_path = save_path("write-piece-skipped");
+        let mut torrent = torrent();
+        torrent.set_file_priority(0, FilePriority::Skip);
+        let mut storage = FileStorage::new(&torrent, &save_path);
+        storage
+            .write_block(0, 0, &(0..8).collect::<Vec<u8>>())
+            .unwrap();
+        storage.flush().unwrap();
+
+        assert!(!save_path.join("root/a").exists());
+        assert_eq!(fs::read(save_path.join("root/b")).unwrap()[..2], [6, 7]);
+        assert!(save_path.join(".root.parts").exists());
+
+        let mut storage = FileStorage::new(&torrent, &save_path);
+        assert_eq!(
+            storage.read_block(0, 0, 8).unwrap(),
+            (0..8).collect::<Vec<u8>>()
+        );
+        fs::remove_dir_all(&save_path).unwrap();
+    }
+
+    #[test]
+    fn unskipped_file_gets_part_file_data() {
+        let save_path = save_path("unskip");
+        let mut torrent = torrent();
+        torrent.set_file_priority(0, FilePriority::Skip);
+        let mut storage = FileStorage::new(&torrent, &save_path);
+        storage
+            .write_block(0, 0, &(0..8).collect::<Vec<u8>>())
+            .unwrap();
+        storage.flush().unwrap();
+        assert!(!save_path.join("root/a").exists());
+
+        storage.set_file_priority(0, FilePriority::Normal).unwrap();
+        storage.flush().unwrap();
+        assert_eq!(
+            fs::read(save_path.join("root/a")).unwrap(),
+            (0..6).collect::<Vec<u8>>()
+        );
+        assert_eq!(
+            storage.read_block(0, 0, 8).unwrap(),
+            (0..8).collect::<Vec<u8>>()
+        );
+        fs::remove_dir_all(&save_path).unwrap();
+    }
+
+    /// A single 1 MiB file `big` in pieces of 256 KiB.
+    fn big_torrent() -> Torrent {
+        let mut metainfo =
+            b"d4:infod6:lengthi1048576e4:name3:big12:piece lengthi262144e6:pieces80:".to_vec();
+        metainfo.extend(vec![0u8; 80]);
+        metainfo.extend_from_slice(b"ee");
+        let (metainfo, _) = bencoding::try_decode(&metainfo).unwrap();
+        parse_torrent_data(metainfo.as_any().downcast_ref::<BDict>().unwrap())
+    }
+
+    /// Length and bytes allocated on disk of `big` after writing a block to
+    /// the first piece.
+    #[cfg(unix)]
+    fn allocated(name: &str, allocation: AllocationMode) -> (u64, u64) {
+        use std::os::unix::fs::MetadataExt;
+
+        let save_path = save_path(name);
+        let mut storage = FileStorage::with_allocation(&big_torrent(), &save_path, allocation);
+        assert_eq!(storage.get_allocation(), allocation);
+        storage.write_block(0, 0, &[1; 16]).unwrap();
+        storage.flush().unwrap();
+        let metadata = fs::metadata(save_path.join("big")).unwrap();
+        fs::remove_dir_all(&save_path).unwrap();
+        (metadata.len(), metadata.blocks() * 512)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn allocation_modes() {
+        let (length, on_disk) = allocated("sparse", AllocationMode::Sparse);
+        assert_eq!(length, 1 << 20);
+        assert!(on_disk < 1 << 20);
+
+        let (length, on_disk) = allocated("full", AllocationMode::Full);
+        assert_eq!(length, 1 << 20);
+        assert!(on_disk >= 1 << 20);
+
+        let (length, on_disk) = allocated("compact", AllocationMode::Compact);
+        assert_eq!(length, 16);
+        assert!(on_disk < 1 << 20);
+    }
+
+    #[test]
+    fn compact_files_grow_as_written() {
+        let save_path = save_path("compact-grow");
+        let mut storage =
+            FileStorage::with_allocation(&big_torrent(), &save_path, AllocationMode::Compact);
+        storage.write_block(1, 16, &[1; 16]).unwrap();
+        assert_eq!(
+            fs::metadata(save_path.join("big")).unwrap().len(),
+            (1 << 18) + 32
+        );
+        storage.write_block(3, (1 << 18) - 16, &[1; 16]).unwrap();
+        assert_eq!(fs::metadata(save_path.join("big")).unwrap().len(), 1 << 20);
+        assert_eq!(storage.read_block(1, 0, 32).unwrap()[..16], [0; 16]);
+        fs::remove_dir_all(&save_path).unwrap();
+    }
+
+    #[test]
+    fn allocation_applies_to_new_files_only() {
+        let save_path = save_path("allocation-change");
+        let mut storage =
+            FileStorage::with_allocation(&big_torrent(), &save_path, AllocationMode::Compact);
+        storage.write_block(0, 0, &[1; 16]).unwrap();
+        storage.set_allocation(AllocationMode::Sparse);
+        storage.write_block(0, 16, &[1; 16]).unwrap();
+        assert_eq!(fs::metadata(save_path.join("big")).unwrap().len(), 32);
+
+        let mut storage = FileStorage::new(&big_torrent(), &save_path);
+        storage.write_block(0, 32, &[1; 16]).unwrap();
+        assert_eq!(fs::metadata(save_path.join("big")).unwrap().len(), 48);
+        fs::remove_dir_all(&save_path).unwrap();
+    }
+
+    /// Writes 0..16 to the small torrent, `a` is skipped and kept in the
+    /// part file when `skip_a` is set.
+    fn write_all(save_path: &Path, skip_a: bool) -> FileStorage {
+        let mut torrent = torrent();
+        if skip_a {
+            torrent.set_file_priority(0, FilePriority::Skip);
+        }
+        let mut storage = FileStorage::new(&torrent, save_path);
+        storage
+            .write_block(0, 0, &(0..8).collect::<Vec<u8>>())
+            .unwrap();
+        storage
+            .write_block(1, 0, &(8..16).collect::<Vec<u8>>())
+            .unwrap();
+        storage.flush().unwrap();
+        storage
+    }
+
+    fn read_all(storage: &mut FileStorage) -> Vec<u8> {
+        let mut data = storage.read_block(0, 0, 8).unwrap();
+        data.extend(storage.read_block(1, 0, 8).unwrap());
+        data
+    }
+
+    #[test]
+    fn move_to_moves_files_and_part_file() {
+        let from = save_path("move-from");
+        let to = save_path("move-to");
+        let mut storage = write_all(&from, true);
+        assert!(from.join(".root.parts").exists());
+
+        storage.move_to(&to).unwrap();
+        assert_eq!(storage.get_save_path(), to);
+        assert!(!from.join("root").exists());
+        assert!(!from.join(".root.parts").exists());
+        assert!(to.join("root/b").exists());
+        assert!(to.join(".root.parts").exists());
+        assert!(!to.join("root/a").exists());
+        assert_eq!(read_all(&mut storage), (0..16).collect::<Vec<u8>>());
+        fs::remove_dir_all(&from).unwrap();
+        fs::remove_dir_all(&to).unwrap();
+    }
+
+    #[test]
+    fn move_to_fails_when_a_destination_exists() {
+        let from = save_path("move-exists-from");
+        let to = save_path("move-exists-to");
+        let mut storage = write_all(&from, false);
+        fs::create_dir_all(to.join("root")).unwrap();
+        fs::write(to.join("root/b"), b"other").unwrap();
+
+        let err = storage.move_to(&to).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(storage.get_save_path(), from);
+        assert!(!to.join("root/a").exists());
+        assert_eq!(fs::read(to.join("root/b")).unwrap(), b"other");
+        assert_eq!(read_all(&mut storage), (0..16).collect::<Vec<u8>>());
+        fs::remove_dir_all(&from).unwrap();
+        fs::remove_dir_all(&to).unwrap();
+    }
+
+    #[test]
+    fn move_to_rolls_back_a_failed_move() {
+        let from = save_path("move-back-from");
+        let to = save_path("move-back-to");
+        let mut storage = write_all(&from, true);
+        // the files move, then the part file can't
+        fs::create_dir_all(to.join(".root.parts/blocked")).unwrap();
+
+        assert!(storage.move_to(&to).is_err());
+        assert_eq!(storage.get_save_path(), from);
+        assert!(from.join("root/b").exists());
+        assert!(from.join(".root.parts").is_file());
+        assert!(!to.join("root").exists());
+        assert_eq!(read_all(&mut storage), (0..16).collect::<Vec<u8>>());
+        fs::remove_dir_all(&from).unwrap();
+        fs::remove_dir_all(&to).unwrap();
+    }
+
+    #[test]
+    fn finished_downloads_move_to_the_save_path() {
+        let save_path = save_path("complete");
+        let incomplete_path = self::save_path("incomplete");
+        let mut storage =
+            FileStorage::with_incomplete_path(&torrent(), &save_path, &incomplete_path);
+        assert_eq!(storage.get_save_path(), incomplete_path);
+        assert_eq!(storage.get_complete_path(), Some(save_path.as_path()));
+
+        storage
+            .write_block(0, 0, &(0..8).collect::<Vec<u8>>())
+            .unwrap();
+        storage
+            .write_block(1, 0, &(8..16).collect::<Vec<u8>>())
+            .unwrap();
+        storage.get_torrent_mut().set_piece(0);
+        assert!(!storage.finish().unwrap());
+        assert!(incomplete_path.join("root/a").exists());
+
+        storage.get_torrent_mut().set_piece(1);
+        assert!(storage.finish().unwrap());
+        assert_eq!(storage.get_save_path(), save_path);
+        assert_eq!(storage.get_complete_path(), None);
+        assert!(!incomplete_path.join("root").exists());
+        assert_eq!(
+            fs::read(save_path.join("root/b")).unwrap(),
+            (6..16).collect::<Vec<u8>>()
+        );
+        assert!(!storage.finish().unwrap());
+
+        // data already in the save path is used where it is
+        let storage = FileStorage::with_incomplete_path(&torrent(), &save_path, &incomplete_path);
+        assert_eq!(storage.get_save_path(), save_path);
+        assert_eq!(storage.get_complete_path(), None);
+        fs::remove_dir_all(&save_path).unwrap();
+        let _ = fs::remove_dir_all(&incomplete_path);
+    }
+}
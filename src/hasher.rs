@@ -0,0 +1,445 @@
+use crate::torrent::Torrent;
+
+use sha1::{Digest, Sha1};
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// Bytes of piece data allowed to wait for a hashing thread.
+pub const DEFAULT_MAX_PENDING: usize = 64 * 1024 * 1024;
+
+/// Checks downloaded pieces against their hashes on worker threads so the
+/// networking thread never hashes. Pieces are submitted whole or block by
+/// block as they arrive, either way every piece produces one pass or fail
+/// result on `recv`/`try_recv`. Submitting waits while more than
+/// `max_pending` bytes are queued or held, blocks that arrive ahead of the
+/// rest of their piece are held until they can be hashed. A piece whose
+/// held blocks would take the total past the limit, or that gets a block
+/// overlapping one it already has, fails.
+pub struct HashPool {
+    inner: Arc<Inner>,
+    jobs: Option<Sender<Job>>,
+    results: Receiver<(u32, bool)>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+struct Inner {
+    hashes: Vec<[u8; 20]>,
+    piece_sizes: Vec<u64>,
+    pieces: Mutex<HashMap<u32, Arc<Mutex<PartialPiece>>>>,
+    pending: Mutex<Pending>,
+    pending_changed: Condvar,
+    max_pending: usize,
+}
+
+#[derive(Default)]
+struct Pending {
+    /// Bytes submitted and not yet taken by a hashing thread.
+    queued: usize,
+    /// Bytes of blocks waiting for the blocks before them.
+    held: usize,
+}
+
+/// A piece hashed block by block, blocks that arrive ahead of the next
+/// expected offset wait in `blocks`. A closed piece is finished or reset
+/// and no longer in `pieces`.
+#[derive(Default)]
+struct PartialPiece {
+    hasher: Sha1,
+    hashed: u64,
+    blocks: BTreeMap<u64, Vec<u8>>,
+    held: usize,
+    closed: bool,
+}
+
+enum Job {
+    Piece(u32, Vec<u8>),
+    Block(u32, u64, Vec<u8>),
+}
+
+impl HashPool {
+    /// Starts `threads` hashing threads, 0 uses one per core.
+    pub fn new(torrent: &Torrent, threads: usize, max_pending: usize) -> HashPool {
+        let threads = match threads {
+            0 => thread::available_parallelism().map_or(1, |threads| threads.get()),
+            threads => threads,
+        };
+        let piece_count = torrent.get_piece_count();
+        let inner = Arc::new(Inner {
+            hashes: (0..piece_count)
+                .map(|index| *torrent.get_piece_hash(index))
+                .collect(),
+            piece_sizes: (0..piece_count)
+                .map(|index| torrent.get_piece_size(index))
+                .collect(),
+            pieces: Mutex::new(HashMap::new()),
+            pending: Mutex::new(Pending::default()),
+            pending_changed: Condvar::new(),
+            max_pending,
+        });
+
+        let (jobs, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let (result_sender, results) = mpsc::channel();
+        let workers = (0..threads)
+            .map(|_| {
+                let inner = Arc::clone(&inner);
+                let receiver = Arc::clone(&receiver);
+                let result_sender = result_sender.clone();
+                thread::spawn(move || work(inner, receiver, result_sender))
+            })
+            .collect();
+
+        HashPool {
+            inner,
+            jobs: Some(jobs),
+            results,
+            workers,
+        }
+    }
+
+    /// Queues a whole piece.
+    pub fn submit_piece(&self, piece_index: u32, piece: Vec<u8>) {
+        self.inner.reserve(piece.len());
+        self.send(Job::Piece(piece_index, piece));
+    }
+
+    /// Queues a block of a piece, hashing starts before the piece is
+    /// complete. Blocks may come in any order but each one only once, a
+    /// block overlapping an earlier one fails the piece.
+    pub fn submit_block(&self, piece_index: u32, begin: u64, data: Vec<u8>) {
+        self.inner.reserve(data.len());
+        self.send(Job::Block(piece_index, begin, data));
+    }
+
+    /// Forgets the blocks of a piece hashed so far, for a piece that is
+    /// downloaded again from the start.
+    pub fn reset_piece(&self, piece_index: u32) {
+        let piece = self.inner.pieces.lock().unwrap().remove(&piece_index);
+        if let Some(piece) = piece {
+            self.inner.close(&mut piece.lock().unwrap());
+        }
+    }
+
+    /// Bytes queued for the hashing threads or held until the blocks before
+    /// them arrive.
+    pub fn get_pending(&self) -> usize {
+        let pending = self.inner.pending.lock().unwrap();
+        pending.queued + pending.held
+    }
+
+    /// Waits for the next result, None once the pool is shut down.
+    pub fn recv(&self) -> Option<(u32, bool)> {
+        self.results.recv().ok()
+    }
+
+    pub fn try_recv(&self) -> Option<(u32, bool)> {
+        self.results.try_recv().ok()
+    }
+
+    fn send(&self, job: Job) {
+        if let Some(jobs) = &self.jobs {
+            jobs.send(job).unwrap();
+        }
+    }
+}
+
+impl Drop for HashPool {
+    fn drop(&mut self) {
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+}
+
+impl Inner {
+    /// Waits until `size` more bytes fit. Held blocks only leave once more
+    /// blocks are hashed, so anything is let through once nothing is queued.
+    fn reserve(&self, size: usize) {
+        let mut pending = self.pending.lock().unwrap();
+        while pending.queued > 0 && pending.queued + pending.held + size > self.max_pending {
+            pending = self.pending_changed.wait(pending).unwrap();
+        }
+        pending.queued += size;
+    }
+
+    fn release(&self, size: usize) {
+        self.pending.lock().unwrap().queued -= size;
+        self.pending_changed.notify_all();
+    }
+
+    /// Moves bytes between held and not held, returns the total held.
+    fn change_held(&self, added: usize, removed: usize) -> usize {
+        let mut pending = self.pending.lock().unwrap();
+        pending.held = pending.held + added - removed;
+        if removed > 0 {
+            self.pending_changed.notify_all();
+        }
+        pending.held
+    }
+
+    /// Drops the held blocks of a piece that is done with.
+    fn close(&self, piece: &mut PartialPiece) {
+        if !piece.closed {
+            piece.closed = true;
+            piece.blocks.clear();
+            self.change_held(0, piece.held);
+            piece.held = 0;
+        }
+    }
+
+    fn check(&self, piece_index: u32, digest: &[u8]) -> bool {
+        match self.hashes.get(piece_index as usize) {
+            Some(hash) => digest == hash,
+            None => false,
+        }
+    }
+
+    /// Feeds the block to the piece, returns the result once every byte of
+    /// the piece is hashed or the piece failed.
+    fn add_block(&self, piece_index: u32, begin: u64, data: Vec<u8>) -> Option<bool> {
+        let piece_size = match self.piece_sizes.get(piece_index as usize) {
+            Some(piece_size) => *piece_size,
+            None => return Some(false),
+        };
+        loop {
+            let piece = Arc::clone(self.pieces.lock().unwrap().entry(piece_index).or_default());
+            let mut guard = piece.lock().unwrap();
+            // reset or finished while we waited, the next block starts over
+            if guard.closed {
+                continue;
+            }
+
+            let result = self.add_to_piece(&mut guard, piece_index, piece_size, begin, data);
+            if result.is_some() {
+                self.close(&mut guard);
+                drop(guard);
+                let mut pieces = self.pieces.lock().unwrap();
+                if pieces
+                    .get(&piece_index)
+                    .is_some_and(|current| Arc::ptr_eq(current, &piece))
+                {
+                    pieces.remove(&piece_index);
+                }
+            }
+            return result;
+        }
+    }
+
+    fn add_to_piece(
+        &self,
+        piece: &mut PartialPiece,
+        piece_index: u32,
+        piece_size: u64,
+        begin: u64,
+        data: Vec<u8>,
+    ) -> Option<bool> {
+        let end = begin + data.len() as u64;
+        let overlaps_before = begin < piece.hashed
+            || piece
+                .blocks
+                .range(..=begin)
+                .next_back()
+                .is_some_and(|(start, block)| start + block.len() as u64 > begin);
+        let overlaps_after = piece.blocks.range(begin..end).next().is_some();
+        if overlaps_before || overlaps_after || end > piece_size {
+            return Some(false);
+        }
+
+        if begin > piece.hashed {
+            piece.held += data.len();
+            let held = self.change_held(data.len(), 0);
+            piece.blocks.insert(begin, data);
+            return if held > self.max_pending {
+                Some(false)
+            } else {
+                None
+            };
+        }
+
+        piece.hasher.update(&data);
+        piece.hashed += data.len() as u64;
+        let mut unheld = 0;
+        while let Some(block) = piece.blocks.remove(&piece.hashed) {
+            piece.hasher.update(&block);
+            piece.hashed += block.len() as u64;
+            unheld += block.len();
+        }
+        if unheld > 0 {
+            piece.held -= unheld;
+            self.change_held(0, unheld);
+        }
+        if piece.hashed < piece_size {
+            return None;
+        }
+
+        let digest = piece.hasher.clone().finalize();
+        Some(self.check(piece_index, &digest))
+    }
+}
+
+fn work(inner: Arc<Inner>, jobs: Arc<Mutex<Receiver<Job>>>, results: Sender<(u32, bool)>) {
+    loop {
+        let job = jobs.lock().unwrap().recv();
+        let result = match job {
+            Ok(Job::Piece(piece_index, piece)) => {
+                let passed = inner.check(piece_index, &Sha1::digest(&piece));
+                inner.release(piece.len());
+                Some((piece_index, passed))
+            }
+            Ok(Job::Block(piece_index, begin, data)) => {
+                let size = data.len();
+                let passed = inner.add_block(piece_index, begin, data);
+                inner.release(size);
+                passed.map(|passed| (piece_index, passed))
+            }
+            Err(_) => return,
+        };
+        if let Some(result) = result {
+            // nobody listens once the pool is dropped
+            let _ = results.send(result);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencoding;
+    use crate::bencoding::BDict;
+    use crate::torrent::parse_torrent_data;
+
+    use std::time::Duration;
+
+    /// 40 bytes of data in pieces of 16, the last piece is 8 bytes.
+    fn data() -> Vec<u8> {
+        (0..40).collect()
+    }
+
+    fn torrent() -> Torrent {
+        let data = data();
+        let mut metainfo =
+            b"d4:infod6:lengthi40e4:name4:file12:piece lengthi16e6:pieces60:".to_vec();
+        for piece in data.chunks(16) {
+            metainfo.extend_from_slice(&Sha1::digest(piece));
+        }
+        metainfo.extend_from_slice(b"ee");
+        let (metainfo, _) = bencoding::try_decode(&metainfo).unwrap();
+        parse_torrent_data(metainfo.as_any().downcast_ref::<BDict>().unwrap())
+    }
+
+    fn piece(piece_index: u32) -> Vec<u8> {
+        data()
+            .chunks(16)
+            .nth(piece_index as usize)
+            .unwrap()
+            .to_vec()
+    }
+
+    #[test]
+    fn whole_pieces_pass_or_fail() {
+        let pool = HashPool::new(&torrent(), 2, DEFAULT_MAX_PENDING);
+        pool.submit_piece(0, piece(0));
+        assert_eq!(pool.recv(), Some((0, true)));
+        pool.submit_piece(1, piece(0));
+        assert_eq!(pool.recv(), Some((1, false)));
+        pool.submit_piece(7, piece(0));
+        assert_eq!(pool.recv(), Some((7, false)));
+        assert_eq!(pool.get_pending(), 0);
+    }
+
+    #[test]
+    fn out_of_order_blocks() {
+        let pool = HashPool::new(&torrent(), 2, DEFAULT_MAX_PENDING);
+        let piece = piece(1);
+        pool.submit_block(1, 12, piece[12..].to_vec());
+        pool.submit_block(1, 4, piece[4..12].to_vec());
+        pool.submit_block(1, 0, piece[..4].to_vec());
+        assert_eq!(pool.recv(), Some((1, true)));
+        assert_eq!(pool.get_pending(), 0);
+
+        let mut corrupt = piece.clone();
+        corrupt[15] ^= 1;
+        pool.submit_block(1, 8, corrupt[8..].to_vec());
+        pool.submit_block(1, 0, corrupt[..8].to_vec());
+        assert_eq!(pool.recv(), Some((1, false)));
+    }
+
+    #[test]
+    fn short_last_piece() {
+        let pool = HashPool::new(&torrent(), 1, DEFAULT_MAX_PENDING);
+        let piece = piece(2);
+        assert_eq!(piece.len(), 8);
+        pool.submit_block(2, 4, piece[4..].to_vec());
+        pool.submit_block(2, 0, piece[..4].to_vec());
+        assert_eq!(pool.recv(), Some((2, true)));
+
+        // data past the end of the piece
+        pool.submit_block(2, 0, vec![0; 16]);
+        assert_eq!(pool.recv(), Some((2, false)));
+    }
+
+    #[test]
+    fn overlapping_blocks_fail_the_piece() {
+        let pool = HashPool::new(&torrent(), 1, DEFAULT_MAX_PENDING);
+        let piece = piece(0);
+        pool.submit_block(0, 8, piece[8..].to_vec());
+        pool.submit_block(0, 8, piece[8..].to_vec());
+        assert_eq!(pool.recv(), Some((0, false)));
+        assert_eq!(pool.get_pending(), 0);
+
+        pool.submit_block(0, 0, piece[..8].to_vec());
+        pool.submit_block(0, 4, piece[4..].to_vec());
+        assert_eq!(pool.recv(), Some((0, false)));
+        assert!(pool.inner.pieces.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn held_blocks_count_against_the_limit() {
+        let pool = HashPool::new(&torrent(), 1, 10);
+        let piece = piece(0);
+        pool.submit_block(0, 12, piece[12..].to_vec());
+        pool.submit_block(0, 8, piece[8..12].to_vec());
+        while pool.inner.pending.lock().unwrap().queued > 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(pool.get_pending(), 8);
+
+        // one more held block takes the piece past the limit
+        pool.submit_block(0, 4, piece[4..8].to_vec());
+        assert_eq!(pool.recv(), Some((0, false)));
+        assert_eq!(pool.get_pending(), 0);
+
+        // resetting a piece drops its held blocks
+        pool.submit_block(1, 4, vec![0; 4]);
+        while pool.inner.pending.lock().unwrap().queued > 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(pool.get_pending(), 4);
+        pool.reset_piece(1);
+        assert_eq!(pool.get_pending(), 0);
+    }
+
+    #[test]
+    fn submitting_waits_while_pending_is_full() {
+        let pool = HashPool::new(&torrent(), 1, 16);
+        let inner = Arc::clone(&pool.inner);
+        inner.reserve(16);
+
+        let (done, waiting) = mpsc::channel();
+        let waiter = Arc::clone(&inner);
+        thread::spawn(move || {
+            waiter.reserve(4);
+            done.send(()).unwrap();
+        });
+        assert!(waiting.recv_timeout(Duration::from_millis(100)).is_err());
+
+        inner.release(16);
+        assert!(waiting.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert_eq!(pool.get_pending(), 4);
+        inner.release(4);
+    }
+}
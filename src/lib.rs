@@ -1,10 +1,20 @@
 mod bencoding;
-mod client;
+pub mod client;
+pub mod dht;
+pub mod file;
+pub mod hasher;
+pub mod lsd;
 mod message;
-mod torrent;
-mod tracker;
+pub mod peer_pool;
+pub mod pex;
+pub mod resume;
+pub mod storage;
+pub mod torrent;
+pub mod tracker;
 mod utility;
 
+pub use message::MessageId;
+
 /***
 
 //without upload
@@ -0,0 +1,342 @@
+use crate::peer_pool::{PeerPool, PeerSource};
+use crate::tracker::Peer;
+use crate::utility::encode_hex;
+
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+pub const LSD_MULTICAST_ADDR: SocketAddrV4 =
+    SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6771);
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// BEP 14 asks for no more than one announce per minute per torrent.
+pub const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Debug)]
+pub struct LsdConfig {
+    /// Port our peer listener accepts connections on.
+    pub listen_port: u16,
+    pub multicast_addr: SocketAddrV4,
+    pub interface: Ipv4Addr,
+    pub interval: Duration,
+    /// Receive our own announces, needed when several clients share a host.
+    pub multicast_loop: bool,
+}
+
+impl LsdConfig {
+    pub fn new(listen_port: u16) -> LsdConfig {
+        LsdConfig {
+            listen_port,
+            multicast_addr: LSD_MULTICAST_ADDR,
+            interface: Ipv4Addr::UNSPECIFIED,
+            interval: ANNOUNCE_INTERVAL,
+            multicast_loop: true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Announce {
+    port: u16,
+    info_hashes: Vec<Vec<u8>>,
+    cookie: Option<String>,
+}
+
+impl Announce {
+    pub fn get_port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn get_info_hashes(&self) -> &Vec<Vec<u8>> {
+        &self.info_hashes
+    }
+}
+
+struct LsdTorrent {
+    pool: Arc<Mutex<PeerPool>>,
+    last_announce: Option<Instant>,
+}
+
+/// Local Service Discovery, announces active torrents on the LAN multicast
+/// group and adds peers announcing the same torrents to their peer pools.
+/// Start it with `Lsd::start` and register each torrent's pool with
+/// `add_torrent`.
+pub struct Lsd {
+    inner: Arc<Inner>,
+    handle: Option<JoinHandle<()>>,
+}
+
+struct Inner {
+    socket: UdpSocket,
+    config: LsdConfig,
+    cookie: String,
+    torrents: Mutex<HashMap<Vec<u8>, LsdTorrent>>,
+    running: AtomicBool,
+}
+
+impl Lsd {
+    pub fn start(config: LsdConfig) -> io::Result<Lsd> {
+        let socket = Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp()))?;
+        socket.set_reuse_address(true)?;
+        let bind_addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, config.multicast_addr.port());
+        socket.bind(&SockAddr::from(bind_addr))?;
+        if !config.interface.is_unspecified() {
+            socket.set_multicast_if_v4(&config.interface)?;
+        }
+
+        let socket = socket.into_udp_socket();
+        socket.join_multicast_v4(config.multicast_addr.ip(), &config.interface)?;
+        socket.set_multicast_loop_v4(config.multicast_loop)?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+
+        let inner = Arc::new(Inner {
+            socket,
+            config,
+            cookie: encode_hex(&rand::random::<[u8; 4]>()),
+            torrents: Mutex::new(HashMap::new()),
+            running: AtomicBool::new(true),
+        });
+
+        let receiver = Arc::clone(&inner);
+        let handle = thread::spawn(move || receiver.run());
+
+        Ok(Lsd {
+            inner,
+            handle: Some(handle),
+        })
+    }
+
+    /// Starts announcing the torrent, discovered peers go into `pool`.
+    pub fn add_torrent(&self, info_hash: &[u8], pool: Arc<Mutex<PeerPool>>) {
+        self.inner.torrents.lock().unwrap().insert(
+            info_hash.to_vec(),
+            LsdTorrent {
+                pool,
+                last_announce: None,
+            },
+        );
+        self.inner.announce_due();
+    }
+
+    pub fn remove_torrent(&self, info_hash: &[u8]) {
+        self.inner.torrents.lock().unwrap().remove(info_hash);
+    }
+}
+
+impl Drop for Lsd {
+    fn drop(&mut self) {
+        self.inner.running.store(false, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            handle.join().unwrap();
+        }
+    }
+}
+
+impl Inner {
+    fn run(&self) {
+        let mut buffer = [0u8; 1500];
+        let mut last_check = Instant::now();
+        while self.running.load(Ordering::SeqCst) {
+            if last_check.elapsed() >= POLL_INTERVAL {
+                self.announce_due();
+                last_check = Instant::now();
+            }
+
+            let (length, addr) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(_) => continue,
+            };
+            if let Some(announce) = parse_announce(&buffer[..length]) {
+                self.handle_announce(announce, addr);
+            }
+        }
+    }
+
+    /// Announces every torrent whose interval elapsed, batching the info
+    /// hashes into as few messages as possible.
+    fn announce_due(&self) {
+        let mut due = Vec::new();
+        for (info_hash, torrent) in self.torrents.lock().unwrap().iter_mut() {
+            let interval = self.config.interval.max(MIN_ANNOUNCE_INTERVAL);
+            if torrent
+                .last_announce
+                .is_none_or(|last| last.elapsed() >= interval)
+            {
+                torrent.last_announce = Some(Instant::now());
+                due.push(info_hash.clone());
+            }
+        }
+
+        for info_hashes in due.chunks(10) {
+            let message = build_announce(
+                &self.config.multicast_addr,
+                self.config.listen_port,
+                info_hashes,
+                &self.cookie,
+            );
+            if let Err(error) = self
+                .socket
+                .send_to(&message, SocketAddr::V4(self.config.multicast_addr))
+            {
+                eprintln!("Error: LSD announce failed: {}", error);
+            }
+        }
+    }
+
+    fn handle_announce(&self, announce: Announce, addr: SocketAddr) {
+        if announce.cookie.as_deref() == Some(&self.cookie[..]) {
+            return;
+        }
+        let torrents = self.torrents.lock().unwrap();
+        for info_hash in &announce.info_hashes {
+            if let Some(torrent) = torrents.get(info_hash) {
+                torrent.pool.lock().unwrap().add(
+                    Peer::new(SocketAddr::new(addr.ip(), announce.port)),
+                    PeerSource::Lsd,
+                );
+            }
+        }
+    }
+}
+
+pub fn build_announce(
+    multicast_addr: &SocketAddrV4,
+    port: u16,
+    info_hashes: &[Vec<u8>],
+    cookie: &str,
+) -> Vec<u8> {
+    let mut message = String::from("BT-SEARCH * HTTP/1.1\r\n");
+    message.push_str(&format!("Host: {}\r\n", multicast_addr));
+    message.push_str(&format!("Port: {}\r\n", port));
+    for info_hash in info_hashes {
+        message.push_str(&format!("Infohash: {}\r\n", encode_hex(info_hash)));
+    }
+    message.push_str(&format!("cookie: {}\r\n", cookie));
+    message.push_str("\r\n\r\n");
+    message.into_bytes()
+}
+
+pub fn parse_announce(message: &[u8]) -> Option<Announce> {
+    let message = str::from_utf8(message).ok()?;
+    let mut lines = message.split("\r\n");
+    if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+        return None;
+    }
+
+    let mut port = None;
+    let mut info_hashes = Vec::new();
+    let mut cookie = None;
+    for line in lines {
+        let (name, value) = match line.find(':') {
+            Some(position) => (&line[..position], line[position + 1..].trim()),
+            None => continue,
+        };
+        match &name.trim().to_ascii_lowercase()[..] {
+            "port" => port = value.parse::<u16>().ok(),
+            "infohash" => info_hashes.push(decode_hex(value)?),
+            "cookie" => cookie = Some(value.to_string()),
+            _ => (),
+        }
+    }
+
+    if info_hashes.is_empty() {
+        return None;
+    }
+    Some(Announce {
+        port: port.filter(|port| *port != 0)?,
+        info_hashes,
+        cookie,
+    })
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() != 40 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loopback_config(listen_port: u16, group_port: u16) -> LsdConfig {
+        let mut config = LsdConfig::new(listen_port);
+        config.multicast_addr = SocketAddrV4::new(*LSD_MULTICAST_ADDR.ip(), group_port);
+        config.interface = Ipv4Addr::LOCALHOST;
+        config
+    }
+
+    fn wait_for_peer(pool: &Arc<Mutex<PeerPool>>, peer: &Peer) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            if pool.lock().unwrap().contains(peer) {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        false
+    }
+
+    #[test]
+    fn announces_reach_other_clients() {
+        let info_hash = vec![0xab; 20];
+        let first = Lsd::start(loopback_config(6001, 16771)).unwrap();
+        let second = Lsd::start(loopback_config(6002, 16771)).unwrap();
+        let first_pool = Arc::new(Mutex::new(PeerPool::new()));
+        let second_pool = Arc::new(Mutex::new(PeerPool::new()));
+
+        first.add_torrent(&info_hash, Arc::clone(&first_pool));
+        second.add_torrent(&info_hash, Arc::clone(&second_pool));
+
+        let first_peer = Peer::new(SocketAddr::from(([127, 0, 0, 1], 6001)));
+        let second_peer = Peer::new(SocketAddr::from(([127, 0, 0, 1], 6002)));
+        assert!(wait_for_peer(&first_pool, &second_peer));
+        assert!(wait_for_peer(&second_pool, &first_peer));
+        assert_eq!(
+            first_pool.lock().unwrap().get_source(&second_peer),
+            Some(PeerSource::Lsd)
+        );
+        // our own announces come back over the loop and are ignored
+        assert!(!first_pool.lock().unwrap().contains(&first_peer));
+    }
+
+    #[test]
+    fn other_torrents_are_ignored() {
+        let first = Lsd::start(loopback_config(6003, 16772)).unwrap();
+        let second = Lsd::start(loopback_config(6004, 16772)).unwrap();
+        let first_pool = Arc::new(Mutex::new(PeerPool::new()));
+        let second_pool = Arc::new(Mutex::new(PeerPool::new()));
+
+        first.add_torrent(&[0x01; 20], Arc::clone(&first_pool));
+        second.add_torrent(&[0x02; 20], Arc::clone(&second_pool));
+
+        let second_peer = Peer::new(SocketAddr::from(([127, 0, 0, 1], 6004)));
+        assert!(!wait_for_peer(&first_pool, &second_peer));
+    }
+
+    #[test]
+    fn announce_round_trip() {
+        let info_hashes = vec![vec![0x12; 20], vec![0x34; 20]];
+        let message = build_announce(&LSD_MULTICAST_ADDR, 6881, &info_hashes, "cafe");
+        let announce = parse_announce(&message).unwrap();
+        assert_eq!(announce.get_port(), 6881);
+        assert_eq!(announce.get_info_hashes(), &info_hashes);
+        assert_eq!(announce.cookie.as_deref(), Some("cafe"));
+
+        assert!(parse_announce(b"BT-SEARCH * HTTP/1.1\r\nPort: 6881\r\n\r\n\r\n").is_none());
+        assert!(parse_announce(b"M-SEARCH * HTTP/1.1\r\n\r\n").is_none());
+    }
+}
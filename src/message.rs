@@ -1,3 +1,8 @@
+use crate::bencoding;
+use crate::bencoding::{BDict, BInt, BString, BType};
+
+use std::collections::BTreeMap;
+
 pub enum MessageId {
     Choke = 0,
     UnChoke = 1,
@@ -9,6 +14,7 @@ pub enum MessageId {
     Piece = 7,
     Cancel = 8,
     Port = 9,
+    Extended = 20,
     KeepAlive,
     Invalid = 999,
 }
@@ -26,6 +32,7 @@ impl MessageId {
             7 => MessageId::Piece,
             8 => MessageId::Cancel,
             9 => MessageId::Port,
+            20 => MessageId::Extended,
             _ => MessageId::Invalid,
         }
     }
@@ -42,6 +49,10 @@ pub fn serialize_message(id: MessageId, payload: Option<Vec<u8>>) -> Vec<u8> {
                 panic!("BitField and Piece must have a payload")
             }
         }
+        MessageId::Extended => match &payload {
+            Some(payload) => payload.len() as u32 + 1,
+            None => panic!("Extended must have a payload"),
+        },
         _ => length_prefix(&id),
     };
 
@@ -54,6 +65,49 @@ pub fn serialize_message(id: MessageId, payload: Option<Vec<u8>>) -> Vec<u8> {
     message
 }
 
+/// Serializes a BEP 10 extended message, extended id 0 is the extension handshake.
+pub fn serialize_extended(extended_id: u8, mut payload: Vec<u8>) -> Vec<u8> {
+    let mut extended = vec![extended_id];
+    extended.append(&mut payload);
+    serialize_message(MessageId::Extended, Some(extended))
+}
+
+pub fn extension_handshake(extensions: &[(&str, u8)]) -> Vec<u8> {
+    let mut m = BDict::new(BTreeMap::new());
+    for (name, id) in extensions {
+        m.insert(
+            BString::new(&name.as_bytes().to_vec()),
+            Box::new(BInt::new(*id as i64)),
+        );
+    }
+
+    let mut handshake = BDict::new(BTreeMap::new());
+    handshake.insert(BString::new(&b"m".to_vec()), Box::new(m));
+    handshake.encode()
+}
+
+/// Parses the `m` dictionary of a received extension handshake.
+pub fn parse_extension_handshake(payload: &[u8]) -> BTreeMap<String, u8> {
+    let mut extensions = BTreeMap::new();
+    let handshake = match bencoding::try_decode(payload) {
+        Some((handshake, _)) => handshake,
+        None => return extensions,
+    };
+    let m = match handshake.as_any().downcast_ref::<BDict>() {
+        Some(handshake) => handshake.get::<BDict>("m"),
+        None => None,
+    };
+    if let Some(m) = m {
+        for (name, id) in m.iter() {
+            if let (Some(name), Some(id)) = (name.into_string(), id.as_any().downcast_ref::<BInt>())
+            {
+                extensions.insert(name, id.into_int() as u8);
+            }
+        }
+    }
+    extensions
+}
+
 fn length_prefix(id: &MessageId) -> u32 {
     match id {
         MessageId::Choke
@@ -0,0 +1,190 @@
+use crate::tracker::Peer;
+
+use std::collections::HashMap;
+
+pub const MAX_POOL_SIZE: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PeerSource {
+    Tracker,
+    Pex,
+    Dht,
+    Lsd,
+}
+
+#[derive(Clone, Debug)]
+struct PeerEntry {
+    source: PeerSource,
+    flags: u8,
+    connected: bool,
+}
+
+/// Peers known for one torrent and where they came from. Trackers, PEX,
+/// DHT and LSD all feed the same pool, share it between them and the peer
+/// connections as `Arc<Mutex<PeerPool>>`.
+#[derive(Debug)]
+pub struct PeerPool {
+    peers: HashMap<Peer, PeerEntry>,
+    max_peers: usize,
+}
+
+impl PeerPool {
+    pub fn new() -> PeerPool {
+        PeerPool::with_capacity(MAX_POOL_SIZE)
+    }
+
+    pub fn with_capacity(max_peers: usize) -> PeerPool {
+        PeerPool {
+            peers: HashMap::new(),
+            max_peers,
+        }
+    }
+
+    /// Adds a peer to the pool, returns false if the peer is already known
+    /// or the pool is full.
+    pub fn add(&mut self, peer: Peer, source: PeerSource) -> bool {
+        self.add_with_flags(peer, source, 0)
+    }
+
+    pub fn add_with_flags(&mut self, peer: Peer, source: PeerSource, flags: u8) -> bool {
+        if let Some(entry) = self.peers.get_mut(&peer) {
+            entry.flags |= flags;
+            return false;
+        }
+        if self.peers.len() >= self.max_peers {
+            return false;
+        }
+
+        self.peers.insert(
+            peer,
+            PeerEntry {
+                source,
+                flags,
+                connected: false,
+            },
+        );
+        true
+    }
+
+    pub fn extend(&mut self, peers: Vec<Peer>, source: PeerSource) -> usize {
+        let mut added = 0;
+        for peer in peers {
+            if self.add(peer, source) {
+                added += 1;
+            }
+        }
+        added
+    }
+
+    pub fn remove(&mut self, peer: &Peer) {
+        self.peers.remove(peer);
+    }
+
+    pub fn contains(&self, peer: &Peer) -> bool {
+        self.peers.contains_key(peer)
+    }
+
+    pub fn get_source(&self, peer: &Peer) -> Option<PeerSource> {
+        self.peers.get(peer).map(|entry| entry.source)
+    }
+
+    pub fn get_flags(&self, peer: &Peer) -> u8 {
+        match self.peers.get(peer) {
+            Some(entry) => entry.flags,
+            None => 0,
+        }
+    }
+
+    pub fn set_flags(&mut self, peer: &Peer, flags: u8) {
+        if let Some(entry) = self.peers.get_mut(peer) {
+            entry.flags = flags;
+        }
+    }
+
+    pub fn is_connected(&self, peer: &Peer) -> bool {
+        self.peers.get(peer).is_some_and(|entry| entry.connected)
+    }
+
+    /// Marks a known peer as having an open connection, only connected
+    /// peers are advertised over PEX.
+    pub fn set_connected(&mut self, peer: &Peer, connected: bool) {
+        if let Some(entry) = self.peers.get_mut(peer) {
+            entry.connected = connected;
+        }
+    }
+
+    /// Connected peers with their PEX flags.
+    pub fn get_connected(&self) -> Vec<(Peer, u8)> {
+        self.peers
+            .iter()
+            .filter(|(_, entry)| entry.connected)
+            .map(|(peer, entry)| (peer.clone(), entry.flags))
+            .collect()
+    }
+
+    pub fn get_peer_list(&self) -> Vec<Peer> {
+        self.peers.keys().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+}
+
+impl Default for PeerPool {
+    fn default() -> Self {
+        PeerPool::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn peer(port: u16) -> Peer {
+        Peer::new(SocketAddr::from(([127, 0, 0, 1], port)))
+    }
+
+    #[test]
+    fn known_peers_are_not_added_again() {
+        let mut pool = PeerPool::new();
+        assert!(pool.add(peer(1), PeerSource::Tracker));
+        assert!(!pool.add_with_flags(peer(1), PeerSource::Pex, 0x02));
+        assert_eq!(pool.get_source(&peer(1)), Some(PeerSource::Tracker));
+        assert_eq!(pool.get_flags(&peer(1)), 0x02);
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn full_pool_rejects_peers() {
+        let mut pool = PeerPool::with_capacity(2);
+        assert_eq!(
+            pool.extend(vec![peer(1), peer(2), peer(3)], PeerSource::Dht),
+            2
+        );
+        assert!(!pool.contains(&peer(3)));
+
+        pool.remove(&peer(1));
+        assert!(pool.add(peer(3), PeerSource::Lsd));
+    }
+
+    #[test]
+    fn only_connected_peers_are_listed() {
+        let mut pool = PeerPool::new();
+        pool.add_with_flags(peer(1), PeerSource::Tracker, 0x10);
+        pool.add(peer(2), PeerSource::Tracker);
+        pool.set_connected(&peer(1), true);
+        // unknown peers are not added by connecting
+        pool.set_connected(&peer(3), true);
+        assert_eq!(pool.get_connected(), vec![(peer(1), 0x10)]);
+
+        pool.set_connected(&peer(1), false);
+        assert!(pool.get_connected().is_empty());
+        assert!(!pool.is_connected(&peer(3)));
+    }
+}
@@ -0,0 +1,371 @@
+use crate::bencoding;
+use crate::bencoding::{BDict, BString, BType};
+use crate::peer_pool::{PeerPool, PeerSource};
+use crate::tracker::{parse_compact_peers, Peer};
+use crate::utility::to_vec;
+
+use std::collections::{BTreeMap, HashSet};
+use std::time::{Duration, Instant};
+
+pub const EXTENSION_NAME: &str = "ut_pex";
+/// Extended message id we ask peers to use when sending us ut_pex messages.
+pub const LOCAL_PEX_ID: u8 = 1;
+
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+/// Peers are allowed a little slack on the one message per minute rule.
+pub const MIN_RECEIVE_INTERVAL: Duration = Duration::from_secs(45);
+pub const MAX_PEERS_PER_MESSAGE: usize = 50;
+/// Peers accepted from a single connection per `ACCEPT_WINDOW`.
+pub const MAX_ACCEPTED_PER_SOURCE: usize = 200;
+pub const ACCEPT_WINDOW: Duration = Duration::from_secs(600);
+
+pub const FLAG_ENCRYPTION: u8 = 0x01;
+pub const FLAG_SEED: u8 = 0x02;
+pub const FLAG_UTP: u8 = 0x04;
+pub const FLAG_HOLEPUNCH: u8 = 0x08;
+pub const FLAG_CONNECTABLE: u8 = 0x10;
+
+#[derive(Clone, Debug, Default)]
+pub struct PexMessage {
+    added: Vec<(Peer, u8)>,
+    dropped: Vec<Peer>,
+}
+
+impl PexMessage {
+    pub fn new(added: Vec<(Peer, u8)>, dropped: Vec<Peer>) -> PexMessage {
+        PexMessage { added, dropped }
+    }
+
+    pub fn get_added(&self) -> &Vec<(Peer, u8)> {
+        &self.added
+    }
+
+    pub fn get_dropped(&self) -> &Vec<Peer> {
+        &self.dropped
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.dropped.is_empty()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut message = BDict::new(BTreeMap::new());
+        for (suffix, ipv6) in [("", false), ("6", true)].iter() {
+            let mut added = Vec::new();
+            let mut flags = Vec::new();
+            for (peer, flag) in self
+                .added
+                .iter()
+                .filter(|(peer, _)| peer.is_ipv6() == *ipv6)
+            {
+                added.append(&mut peer.to_compact());
+                flags.push(*flag);
+            }
+
+            let mut dropped = Vec::new();
+            for peer in self.dropped.iter().filter(|peer| peer.is_ipv6() == *ipv6) {
+                dropped.append(&mut peer.to_compact());
+            }
+
+            if *ipv6 && added.is_empty() && dropped.is_empty() {
+                continue;
+            }
+            message.insert(
+                key(&format!("added{}", suffix)),
+                Box::new(BString::new(&added)),
+            );
+            message.insert(
+                key(&format!("added{}.f", suffix)),
+                Box::new(BString::new(&flags)),
+            );
+            message.insert(
+                key(&format!("dropped{}", suffix)),
+                Box::new(BString::new(&dropped)),
+            );
+        }
+        message.encode()
+    }
+
+    pub fn decode(payload: &[u8]) -> Option<PexMessage> {
+        let (message, _) = bencoding::try_decode(payload)?;
+        let message = message.as_any().downcast_ref::<BDict>()?;
+
+        let mut added = Vec::new();
+        let mut dropped = Vec::new();
+        for (suffix, ipv6) in [("", false), ("6", true)].iter() {
+            let peers = compact_peers(message.get::<BString>(&format!("added{}", suffix)), *ipv6);
+            let flags = match message.get::<BString>(&format!("added{}.f", suffix)) {
+                Some(flags) => flags.to_vec(),
+                None => Vec::new(),
+            };
+            added.extend(
+                peers
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, peer)| (peer, flags.get(i).cloned().unwrap_or(0))),
+            );
+            dropped.append(&mut compact_peers(
+                message.get::<BString>(&format!("dropped{}", suffix)),
+                *ipv6,
+            ));
+        }
+
+        Some(PexMessage { added, dropped })
+    }
+}
+
+/// Per connection ut_pex bookkeeping, tracks what we told the peer last
+/// time and how much we have accepted from it.
+#[derive(Debug, Default)]
+pub struct PexState {
+    sent_peers: HashSet<Peer>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+    accepted: usize,
+    accept_window_start: Option<Instant>,
+}
+
+impl PexState {
+    pub fn new() -> PexState {
+        PexState::default()
+    }
+
+    /// Builds the next message for the peer from the currently connected
+    /// peers, returns None when it is too early or nothing changed.
+    pub fn next_message(&mut self, connected: &[(Peer, u8)]) -> Option<PexMessage> {
+        if let Some(last_sent) = self.last_sent {
+            if last_sent.elapsed() < PEX_INTERVAL {
+                return None;
+            }
+        }
+
+        let current: HashSet<Peer> = connected.iter().map(|(peer, _)| peer.clone()).collect();
+        let added: Vec<(Peer, u8)> = connected
+            .iter()
+            .filter(|(peer, _)| !self.sent_peers.contains(peer))
+            .take(MAX_PEERS_PER_MESSAGE)
+            .cloned()
+            .collect();
+        let dropped: Vec<Peer> = self
+            .sent_peers
+            .iter()
+            .filter(|peer| !current.contains(peer))
+            .take(MAX_PEERS_PER_MESSAGE)
+            .cloned()
+            .collect();
+
+        let message = PexMessage::new(added, dropped);
+        if message.is_empty() {
+            return None;
+        }
+
+        for (peer, _) in &message.added {
+            self.sent_peers.insert(peer.clone());
+        }
+        for peer in &message.dropped {
+            self.sent_peers.remove(peer);
+        }
+        self.last_sent = Some(Instant::now());
+
+        Some(message)
+    }
+
+    /// Feeds a received ut_pex payload into the peer pool, returns the
+    /// number of newly added peers. Dropped peers we only know through PEX
+    /// leave the pool.
+    pub fn handle_message(&mut self, payload: &[u8], pool: &mut PeerPool) -> usize {
+        if let Some(last_received) = self.last_received {
+            if last_received.elapsed() < MIN_RECEIVE_INTERVAL {
+                return 0;
+            }
+        }
+        self.last_received = Some(Instant::now());
+
+        let message = match PexMessage::decode(payload) {
+            Some(message) => message,
+            None => return 0,
+        };
+
+        for peer in message.dropped.iter().take(MAX_PEERS_PER_MESSAGE) {
+            if pool.get_source(peer) == Some(PeerSource::Pex) && !pool.is_connected(peer) {
+                pool.remove(peer);
+            }
+        }
+
+        match self.accept_window_start {
+            Some(start) if start.elapsed() < ACCEPT_WINDOW => (),
+            _ => {
+                self.accept_window_start = Some(Instant::now());
+                self.accepted = 0;
+            }
+        }
+        let mut added = 0;
+        for (peer, flags) in message.added.into_iter().take(MAX_PEERS_PER_MESSAGE) {
+            if self.accepted >= MAX_ACCEPTED_PER_SOURCE {
+                break;
+            }
+            if pool.add_with_flags(peer, PeerSource::Pex, flags) {
+                self.accepted += 1;
+                added += 1;
+            }
+        }
+        added
+    }
+}
+
+fn compact_peers(peers: Option<&BString>, ipv6: bool) -> Vec<Peer> {
+    match peers {
+        Some(peers) => parse_compact_peers(peers, ipv6),
+        None => Vec::new(),
+    }
+}
+
+fn key(name: &str) -> BString {
+    BString::new(&to_vec(name.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn peer(addr: &str) -> Peer {
+        Peer::new(addr.parse::<SocketAddr>().unwrap())
+    }
+
+    /// `count` distinct IPv4 peers starting at 10.0.<base>.1.
+    fn peers(base: u8, count: usize) -> Vec<(Peer, u8)> {
+        (0..count)
+            .map(|i| {
+                let addr =
+                    SocketAddr::from(([10, base, (i / 250) as u8, (i % 250) as u8 + 1], 6881));
+                (Peer::new(addr), 0)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let message = PexMessage::new(
+            vec![
+                (peer("1.2.3.4:6881"), FLAG_SEED | FLAG_CONNECTABLE),
+                (peer("[2001:db8::1]:51413"), FLAG_UTP),
+                (peer("5.6.7.8:1"), 0),
+            ],
+            vec![peer("9.9.9.9:9"), peer("[2001:db8::2]:2")],
+        );
+        let decoded = PexMessage::decode(&message.encode()).unwrap();
+        assert_eq!(
+            decoded.get_added(),
+            &vec![
+                (peer("1.2.3.4:6881"), FLAG_SEED | FLAG_CONNECTABLE),
+                (peer("5.6.7.8:1"), 0),
+                (peer("[2001:db8::1]:51413"), FLAG_UTP),
+            ]
+        );
+        assert_eq!(
+            decoded.get_dropped(),
+            &vec![peer("9.9.9.9:9"), peer("[2001:db8::2]:2")]
+        );
+    }
+
+    #[test]
+    fn ipv4_only_messages_leave_out_ipv6_keys() {
+        let message = PexMessage::new(vec![(peer("1.2.3.4:6881"), 0)], Vec::new());
+        let encoded = String::from_utf8_lossy(&message.encode()).into_owned();
+        assert!(encoded.contains("5:added"));
+        assert!(!encoded.contains("6:added6"));
+        assert!(PexMessage::decode(b"not bencode").is_none());
+    }
+
+    #[test]
+    fn next_message_waits_for_the_interval() {
+        let mut state = PexState::new();
+        let connected = vec![(peer("1.1.1.1:1"), 0), (peer("2.2.2.2:2"), 0)];
+        let first = state.next_message(&connected).unwrap();
+        assert_eq!(first.get_added().len(), 2);
+        assert!(first.get_dropped().is_empty());
+
+        // too early, even though the peers changed
+        assert!(state.next_message(&connected[..1]).is_none());
+
+        state.last_sent = None;
+        let second = state.next_message(&connected[..1]).unwrap();
+        assert!(second.get_added().is_empty());
+        assert_eq!(second.get_dropped(), &vec![peer("2.2.2.2:2")]);
+
+        // nothing changed since
+        state.last_sent = None;
+        assert!(state.next_message(&connected[..1]).is_none());
+    }
+
+    #[test]
+    fn next_message_is_capped() {
+        let mut state = PexState::new();
+        let connected = peers(1, MAX_PEERS_PER_MESSAGE + 10);
+        let message = state.next_message(&connected).unwrap();
+        assert_eq!(message.get_added().len(), MAX_PEERS_PER_MESSAGE);
+
+        state.last_sent = None;
+        let message = state.next_message(&connected).unwrap();
+        assert_eq!(message.get_added().len(), 10);
+    }
+
+    #[test]
+    fn received_messages_are_rate_limited() {
+        let mut state = PexState::new();
+        let mut pool = PeerPool::new();
+        let first = PexMessage::new(peers(1, 3), Vec::new()).encode();
+        let second = PexMessage::new(peers(2, 3), Vec::new()).encode();
+        assert_eq!(state.handle_message(&first, &mut pool), 3);
+        assert_eq!(state.handle_message(&second, &mut pool), 0);
+        assert_eq!(pool.len(), 3);
+        assert_eq!(pool.get_source(&peers(1, 1)[0].0), Some(PeerSource::Pex));
+    }
+
+    #[test]
+    fn received_messages_are_capped() {
+        let mut state = PexState::new();
+        let mut pool = PeerPool::new();
+        let mut accepted = 0;
+        for base in 0..6 {
+            let message = PexMessage::new(peers(base, 2 * MAX_PEERS_PER_MESSAGE), Vec::new());
+            state.last_received = None;
+            let added = state.handle_message(&message.encode(), &mut pool);
+            assert!(added <= MAX_PEERS_PER_MESSAGE);
+            accepted += added;
+        }
+        assert_eq!(accepted, MAX_ACCEPTED_PER_SOURCE);
+
+        // a new window accepts peers again
+        state.last_received = None;
+        state.accept_window_start = None;
+        let message = PexMessage::new(peers(10, 5), Vec::new());
+        assert_eq!(state.handle_message(&message.encode(), &mut pool), 5);
+    }
+
+    #[test]
+    fn dropped_peers_leave_the_pool_unless_connected() {
+        let mut state = PexState::new();
+        let mut pool = PeerPool::new();
+        let (pex_peer, connected_peer, tracker_peer) =
+            (peer("1.1.1.1:1"), peer("2.2.2.2:2"), peer("3.3.3.3:3"));
+        pool.add(pex_peer.clone(), PeerSource::Pex);
+        pool.add(connected_peer.clone(), PeerSource::Pex);
+        pool.set_connected(&connected_peer, true);
+        pool.add(tracker_peer.clone(), PeerSource::Tracker);
+
+        let message = PexMessage::new(
+            Vec::new(),
+            vec![
+                pex_peer.clone(),
+                connected_peer.clone(),
+                tracker_peer.clone(),
+            ],
+        );
+        state.handle_message(&message.encode(), &mut pool);
+        assert!(!pool.contains(&pex_peer));
+        assert!(pool.contains(&connected_peer));
+        assert!(pool.contains(&tracker_peer));
+    }
+}
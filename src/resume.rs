@@ -0,0 +1,569 @@
+use crate::bencoding;
+use crate::bencoding::{BDict, BInt, BList, BString, BType};
+use crate::file::FileStorage;
+use crate::storage::{self, Storage};
+use crate::torrent::Torrent;
+use crate::tracker::{parse_compact_peers, Peer};
+use crate::utility::to_vec;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+pub const RESUME_INTERVAL: Duration = Duration::from_secs(60);
+pub const MAX_RESUME_PEERS: usize = 200;
+
+const FILE_FORMAT: &str = "birdtorrent resume file";
+const FILE_VERSION: i64 = 1;
+
+/// Download progress of a torrent that survives a restart: the pieces we
+/// have, the blocks of unfinished pieces, transfer stats and peers worth
+/// reconnecting to. The size and modification time of every file and of
+/// the part file are recorded so stale data is noticed without hashing
+/// everything.
+#[derive(Clone, Debug, Default)]
+pub struct ResumeData {
+    info_hash: Vec<u8>,
+    pieces: Vec<u8>,
+    files: Vec<(u64, u64)>,
+    unfinished: BTreeMap<u32, Vec<u8>>,
+    uploaded: i64,
+    downloaded: i64,
+    peers: Vec<Peer>,
+}
+
+impl ResumeData {
+    pub fn new(info_hash: &[u8]) -> ResumeData {
+        ResumeData {
+            info_hash: info_hash.to_vec(),
+            ..ResumeData::default()
+        }
+    }
+
+    /// Snapshot of the pieces the torrent has and the files as they are on
+    /// disk now. Flush the storage first, a later write changes the
+    /// modification time and invalidates the snapshot.
+    pub fn capture(info_hash: &[u8], storage: &FileStorage) -> ResumeData {
+        let mut resume_data = ResumeData::new(info_hash);
+        resume_data.update(storage);
+        resume_data
+    }
+
+    /// Refreshes the piece bitfield and file state, keeps everything else.
+    pub fn update(&mut self, storage: &FileStorage) {
+        let torrent = storage.get_torrent();
+        let piece_count = torrent.get_piece_count();
+        self.pieces = vec![0u8; (piece_count as usize).div_ceil(8)];
+        for index in 0..piece_count {
+            if torrent.get_piece(index) == 1 {
+                self.pieces[index as usize / 8] |= 0x80 >> (index % 8);
+                self.unfinished.remove(&index);
+            }
+        }
+        self.files = file_states(storage);
+    }
+
+    pub fn get_info_hash(&self) -> &Vec<u8> {
+        &self.info_hash
+    }
+
+    pub fn has_piece(&self, index: u32) -> bool {
+        match self.pieces.get(index as usize / 8) {
+            Some(byte) => byte & (0x80 >> (index % 8)) != 0,
+            None => false,
+        }
+    }
+
+    /// Blocks of an unfinished piece already written to disk, one bit per
+    /// 16 KiB block.
+    pub fn get_unfinished(&self, index: u32) -> Option<&Vec<u8>> {
+        self.unfinished.get(&index)
+    }
+
+    pub fn set_unfinished(&mut self, index: u32, blocks: Vec<u8>) {
+        if blocks.iter().all(|byte| *byte == 0) {
+            self.unfinished.remove(&index);
+        } else {
+            self.unfinished.insert(index, blocks);
+        }
+    }
+
+    pub fn get_uploaded(&self) -> i64 {
+        self.uploaded
+    }
+
+    pub fn get_downloaded(&self) -> i64 {
+        self.downloaded
+    }
+
+    pub fn set_stats(&mut self, uploaded: i64, downloaded: i64) {
+        self.uploaded = uploaded;
+        self.downloaded = downloaded;
+    }
+
+    pub fn get_peers(&self) -> &Vec<Peer> {
+        &self.peers
+    }
+
+    pub fn set_peers(&mut self, mut peers: Vec<Peer>) {
+        peers.truncate(MAX_RESUME_PEERS);
+        self.peers = peers;
+    }
+
+    /// Cheap check that the data on disk is what the snapshot describes,
+    /// compares sizes and modification times only.
+    pub fn is_valid(&self, info_hash: &[u8], storage: &FileStorage) -> bool {
+        let piece_count = storage.get_torrent().get_piece_count() as usize;
+        self.info_hash == info_hash
+            && self.pieces.len() == piece_count.div_ceil(8)
+            && self.files == file_states(storage)
+    }
+
+    /// Marks the pieces of the snapshot as downloaded in the torrent.
+    pub fn apply(&self, torrent: &mut Torrent) {
+        for index in 0..torrent.get_piece_count() {
+            if self.has_piece(index) {
+                torrent.set_piece(index);
+            }
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut resume = BDict::new(BTreeMap::new());
+        resume.insert(key("file-format"), bstring(FILE_FORMAT.as_bytes()));
+        resume.insert(key("file-version"), Box::new(BInt::new(FILE_VERSION)));
+        resume.insert(key("info-hash"), bstring(&self.info_hash));
+        resume.insert(key("pieces"), bstring(&self.pieces));
+
+        let mut files = BList::new(Vec::new());
+        for (size, mtime) in &self.files {
+            files.push(Box::new(BList::new(vec![
+                Box::new(BInt::new(*size as i64)),
+                Box::new(BInt::new(*mtime as i64)),
+            ])));
+        }
+        resume.insert(key("file sizes"), Box::new(files));
+
+        let mut unfinished = BList::new(Vec::new());
+        for (index, blocks) in &self.unfinished {
+            let mut piece = BDict::new(BTreeMap::new());
+            piece.insert(key("piece"), Box::new(BInt::new(*index as i64)));
+            piece.insert(key("bitmask"), bstring(blocks));
+            unfinished.push(Box::new(piece));
+        }
+        resume.insert(key("unfinished"), Box::new(unfinished));
+
+        resume.insert(key("uploaded"), Box::new(BInt::new(self.uploaded)));
+        resume.insert(key("downloaded"), Box::new(BInt::new(self.downloaded)));
+
+        for (name, ipv6) in [("peers", false), ("peers6", true)].iter() {
+            let mut peers = Vec::new();
+            for peer in self.peers.iter().filter(|peer| peer.is_ipv6() == *ipv6) {
+                peers.append(&mut peer.to_compact());
+            }
+            resume.insert(key(name), bstring(&peers));
+        }
+
+        resume.encode()
+    }
+
+    /// Returns None for anything that is not a resume file we wrote.
+    pub fn decode(data: &[u8]) -> Option<ResumeData> {
+        let (resume, _) = bencoding::try_decode(data)?;
+        let resume = resume.as_any().downcast_ref::<BDict>()?;
+        if resume.get::<BString>("file-format")?.to_vec() != FILE_FORMAT.as_bytes()
+            || resume.get::<BInt>("file-version")?.into_int() != FILE_VERSION
+        {
+            return None;
+        }
+
+        let mut files = Vec::new();
+        for file in resume.get::<BList>("file sizes")?.get() {
+            let file = file.as_any().downcast_ref::<BList>()?.get();
+            let size = file.first()?.as_any().downcast_ref::<BInt>()?.into_int();
+            let mtime = file.get(1)?.as_any().downcast_ref::<BInt>()?.into_int();
+            files.push((size as u64, mtime as u64));
+        }
+
+        let mut unfinished = BTreeMap::new();
+        if let Some(pieces) = resume.get::<BList>("unfinished") {
+            for piece in pieces.get() {
+                let piece = piece.as_any().downcast_ref::<BDict>()?;
+                let index = piece.get::<BInt>("piece")?.into_int();
+                let blocks = piece.get::<BString>("bitmask")?.to_vec();
+                unfinished.insert(index as u32, blocks);
+            }
+        }
+
+        let mut peers = Vec::new();
+        for (name, ipv6) in [("peers", false), ("peers6", true)].iter() {
+            if let Some(compact) = resume.get::<BString>(name) {
+                peers.append(&mut parse_compact_peers(compact, *ipv6));
+            }
+        }
+
+        Some(ResumeData {
+            info_hash: resume.get::<BString>("info-hash")?.to_vec(),
+            pieces: resume.get::<BString>("pieces")?.to_vec(),
+            files,
+            unfinished,
+            uploaded: resume.get::<BInt>("uploaded").map_or(0, BInt::into_int),
+            downloaded: resume.get::<BInt>("downloaded").map_or(0, BInt::into_int),
+            peers,
+        })
+    }
+
+    pub fn load(path: &Path) -> io::Result<ResumeData> {
+        let data = fs::read(path)?;
+        ResumeData::decode(&data)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid resume file"))
+    }
+
+    /// Writes to a temporary file first so a crash never leaves a
+    /// truncated resume file behind.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        fs::write(&temp_path, self.encode())?;
+        fs::rename(&temp_path, path)
+    }
+}
+
+/// Saves the resume data of a torrent every `RESUME_INTERVAL` while it
+/// runs and once more on shutdown.
+#[derive(Debug)]
+pub struct ResumeWriter {
+    path: PathBuf,
+    interval: Duration,
+    last_saved: Option<Instant>,
+}
+
+impl ResumeWriter {
+    pub fn new(path: &Path) -> ResumeWriter {
+        ResumeWriter::with_interval(path, RESUME_INTERVAL)
+    }
+
+    pub fn with_interval(path: &Path, interval: Duration) -> ResumeWriter {
+        ResumeWriter {
+            path: path.to_path_buf(),
+            interval,
+            last_saved: None,
+        }
+    }
+
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Call regularly, saves when the interval is up. Returns whether the
+    /// file was written.
+    pub fn tick(&mut self, resume_data: &ResumeData) -> io::Result<bool> {
+        match self.last_saved {
+            Some(last_saved) if last_saved.elapsed() < self.interval => Ok(false),
+            _ => self.save(resume_data).map(|_| true),
+        }
+    }
+
+    /// Saves right away, meant for shutdown.
+    pub fn save(&mut self, resume_data: &ResumeData) -> io::Result<()> {
+        resume_data.save(&self.path)?;
+        self.last_saved = Some(Instant::now());
+        Ok(())
+    }
+}
+
+/// Restores the progress of a torrent on startup. A missing, corrupt or
+/// stale resume file makes every piece on disk get hashed instead. Returns
+/// the resume data the torrent continues with.
+pub fn restore(path: &Path, info_hash: &[u8], storage: &mut FileStorage) -> ResumeData {
+    let resume_data = match ResumeData::load(path) {
+        Ok(resume_data) => resume_data,
+        Err(err) => {
+            if err.kind() != io::ErrorKind::NotFound {
+                eprintln!("Warning: Discarding resume file {:?}: {}", path, err);
+            }
+            return recheck(info_hash, storage);
+        }
+    };
+
+    if !resume_data.is_valid(info_hash, storage) {
+        eprintln!("Warning: Files changed since {:?} was written", path);
+        return recheck(info_hash, storage);
+    }
+
+    resume_data.apply(storage.get_torrent_mut());
+    resume_data
+}
+
+fn recheck(info_hash: &[u8], storage: &mut FileStorage) -> ResumeData {
+    if let Err(err) = storage::recheck(storage, 0, |_| ()) {
+        eprintln!(
+            "Error: Checking {:?} failed: {}",
+            storage.get_save_path(),
+            err
+        );
+    }
+    ResumeData::capture(info_hash, storage)
+}
+
+/// Size and modification time in seconds of every file followed by the
+/// part file, zeros for files that don't exist.
+fn file_states(storage: &FileStorage) -> Vec<(u64, u64)> {
+    storage
+        .get_files()
+        .into_iter()
+        .map(|(path, _)| path)
+        .chain(std::iter::once(storage.get_part_file_path()))
+        .map(|path| match fs::metadata(path) {
+            Ok(metadata) => {
+                let mtime = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |mtime| mtime.as_secs());
+                (metadata.len(), mtime)
+            }
+            Err(_) => (0, 0),
+        })
+        .collect()
+}
+
+fn key(name: &str) -> BString {
+    BString::new(&to_vec(name.as_bytes()))
+}
+
+fn bstring(data: &[u8]) -> Box<dyn BType> {
+    Box::new(BString::new(&to_vec(data)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::{parse_torrent_data, FilePriority};
+
+    use sha1::{Digest, Sha1};
+
+    use std::net::SocketAddr;
+    use std::time::SystemTime;
+
+    const INFO_HASH: [u8; 20] = [1; 20];
+
+    /// Files `a` (6 bytes) and `b` (10 bytes) holding 0..16 in pieces of 8
+    /// bytes.
+    fn torrent() -> Torrent {
+        let mut metainfo = b"d4:infod5:filesld6:lengthi6e4:pathl1:aeed6:lengthi10e4:pathl1:bee\
+                             e4:name4:root12:piece lengthi8e6:pieces40:"
+            .to_vec();
+        for piece in data().chunks(8) {
+            metainfo.extend_from_slice(&Sha1::digest(piece));
+        }
+        metainfo.extend_from_slice(b"ee");
+        let (metainfo, _) = bencoding::try_decode(&metainfo).unwrap();
+        parse_torrent_data(metainfo.as_any().downcast_ref::<BDict>().unwrap())
+    }
+
+    fn data() -> Vec<u8> {
+        (0..16).collect()
+    }
+
+    fn save_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("resume-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&path);
+        path
+    }
+
+    /// Downloads the whole torrent to `save_path` and saves its resume
+    /// data there, `a` is kept in the part file when it is skipped.
+    fn download(save_path: &Path, skip_a: bool) -> (Torrent, PathBuf) {
+        let mut torrent = torrent();
+        if skip_a {
+            torrent.set_file_priority(0, FilePriority::Skip);
+        }
+        let mut storage = FileStorage::new(&torrent, save_path);
+        let data = data();
+        storage.write_block(0, 0, &data[..8]).unwrap();
+        storage.write_block(1, 0, &data[8..]).unwrap();
+        storage.flush().unwrap();
+        storage.get_torrent_mut().set_piece(0);
+        storage.get_torrent_mut().set_piece(1);
+
+        let resume_path = save_path.join("resume");
+        ResumeData::capture(&INFO_HASH, &storage)
+            .save(&resume_path)
+            .unwrap();
+        (torrent, resume_path)
+    }
+
+    fn set_modified(path: &Path, modified: SystemTime) {
+        fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    /// Overwrites the last byte of `b`, which is in piece 1, leaving its
+    /// modification time as it was.
+    fn corrupt_b(save_path: &Path) {
+        let path = save_path.join("root/b");
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        let mut data = fs::read(&path).unwrap();
+        *data.last_mut().unwrap() ^= 0xff;
+        fs::write(&path, data).unwrap();
+        set_modified(&path, modified);
+    }
+
+    /// Restores with fresh storage, returns which pieces it has.
+    fn restore_pieces(
+        torrent: &Torrent,
+        save_path: &Path,
+        resume_path: &Path,
+        info_hash: &[u8],
+    ) -> Vec<u8> {
+        let mut storage = FileStorage::new(torrent, save_path);
+        let resume_data = restore(resume_path, info_hash, &mut storage);
+        assert_eq!(resume_data.get_info_hash(), &info_hash.to_vec());
+        (0..2)
+            .map(|index| storage.get_torrent().get_piece(index))
+            .collect()
+    }
+
+    #[test]
+    fn encode_and_decode() {
+        let mut resume_data = ResumeData::new(&INFO_HASH);
+        resume_data.pieces = vec![0b1010_0000];
+        resume_data.files = vec![(6, 1_600_000_000), (10, 1_600_000_001), (0, 0)];
+        resume_data.set_unfinished(1, vec![0b1100_0000]);
+        resume_data.set_unfinished(3, vec![0]);
+        resume_data.set_stats(100, 200);
+        let peers: Vec<Peer> = ["10.0.0.1:6881", "[2001:db8::1]:51413", "10.0.0.2:1"]
+            .iter()
+            .map(|addr| Peer::new(addr.parse::<SocketAddr>().unwrap()))
+            .collect();
+        resume_data.set_peers(peers.clone());
+
+        let decoded = ResumeData::decode(&resume_data.encode()).unwrap();
+        assert_eq!(decoded.get_info_hash(), &INFO_HASH.to_vec());
+        assert_eq!(decoded.pieces, resume_data.pieces);
+        assert!(decoded.has_piece(0) && !decoded.has_piece(1) && decoded.has_piece(2));
+        assert_eq!(decoded.files, resume_data.files);
+        assert_eq!(decoded.get_unfinished(1), Some(&vec![0b1100_0000]));
+        assert_eq!(decoded.get_unfinished(3), None);
+        assert_eq!(decoded.get_uploaded(), 100);
+        assert_eq!(decoded.get_downloaded(), 200);
+        // IPv4 peers come first, from `peers`, then those of `peers6`
+        assert_eq!(
+            decoded.get_peers(),
+            &vec![peers[0].clone(), peers[2].clone(), peers[1].clone()]
+        );
+
+        assert!(ResumeData::decode(b"d4:infod6:lengthi1eee").is_none());
+        assert!(ResumeData::decode(b"not bencoded").is_none());
+    }
+
+    #[test]
+    fn unchanged_files_are_not_rechecked() {
+        let save_path = save_path("unchanged");
+        let (torrent, resume_path) = download(&save_path, false);
+        // only sizes and times are compared, the corruption goes unnoticed
+        corrupt_b(&save_path);
+        assert_eq!(
+            restore_pieces(&torrent, &save_path, &resume_path, &INFO_HASH),
+            vec![1, 1]
+        );
+        fs::remove_dir_all(&save_path).unwrap();
+    }
+
+    #[test]
+    fn changed_modification_time_rechecks() {
+        let save_path = save_path("mtime");
+        let (torrent, resume_path) = download(&save_path, false);
+        corrupt_b(&save_path);
+        set_modified(
+            &save_path.join("root/b"),
+            SystemTime::now() + Duration::from_secs(10),
+        );
+        assert_eq!(
+            restore_pieces(&torrent, &save_path, &resume_path, &INFO_HASH),
+            vec![1, 0]
+        );
+        fs::remove_dir_all(&save_path).unwrap();
+    }
+
+    #[test]
+    fn changed_size_rechecks() {
+        let save_path = save_path("size");
+        let (torrent, resume_path) = download(&save_path, false);
+        corrupt_b(&save_path);
+        let path = save_path.join("root/b");
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(11)
+            .unwrap();
+        set_modified(&path, modified);
+        assert_eq!(
+            restore_pieces(&torrent, &save_path, &resume_path, &INFO_HASH),
+            vec![1, 0]
+        );
+        fs::remove_dir_all(&save_path).unwrap();
+    }
+
+    #[test]
+    fn other_info_hash_rechecks() {
+        let save_path = save_path("info-hash");
+        let (torrent, resume_path) = download(&save_path, false);
+        corrupt_b(&save_path);
+        assert_eq!(
+            restore_pieces(&torrent, &save_path, &resume_path, &[2; 20]),
+            vec![1, 0]
+        );
+        fs::remove_dir_all(&save_path).unwrap();
+    }
+
+    #[test]
+    fn changed_part_file_rechecks() {
+        let save_path = save_path("part-file");
+        let (torrent, resume_path) = download(&save_path, true);
+        let part_file_path = save_path.join(".root.parts");
+        assert!(part_file_path.exists());
+        assert!(!save_path.join("root/a").exists());
+
+        fs::remove_file(&part_file_path).unwrap();
+        assert_eq!(
+            restore_pieces(&torrent, &save_path, &resume_path, &INFO_HASH),
+            vec![0, 1]
+        );
+        fs::remove_dir_all(&save_path).unwrap();
+    }
+
+    #[test]
+    fn save_replaces_the_file_at_once() {
+        let save_path = save_path("save");
+        let path = save_path.join("resume");
+        let mut resume_data = ResumeData::new(&INFO_HASH);
+        resume_data.set_stats(1, 1);
+        resume_data.save(&path).unwrap();
+        assert!(!save_path.join("resume.tmp").exists());
+
+        // a save that fails half way leaves the previous file intact
+        fs::create_dir(save_path.join("resume.tmp")).unwrap();
+        resume_data.set_stats(2, 2);
+        assert!(resume_data.save(&path).is_err());
+        assert_eq!(ResumeData::load(&path).unwrap().get_uploaded(), 1);
+
+        fs::remove_dir(save_path.join("resume.tmp")).unwrap();
+        resume_data.save(&path).unwrap();
+        assert_eq!(ResumeData::load(&path).unwrap().get_uploaded(), 2);
+        assert!(!save_path.join("resume.tmp").exists());
+        fs::remove_dir_all(&save_path).unwrap();
+    }
+}
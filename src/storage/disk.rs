@@ -0,0 +1,713 @@
+use super::Storage;
+
+use crate::torrent::Torrent;
+
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// Size of the blocks pieces are split into when read ahead.
+const BLOCK_SIZE: u64 = 16384;
+
+#[derive(Clone, Debug)]
+pub struct DiskConfig {
+    /// Bytes of block data kept in memory, dirty and clean.
+    pub cache_size: usize,
+    /// Threads doing the disk I/O.
+    pub threads: usize,
+}
+
+impl Default for DiskConfig {
+    fn default() -> Self {
+        DiskConfig {
+            cache_size: 32 * 1024 * 1024,
+            threads: 4,
+        }
+    }
+}
+
+/// Runs the disk I/O of a torrent on its own threads behind a block cache,
+/// the caller only waits for the disk when written blocks fill the cache.
+/// Written blocks are kept in the
+/// cache and written out a whole piece at a time with adjacent blocks
+/// coalesced into a single write. Reads pull the whole piece into the cache
+/// so the following requests for it are served from memory. Least recently
+/// used clean blocks are evicted once the cache is full, dirty blocks stay
+/// until written. Workers only hold the storage for the call into it, the
+/// cache work of other jobs goes on meanwhile. `shutdown` flushes
+/// everything and stops the threads, dropping does the same but cannot
+/// report errors.
+pub struct DiskIo<S: Storage + 'static> {
+    inner: Arc<Inner<S>>,
+    jobs: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+struct Inner<S> {
+    torrent: Torrent,
+    storage: Mutex<S>,
+    cache: Mutex<BlockCache>,
+    /// Signalled when flushed blocks leave the dirty bytes.
+    dirty_changed: Condvar,
+    errors: Mutex<Vec<(u32, io::Error)>>,
+}
+
+enum Job {
+    Read {
+        piece_index: u32,
+        begin: u64,
+        length: u64,
+        reply: Sender<io::Result<Vec<u8>>>,
+    },
+    Flush {
+        piece_index: u32,
+    },
+    FlushAll {
+        reply: Sender<io::Result<()>>,
+    },
+}
+
+impl<S: Storage + 'static> DiskIo<S> {
+    pub fn new(storage: S) -> DiskIo<S> {
+        DiskIo::with_config(storage, DiskConfig::default())
+    }
+
+    pub fn with_config(storage: S, config: DiskConfig) -> DiskIo<S> {
+        let inner = Arc::new(Inner {
+            torrent: storage.get_torrent().clone(),
+            storage: Mutex::new(storage),
+            cache: Mutex::new(BlockCache::new(config.cache_size)),
+            dirty_changed: Condvar::new(),
+            errors: Mutex::new(Vec::new()),
+        });
+
+        let (jobs, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..config.threads.max(1))
+            .map(|_| {
+                let inner = Arc::clone(&inner);
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || work(inner, receiver))
+            })
+            .collect();
+
+        DiskIo {
+            inner,
+            jobs: Some(jobs),
+            workers,
+        }
+    }
+
+    /// Queues a block for writing, it is readable right away. Blocks the
+    /// calling thread while the dirty blocks take up more than the cache.
+    pub fn write_block(&self, piece_index: u32, begin: u64, data: Vec<u8>) {
+        let mut cache = self.inner.cache.lock().unwrap();
+        cache.insert(piece_index, begin, Arc::new(data), true);
+        let flush = if cache.dirty_size > cache.capacity / 2 {
+            cache.take_unqueued()
+        } else if cache.piece_dirty_size(piece_index)
+            >= self.inner.torrent.get_piece_size(piece_index)
+        {
+            cache.take_unqueued_piece(piece_index)
+        } else {
+            Vec::new()
+        };
+        for piece_index in flush {
+            self.send(Job::Flush { piece_index });
+        }
+
+        while cache.dirty_size > cache.capacity {
+            // pieces written to after their flush job started need another
+            for piece_index in cache.take_unqueued() {
+                self.send(Job::Flush { piece_index });
+            }
+            cache = self.inner.dirty_changed.wait(cache).unwrap();
+        }
+    }
+
+    /// Reads a block, the result arrives on the returned channel. Served
+    /// straight from the cache when the block is in it.
+    pub fn read_block(
+        &self,
+        piece_index: u32,
+        begin: u64,
+        length: u64,
+    ) -> Receiver<io::Result<Vec<u8>>> {
+        let (reply, receiver) = mpsc::channel();
+        let cached = self
+            .inner
+            .cache
+            .lock()
+            .unwrap()
+            .get(piece_index, begin, length);
+        match cached {
+            Some(block) => {
+                let _ = reply.send(Ok(block));
+            }
+            None => self.send(Job::Read {
+                piece_index,
+                begin,
+                length,
+                reply,
+            }),
+        }
+        receiver
+    }
+
+    /// Writes every dirty block and flushes the storage, the result arrives
+    /// on the returned channel.
+    pub fn flush(&self) -> Receiver<io::Result<()>> {
+        let (reply, receiver) = mpsc::channel();
+        self.send(Job::FlushAll { reply });
+        receiver
+    }
+
+    /// Pieces whose blocks could not be written, they have to be downloaded
+    /// again.
+    pub fn take_errors(&self) -> Vec<(u32, io::Error)> {
+        std::mem::take(&mut *self.inner.errors.lock().unwrap())
+    }
+
+    /// Bytes currently held by the cache and how many of them wait to be
+    /// written.
+    pub fn get_cache_usage(&self) -> (usize, usize) {
+        let cache = self.inner.cache.lock().unwrap();
+        (cache.size, cache.dirty_size)
+    }
+
+    /// Runs `f` on the storage once all queued writes are on disk, blocks
+    /// the calling thread while disk jobs are running.
+    pub fn with_storage<R, F: FnOnce(&mut S) -> R>(&self, f: F) -> io::Result<R> {
+        flush_all(&self.inner)?;
+        let mut storage = self.inner.storage.lock().unwrap();
+        let result = f(&mut storage);
+        // the storage may have moved or rewritten data
+        self.inner.cache.lock().unwrap().clear_clean();
+        Ok(result)
+    }
+
+    /// Writes every dirty block, flushes the storage and stops the disk
+    /// threads.
+    pub fn shutdown(mut self) -> io::Result<()> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        if self.jobs.is_none() {
+            return Ok(());
+        }
+        let result = match self.flush().recv() {
+            Ok(result) => result,
+            Err(_) => Err(worker_panicked()),
+        };
+        self.jobs = None;
+        let mut panicked = false;
+        for worker in self.workers.drain(..) {
+            panicked |= worker.join().is_err();
+        }
+        match result {
+            Ok(()) if panicked => Err(worker_panicked()),
+            result => result,
+        }
+    }
+
+    fn send(&self, job: Job) {
+        if let Some(jobs) = &self.jobs {
+            jobs.send(job).unwrap();
+        }
+    }
+}
+
+impl<S: Storage + 'static> Drop for DiskIo<S> {
+    fn drop(&mut self) {
+        // the owner chose not to hear about errors by not calling shutdown
+        let _ = self.stop();
+    }
+}
+
+fn worker_panicked() -> io::Error {
+    io::Error::other("A disk thread panicked")
+}
+
+fn work<S: Storage>(inner: Arc<Inner<S>>, jobs: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        let job = jobs.lock().unwrap().recv();
+        match job {
+            Ok(Job::Read {
+                piece_index,
+                begin,
+                length,
+                reply,
+            }) => {
+                let _ = reply.send(read(&inner, piece_index, begin, length));
+            }
+            Ok(Job::Flush { piece_index }) => flush_piece(&inner, piece_index),
+            Ok(Job::FlushAll { reply }) => {
+                let _ = reply.send(flush_all(&inner));
+            }
+            Err(_) => return,
+        }
+    }
+}
+
+/// Reads the whole piece into the cache and answers from it, falls back to
+/// the requested range when the piece is incomplete on disk.
+fn read<S: Storage>(
+    inner: &Inner<S>,
+    piece_index: u32,
+    begin: u64,
+    length: u64,
+) -> io::Result<Vec<u8>> {
+    // another job may have read the piece in the meantime
+    if let Some(block) = inner.cache.lock().unwrap().get(piece_index, begin, length) {
+        return Ok(block);
+    }
+    flush_piece(inner, piece_index);
+
+    let mut storage = inner.storage.lock().unwrap();
+    let piece_size = inner.torrent.get_piece_size(piece_index);
+    let piece = match storage.read_block(piece_index, 0, piece_size) {
+        Ok(piece) => piece,
+        Err(_) => return storage.read_block(piece_index, begin, length),
+    };
+    // cached before the storage is unlocked, a flush finishing in between
+    // would mark newer blocks clean that this data then replaces
+    let mut cache = inner.cache.lock().unwrap();
+    drop(storage);
+    for (i, block) in piece.chunks(BLOCK_SIZE as usize).enumerate() {
+        cache.insert(
+            piece_index,
+            i as u64 * BLOCK_SIZE,
+            Arc::new(block.to_vec()),
+            false,
+        );
+    }
+    let begin = begin.min(piece.len() as u64) as usize;
+    let end = (begin as u64 + length).min(piece.len() as u64) as usize;
+    Ok(piece[begin..end].to_vec())
+}
+
+/// Writes the dirty blocks of a piece, adjacent blocks in a single write.
+/// The blocks are taken with the storage locked so an older copy of a block
+/// is never written after a newer one.
+fn flush_piece<S: Storage>(inner: &Inner<S>, piece_index: u32) {
+    let mut storage = inner.storage.lock().unwrap();
+    let blocks = {
+        let mut cache = inner.cache.lock().unwrap();
+        cache.queued.remove(&piece_index);
+        cache.dirty_blocks(piece_index)
+    };
+
+    let mut runs: Vec<(u64, Vec<u8>)> = Vec::new();
+    for (begin, data) in &blocks {
+        match runs.last_mut() {
+            Some((run_begin, run)) if *run_begin + run.len() as u64 == *begin => {
+                run.extend_from_slice(data)
+            }
+            _ => runs.push((*begin, data.to_vec())),
+        }
+    }
+    let result = runs
+        .iter()
+        .try_for_each(|(begin, data)| storage.write_block(piece_index, *begin, data));
+    drop(storage);
+
+    let mut cache = inner.cache.lock().unwrap();
+    match result {
+        Ok(()) => {
+            for (begin, data) in &blocks {
+                cache.mark_clean(piece_index, *begin, data);
+            }
+        }
+        Err(err) => {
+            for (begin, data) in &blocks {
+                cache.remove(piece_index, *begin, data);
+            }
+            inner.errors.lock().unwrap().push((piece_index, err));
+        }
+    }
+    inner.dirty_changed.notify_all();
+}
+
+fn flush_all<S: Storage>(inner: &Inner<S>) -> io::Result<()> {
+    let pieces = inner.cache.lock().unwrap().dirty_pieces();
+    for piece_index in pieces {
+        flush_piece(inner, piece_index);
+    }
+    inner.storage.lock().unwrap().flush()
+}
+
+struct CachedBlock {
+    data: Arc<Vec<u8>>,
+    dirty: bool,
+    last_used: u64,
+}
+
+/// Blocks keyed by piece and offset in the piece. Clean blocks are also
+/// kept in their use order for LRU eviction, dirty blocks join it once
+/// they are written.
+struct BlockCache {
+    blocks: BTreeMap<(u32, u64), CachedBlock>,
+    clean_lru: BTreeMap<u64, (u32, u64)>,
+    /// Pieces with a flush job waiting.
+    queued: HashSet<u32>,
+    tick: u64,
+    size: usize,
+    dirty_size: usize,
+    capacity: usize,
+}
+
+impl BlockCache {
+    fn new(capacity: usize) -> BlockCache {
+        BlockCache {
+            blocks: BTreeMap::new(),
+            clean_lru: BTreeMap::new(),
+            queued: HashSet::new(),
+            tick: 0,
+            size: 0,
+            dirty_size: 0,
+            capacity,
+        }
+    }
+
+    /// Caches a block in place of every part of other blocks it overlaps,
+    /// whatever is left of those before and after it is kept.
+    fn insert(&mut self, piece_index: u32, begin: u64, data: Arc<Vec<u8>>, dirty: bool) {
+        if data.is_empty() {
+            return;
+        }
+        let end = begin + data.len() as u64;
+        let overlapping: Vec<(u64, Arc<Vec<u8>>, bool)> = self
+            .blocks
+            .range((piece_index, 0)..(piece_index, end))
+            .filter(|(key, block)| key.1 + block.data.len() as u64 > begin)
+            .map(|(key, block)| (key.1, Arc::clone(&block.data), block.dirty))
+            .collect();
+        // never replace data waiting to be written with what is on disk
+        if !dirty && overlapping.iter().any(|(_, _, old_dirty)| *old_dirty) {
+            return;
+        }
+
+        for (old_begin, old, old_dirty) in overlapping {
+            self.remove(piece_index, old_begin, &old);
+            let old_end = old_begin + old.len() as u64;
+            if old_begin < begin {
+                let head = old[..(begin - old_begin) as usize].to_vec();
+                self.put(piece_index, old_begin, Arc::new(head), old_dirty);
+            }
+            if old_end > end {
+                let tail = old[(end - old_begin) as usize..].to_vec();
+                self.put(piece_index, end, Arc::new(tail), old_dirty);
+            }
+        }
+        self.put(piece_index, begin, data, dirty);
+        self.evict();
+    }
+
+    fn put(&mut self, piece_index: u32, begin: u64, data: Arc<Vec<u8>>, dirty: bool) {
+        self.tick += 1;
+        self.size += data.len();
+        if dirty {
+            self.dirty_size += data.len();
+        }
+        if !dirty {
+            self.clean_lru.insert(self.tick, (piece_index, begin));
+        }
+        self.blocks.insert(
+            (piece_index, begin),
+            CachedBlock {
+                data,
+                dirty,
+                last_used: self.tick,
+            },
+        );
+    }
+
+    /// Copy of the range if a single cached block holds all of it.
+    fn get(&mut self, piece_index: u32, begin: u64, length: u64) -> Option<Vec<u8>> {
+        let (&key, block) = self
+            .blocks
+            .range((piece_index, 0)..=(piece_index, begin))
+            .next_back()?;
+        let start = (begin - key.1) as usize;
+        let end = start + length as usize;
+        if end > block.data.len() {
+            return None;
+        }
+        let data = block.data[start..end].to_vec();
+
+        self.tick += 1;
+        let block = self.blocks.get_mut(&key).unwrap();
+        if !block.dirty {
+            self.clean_lru.remove(&block.last_used);
+            self.clean_lru.insert(self.tick, key);
+        }
+        block.last_used = self.tick;
+        Some(data)
+    }
+
+    /// Removes the block if it still holds `data`.
+    fn remove(&mut self, piece_index: u32, begin: u64, data: &Arc<Vec<u8>>) {
+        let key = (piece_index, begin);
+        match self.blocks.get(&key) {
+            Some(block) if Arc::ptr_eq(&block.data, data) => (),
+            _ => return,
+        }
+        let block = self.blocks.remove(&key).unwrap();
+        self.size -= block.data.len();
+        if block.dirty {
+            self.dirty_size -= block.data.len();
+        } else {
+            self.clean_lru.remove(&block.last_used);
+        }
+    }
+
+    fn mark_clean(&mut self, piece_index: u32, begin: u64, data: &Arc<Vec<u8>>) {
+        if let Some(block) = self.blocks.get_mut(&(piece_index, begin)) {
+            if block.dirty && Arc::ptr_eq(&block.data, data) {
+                block.dirty = false;
+                self.dirty_size -= block.data.len();
+                self.clean_lru.insert(block.last_used, (piece_index, begin));
+            }
+        }
+        self.evict();
+    }
+
+    fn clear_clean(&mut self) {
+        let clean: Vec<_> = self
+            .blocks
+            .iter()
+            .filter(|(_, block)| !block.dirty)
+            .map(|(key, block)| (*key, Arc::clone(&block.data)))
+            .collect();
+        for ((piece_index, begin), data) in clean {
+            self.remove(piece_index, begin, &data);
+        }
+    }
+
+    fn dirty_blocks(&self, piece_index: u32) -> Vec<(u64, Arc<Vec<u8>>)> {
+        self.blocks
+            .range((piece_index, 0)..(piece_index + 1, 0))
+            .filter(|(_, block)| block.dirty)
+            .map(|(key, block)| (key.1, Arc::clone(&block.data)))
+            .collect()
+    }
+
+    fn piece_dirty_size(&self, piece_index: u32) -> u64 {
+        self.dirty_blocks(piece_index)
+            .iter()
+            .map(|(_, data)| data.len() as u64)
+            .sum()
+    }
+
+    fn dirty_pieces(&self) -> Vec<u32> {
+        let mut pieces: Vec<u32> = self
+            .blocks
+            .iter()
+            .filter(|(_, block)| block.dirty)
+            .map(|(key, _)| key.0)
+            .collect();
+        pieces.dedup();
+        pieces
+    }
+
+    /// Pieces with dirty blocks and no flush job yet, marks them queued.
+    fn take_unqueued(&mut self) -> Vec<u32> {
+        let pieces: Vec<u32> = self
+            .dirty_pieces()
+            .into_iter()
+            .filter(|piece_index| !self.queued.contains(piece_index))
+            .collect();
+        self.queued.extend(&pieces);
+        pieces
+    }
+
+    fn take_unqueued_piece(&mut self, piece_index: u32) -> Vec<u32> {
+        if self.queued.insert(piece_index) {
+            vec![piece_index]
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Drops least recently used clean blocks until the cache fits.
+    fn evict(&mut self) {
+        while self.size > self.capacity {
+            let (piece_index, begin) = match self.clean_lru.values().next() {
+                Some(key) => *key,
+                None => return,
+            };
+            let data = Arc::clone(&self.blocks[&(piece_index, begin)].data);
+            self.remove(piece_index, begin, &data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencoding;
+    use crate::bencoding::BDict;
+    use crate::storage::MemoryStorage;
+    use crate::torrent::parse_torrent_data;
+
+    const PIECE_SIZE: u64 = 4 * BLOCK_SIZE;
+
+    /// A single file of two 64 KiB pieces, stored as all ones.
+    fn storage() -> MemoryStorage {
+        let mut metainfo = format!(
+            "d4:infod6:lengthi{}e4:name4:file12:piece lengthi{}e6:pieces40:",
+            2 * PIECE_SIZE,
+            PIECE_SIZE
+        )
+        .into_bytes();
+        metainfo.extend(vec![0u8; 40]);
+        metainfo.extend_from_slice(b"ee");
+        let (metainfo, _) = bencoding::try_decode(&metainfo).unwrap();
+        let torrent = parse_torrent_data(metainfo.as_any().downcast_ref::<BDict>().unwrap());
+
+        let mut storage = MemoryStorage::new(&torrent);
+        for piece_index in 0..2 {
+            storage
+                .write_block(piece_index, 0, &vec![1; PIECE_SIZE as usize])
+                .unwrap();
+        }
+        storage
+    }
+
+    fn read(disk: &DiskIo<MemoryStorage>, piece_index: u32, begin: u64, length: u64) -> Vec<u8> {
+        disk.read_block(piece_index, begin, length)
+            .recv()
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn write_replaces_overlapping_cached_blocks() {
+        let disk = DiskIo::new(storage());
+        // the read caches the piece as 16 KiB blocks
+        assert_eq!(read(&disk, 0, 0, 16), vec![1; 16]);
+        assert_eq!(read(&disk, 0, BLOCK_SIZE, 16), vec![1; 16]);
+
+        let piece: Vec<u8> = (0..PIECE_SIZE).map(|i| (i % 251) as u8).collect();
+        disk.write_block(0, 0, piece.clone());
+        let expected = &piece[BLOCK_SIZE as usize..BLOCK_SIZE as usize + 16];
+        assert_eq!(read(&disk, 0, BLOCK_SIZE, 16), expected);
+
+        disk.flush().recv().unwrap().unwrap();
+        assert_eq!(read(&disk, 0, BLOCK_SIZE, 16), expected);
+        assert_eq!(read(&disk, 0, 0, PIECE_SIZE), piece);
+    }
+
+    #[test]
+    fn writes_wait_while_dirty_blocks_fill_the_cache() {
+        let config = DiskConfig {
+            cache_size: 2 * BLOCK_SIZE as usize,
+            threads: 1,
+        };
+        let disk = DiskIo::with_config(storage(), config);
+        for i in 0..8 {
+            let begin = (i % 4) as u64 * BLOCK_SIZE;
+            disk.write_block(i / 4, begin, vec![i as u8; BLOCK_SIZE as usize]);
+            let (_, dirty) = disk.get_cache_usage();
+            assert!(dirty <= 2 * BLOCK_SIZE as usize);
+        }
+
+        disk.flush().recv().unwrap().unwrap();
+        for i in 0..8 {
+            assert_eq!(
+                read(&disk, i / 4, (i % 4) as u64 * BLOCK_SIZE, 16),
+                vec![i as u8; 16]
+            );
+        }
+    }
+
+    #[test]
+    fn partial_writes_split_cached_blocks() {
+        let disk = DiskIo::new(storage());
+        assert_eq!(read(&disk, 0, 0, 16), vec![1; 16]);
+
+        disk.write_block(0, 8, vec![2; BLOCK_SIZE as usize]);
+        let mut expected = vec![1; 8];
+        expected.extend(vec![2; BLOCK_SIZE as usize]);
+        expected.extend(vec![1; 8]);
+        assert_eq!(read(&disk, 0, 0, 8), vec![1; 8]);
+        assert_eq!(read(&disk, 0, BLOCK_SIZE + 8, 8), vec![1; 8]);
+
+        disk.flush().recv().unwrap().unwrap();
+        assert_eq!(read(&disk, 0, 0, BLOCK_SIZE + 16), expected);
+        assert_eq!(disk.get_cache_usage().1, 0);
+    }
+
+    #[test]
+    fn clean_blocks_are_evicted_least_recently_used_first() {
+        let mut cache = BlockCache::new(3);
+        let block = |value: u8| Arc::new(vec![value]);
+        let cached = |cache: &BlockCache| cache.blocks.keys().map(|key| key.1).collect::<Vec<_>>();
+        for begin in 0..3 {
+            cache.insert(0, begin, block(1), false);
+        }
+        cache.get(0, 0, 1).unwrap();
+
+        cache.insert(0, 3, block(2), true);
+        cache.insert(0, 4, block(2), true);
+        assert_eq!(cached(&cache), vec![0, 3, 4]);
+        // dirty blocks stay when there is nothing clean left to evict
+        cache.insert(0, 5, block(2), true);
+        cache.insert(0, 6, block(2), true);
+        assert_eq!(cached(&cache), vec![3, 4, 5, 6]);
+        assert_eq!((cache.size, cache.dirty_size), (4, 4));
+
+        let written = Arc::clone(&cache.blocks[&(0, 3)].data);
+        cache.mark_clean(0, 3, &written);
+        assert_eq!(cached(&cache), vec![4, 5, 6]);
+        assert!(cache.clean_lru.is_empty());
+        assert_eq!((cache.size, cache.dirty_size), (3, 3));
+    }
+
+    /// Memory storage whose flush always fails.
+    struct FailingFlush(MemoryStorage);
+
+    impl Storage for FailingFlush {
+        fn get_torrent(&self) -> &Torrent {
+            self.0.get_torrent()
+        }
+
+        fn get_torrent_mut(&mut self) -> &mut Torrent {
+            self.0.get_torrent_mut()
+        }
+
+        fn read_block(&mut self, piece_index: u32, begin: u64, length: u64) -> io::Result<Vec<u8>> {
+            self.0.read_block(piece_index, begin, length)
+        }
+
+        fn write_block(&mut self, piece_index: u32, begin: u64, data: &[u8]) -> io::Result<()> {
+            self.0.write_block(piece_index, begin, data)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::Error::other("disk full"))
+        }
+
+        fn move_to(&mut self, save_path: &std::path::Path) -> io::Result<()> {
+            self.0.move_to(save_path)
+        }
+
+        fn delete(&mut self) -> io::Result<()> {
+            self.0.delete()
+        }
+    }
+
+    #[test]
+    fn shutdown_reports_flush_errors() {
+        let disk = DiskIo::new(storage());
+        disk.write_block(0, 0, vec![2; 16]);
+        disk.shutdown().unwrap();
+
+        let disk = DiskIo::new(FailingFlush(storage()));
+        disk.write_block(0, 0, vec![2; 16]);
+        assert_eq!(disk.shutdown().unwrap_err().to_string(), "disk full");
+    }
+}
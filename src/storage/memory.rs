@@ -0,0 +1,81 @@
+use super::Storage;
+
+use crate::torrent::Torrent;
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+/// Keeps every file in memory, files grow as they are written.
+pub struct MemoryStorage {
+    torrent: Torrent,
+    files: HashMap<usize, Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new(torrent: &Torrent) -> MemoryStorage {
+        MemoryStorage {
+            torrent: torrent.clone(),
+            files: HashMap::new(),
+        }
+    }
+
+    /// Contents of a file, None if nothing was written to it.
+    pub fn get_file(&self, file_index: usize) -> Option<&Vec<u8>> {
+        self.files.get(&file_index)
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get_torrent(&self) -> &Torrent {
+        &self.torrent
+    }
+
+    fn get_torrent_mut(&mut self) -> &mut Torrent {
+        &mut self.torrent
+    }
+
+    fn read_block(&mut self, piece_index: u32, begin: u64, length: u64) -> io::Result<Vec<u8>> {
+        let mut block = Vec::with_capacity(length as usize);
+        for span in self.torrent.map_block(piece_index, begin, length) {
+            let start = span.file_offset as usize;
+            let end = start + span.length as usize;
+            match self.files.get(&span.file_index) {
+                Some(file) if file.len() >= end => block.extend_from_slice(&file[start..end]),
+                _ => return Err(io::ErrorKind::UnexpectedEof.into()),
+            }
+        }
+        Ok(block)
+    }
+
+    fn write_block(&mut self, piece_index: u32, begin: u64, data: &[u8]) -> io::Result<()> {
+        let mut written = 0usize;
+        for span in self
+            .torrent
+            .map_block(piece_index, begin, data.len() as u64)
+        {
+            let start = span.file_offset as usize;
+            let end = start + span.length as usize;
+            let file = self.files.entry(span.file_index).or_default();
+            if file.len() < end {
+                file.resize(end, 0);
+            }
+            file[start..end].copy_from_slice(&data[written..written + span.length as usize]);
+            written += span.length as usize;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_to(&mut self, _save_path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn delete(&mut self) -> io::Result<()> {
+        self.files.clear();
+        Ok(())
+    }
+}
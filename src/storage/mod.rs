@@ -0,0 +1,68 @@
+mod disk;
+mod memory;
+mod part_file;
+mod recheck;
+
+pub use disk::{DiskConfig, DiskIo};
+pub use memory::MemoryStorage;
+pub(crate) use part_file::move_file;
+pub use part_file::PartFile;
+pub use recheck::{recheck, RecheckProgress};
+
+use crate::torrent::Torrent;
+use crate::utility;
+
+use std::io;
+use std::path::Path;
+
+/// Where the data of a torrent lives. Positions are given as a piece index
+/// and an offset in that piece, the backend maps them to its own layout.
+pub trait Storage: Send {
+    fn get_torrent(&self) -> &Torrent;
+
+    /// The piece bitfield of the torrent tracks what the storage holds.
+    fn get_torrent_mut(&mut self) -> &mut Torrent;
+
+    /// Fails with `UnexpectedEof` or `NotFound` for data never written.
+    fn read_block(&mut self, piece_index: u32, begin: u64, length: u64) -> io::Result<Vec<u8>>;
+
+    fn write_block(&mut self, piece_index: u32, begin: u64, data: &[u8]) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// Checks the stored piece against its hash, missing data fails the
+    /// check instead of returning an error.
+    fn verify(&mut self, piece_index: u32) -> io::Result<bool> {
+        match read_piece(self, piece_index)? {
+            Some(piece) => {
+                Ok(utility::hash(piece) == self.get_torrent().get_piece_hash(piece_index))
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Moves the stored data under a new save path. Either all of it ends
+    /// up there or, on error, all of it stays where it was.
+    fn move_to(&mut self, save_path: &Path) -> io::Result<()>;
+
+    /// Removes the stored data of the torrent.
+    fn delete(&mut self) -> io::Result<()>;
+}
+
+/// Reads a whole piece, None when part of it was never written.
+fn read_piece<S: Storage + ?Sized>(
+    storage: &mut S,
+    piece_index: u32,
+) -> io::Result<Option<Vec<u8>>> {
+    let piece_size = storage.get_torrent().get_piece_size(piece_index);
+    match storage.read_block(piece_index, 0, piece_size) {
+        Ok(piece) => Ok(Some(piece)),
+        Err(error)
+            if error.kind() == io::ErrorKind::NotFound
+                || error.kind() == io::ErrorKind::UnexpectedEof =>
+        {
+            Ok(None)
+        }
+        Err(error) => Err(error),
+    }
+}
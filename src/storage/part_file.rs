@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, OpenOptions};
+use std::io::prelude::*;
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Holds the parts of pieces that belong to skipped files. A piece shared
+/// by a wanted and a skipped file can only be hashed and served with all
+/// of its bytes, those of the skipped file end up here so the file itself
+/// is never created.
+///
+/// The file starts with the piece count and a slot number for every piece,
+/// 0 for none, followed by one piece length sized slot per stored piece.
+/// Slots of freed pieces are reused.
+#[derive(Debug)]
+pub struct PartFile {
+    path: PathBuf,
+    piece_count: u32,
+    piece_length: u64,
+    slots: HashMap<u32, u32>,
+    file: Option<fs::File>,
+    dirty: bool,
+}
+
+impl PartFile {
+    /// Opens the part file at `path`, nothing is created until a piece is
+    /// written.
+    pub fn open(path: &Path, piece_count: u32, piece_length: u64) -> io::Result<PartFile> {
+        let mut part_file = PartFile {
+            path: path.to_path_buf(),
+            piece_count,
+            piece_length,
+            slots: HashMap::new(),
+            file: None,
+            dirty: false,
+        };
+
+        let mut file = match OpenOptions::new().read(true).write(true).open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(part_file),
+            Err(err) => return Err(err),
+        };
+        let mut header = vec![0u8; part_file.header_length() as usize];
+        file.read_exact(&mut header)?;
+        if u32::from_be_bytes(header[..4].try_into().unwrap()) != piece_count {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Part file belongs to a different torrent",
+            ));
+        }
+        for (index, slot) in header[4..].chunks_exact(4).enumerate() {
+            match u32::from_be_bytes(slot.try_into().unwrap()) {
+                0 => (),
+                slot => {
+                    part_file.slots.insert(index as u32, slot - 1);
+                }
+            }
+        }
+        part_file.file = Some(file);
+        Ok(part_file)
+    }
+
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn has_piece(&self, piece_index: u32) -> bool {
+        self.slots.contains_key(&piece_index)
+    }
+
+    /// Pieces with data in the file, in no particular order.
+    pub fn get_pieces(&self) -> Vec<u32> {
+        self.slots.keys().cloned().collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Fails with `NotFound` when nothing of the piece was written.
+    pub fn read(&mut self, piece_index: u32, begin: u64, length: u64) -> io::Result<Vec<u8>> {
+        let position = match self.slots.get(&piece_index) {
+            Some(slot) => self.slot_position(*slot) + begin,
+            None => return Err(io::ErrorKind::NotFound.into()),
+        };
+        let file = self.file.as_mut().unwrap();
+        let mut data = vec![0u8; length as usize];
+        file.seek(SeekFrom::Start(position))?;
+        file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    pub fn write(&mut self, piece_index: u32, begin: u64, data: &[u8]) -> io::Result<()> {
+        let slot = match self.slots.get(&piece_index) {
+            Some(slot) => *slot,
+            None => {
+                let slot = (0..).find(|slot| !self.slots.values().any(|used| used == slot));
+                let slot = slot.unwrap();
+                self.slots.insert(piece_index, slot);
+                self.dirty = true;
+                slot
+            }
+        };
+        let position = self.slot_position(slot) + begin;
+        let file = self.create()?;
+        file.seek(SeekFrom::Start(position))?;
+        file.write_all(data)
+    }
+
+    /// Drops a piece, its slot gets reused by the next new piece.
+    pub fn free(&mut self, piece_index: u32) {
+        if self.slots.remove(&piece_index).is_some() {
+            self.dirty = true;
+        }
+    }
+
+    /// Writes the slot table, removes the file once it holds no pieces.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.slots.is_empty() {
+            if self.file.take().is_some() {
+                fs::remove_file(&self.path)?;
+            }
+            self.dirty = false;
+            return Ok(());
+        }
+        if !self.dirty {
+            if let Some(file) = &self.file {
+                file.sync_data()?;
+            }
+            return Ok(());
+        }
+
+        let mut header = Vec::with_capacity(self.header_length() as usize);
+        header.extend_from_slice(&self.piece_count.to_be_bytes());
+        for index in 0..self.piece_count {
+            let slot = self.slots.get(&index).map_or(0, |slot| slot + 1);
+            header.extend_from_slice(&slot.to_be_bytes());
+        }
+        let file = self.create()?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&header)?;
+        file.sync_data()?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    pub fn move_to(&mut self, path: &Path) -> io::Result<()> {
+        self.flush()?;
+        if let Some(file) = self.file.take() {
+            // keep the file open where it is when it can't be moved
+            if let Err(err) = move_file(&self.path, path) {
+                self.file = Some(file);
+                return Err(err);
+            }
+            self.file = Some(OpenOptions::new().read(true).write(true).open(path)?);
+        }
+        self.path = path.to_path_buf();
+        Ok(())
+    }
+
+    pub fn delete(&mut self) -> io::Result<()> {
+        self.slots.clear();
+        self.dirty = false;
+        if self.file.take().is_some() {
+            fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+
+    fn header_length(&self) -> u64 {
+        4 + 4 * self.piece_count as u64
+    }
+
+    fn slot_position(&self, slot: u32) -> u64 {
+        self.header_length() + slot as u64 * self.piece_length
+    }
+
+    fn create(&mut self) -> io::Result<&mut fs::File> {
+        if self.file.is_none() {
+            if let Some(parent) = self.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&self.path)?;
+            self.file = Some(file);
+            self.dirty = true;
+        }
+        Ok(self.file.as_mut().unwrap())
+    }
+}
+
+/// Moves a file, creating the directories leading to `to`.
+pub(crate) fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // rename fails across file systems, copy instead
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to)?;
+        fs::remove_file(from)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,218 @@
+use super::{read_piece, Storage};
+
+use crate::utility;
+
+use std::io;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Pieces read ahead of the hashing threads, per thread.
+const PIECES_PER_THREAD: usize = 2;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RecheckProgress {
+    total: u32,
+    checked: u32,
+    passed: u32,
+}
+
+impl RecheckProgress {
+    pub fn get_total(&self) -> u32 {
+        self.total
+    }
+
+    pub fn get_checked(&self) -> u32 {
+        self.checked
+    }
+
+    /// Pieces that matched their hash so far.
+    pub fn get_passed(&self) -> u32 {
+        self.passed
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.checked == self.total
+    }
+}
+
+/// Hashes every piece in the storage and sets the have-bitfield of the
+/// torrent to the pieces that pass, pieces that fail or are missing are
+/// cleared. Pieces are read in order on the calling thread and hashed on
+/// `threads` worker threads, 0 uses one per core. `on_progress` is called
+/// after each checked piece.
+pub fn recheck<S, F>(
+    storage: &mut S,
+    threads: usize,
+    mut on_progress: F,
+) -> io::Result<RecheckProgress>
+where
+    S: Storage + ?Sized,
+    F: FnMut(&RecheckProgress),
+{
+    let threads = match threads {
+        0 => thread::available_parallelism().map_or(1, |threads| threads.get()),
+        threads => threads,
+    };
+    let mut progress = RecheckProgress {
+        total: storage.get_torrent().get_piece_count(),
+        ..RecheckProgress::default()
+    };
+
+    let (piece_sender, piece_receiver) = mpsc::sync_channel(threads * PIECES_PER_THREAD);
+    let piece_receiver = Arc::new(Mutex::new(piece_receiver));
+    let (result_sender, result_receiver) = mpsc::channel();
+    let workers: Vec<_> = (0..threads)
+        .map(|_| {
+            let piece_receiver = Arc::clone(&piece_receiver);
+            let result_sender = result_sender.clone();
+            thread::spawn(move || hash_pieces(piece_receiver, result_sender))
+        })
+        .collect();
+    drop(result_sender);
+
+    let mut result = Ok(());
+    for index in 0..progress.total {
+        let piece = match read_piece(storage, index) {
+            Ok(piece) => piece,
+            Err(error) => {
+                result = Err(error);
+                break;
+            }
+        };
+        let hash = *storage.get_torrent().get_piece_hash(index);
+        match piece {
+            Some(piece) => piece_sender.send((index, piece, hash)).unwrap(),
+            None => record(storage, &mut progress, index, false, &mut on_progress),
+        }
+        while let Ok((index, passed)) = result_receiver.try_recv() {
+            record(storage, &mut progress, index, passed, &mut on_progress);
+        }
+    }
+
+    drop(piece_sender);
+    for (index, passed) in result_receiver {
+        record(storage, &mut progress, index, passed, &mut on_progress);
+    }
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    result.map(|_| progress)
+}
+
+type Piece = (u32, Vec<u8>, [u8; 20]);
+
+fn hash_pieces(pieces: Arc<Mutex<Receiver<Piece>>>, results: mpsc::Sender<(u32, bool)>) {
+    loop {
+        let piece = pieces.lock().unwrap().recv();
+        let (index, piece, hash) = match piece {
+            Ok(piece) => piece,
+            Err(_) => return,
+        };
+        if results.send((index, utility::hash(piece) == hash)).is_err() {
+            return;
+        }
+    }
+}
+
+fn record<S, F>(
+    storage: &mut S,
+    progress: &mut RecheckProgress,
+    index: u32,
+    passed: bool,
+    on_progress: &mut F,
+) where
+    S: Storage + ?Sized,
+    F: FnMut(&RecheckProgress),
+{
+    if passed {
+        storage.get_torrent_mut().set_piece(index);
+        progress.passed += 1;
+    } else {
+        storage.get_torrent_mut().clear_piece(index);
+    }
+    progress.checked += 1;
+    on_progress(progress);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencoding;
+    use crate::bencoding::BDict;
+    use crate::storage::MemoryStorage;
+    use crate::torrent::{parse_torrent_data, Torrent};
+
+    use sha1::{Digest, Sha1};
+
+    /// 72 bytes of data in pieces of 16, the last piece is 8 bytes.
+    fn data() -> Vec<u8> {
+        (0..72).collect()
+    }
+
+    fn torrent() -> Torrent {
+        let mut metainfo =
+            b"d4:infod6:lengthi72e4:name4:file12:piece lengthi16e6:pieces100:".to_vec();
+        for piece in data().chunks(16) {
+            metainfo.extend_from_slice(&Sha1::digest(piece));
+        }
+        metainfo.extend_from_slice(b"ee");
+        let (metainfo, _) = bencoding::try_decode(&metainfo).unwrap();
+        parse_torrent_data(metainfo.as_any().downcast_ref::<BDict>().unwrap())
+    }
+
+    /// Piece 0 and 2 are good, piece 1 is corrupt and pieces 3 and 4 were
+    /// never written. Every piece starts out set.
+    fn storage() -> MemoryStorage {
+        let mut torrent = torrent();
+        for index in 0..torrent.get_piece_count() {
+            torrent.set_piece(index);
+        }
+        let mut storage = MemoryStorage::new(&torrent);
+        let data = data();
+        storage.write_block(0, 0, &data[..16]).unwrap();
+        storage.write_block(1, 0, &[0xff; 16]).unwrap();
+        storage.write_block(2, 0, &data[32..48]).unwrap();
+        storage
+    }
+
+    fn pieces(storage: &MemoryStorage) -> Vec<u8> {
+        let torrent = storage.get_torrent();
+        (0..torrent.get_piece_count())
+            .map(|index| torrent.get_piece(index))
+            .collect()
+    }
+
+    #[test]
+    fn good_pieces_are_set_and_bad_ones_cleared() {
+        let mut storage = storage();
+        let mut updates = Vec::new();
+        let progress = recheck(&mut storage, 1, |progress| updates.push(*progress)).unwrap();
+
+        assert_eq!(pieces(&storage), vec![1, 0, 1, 0, 0]);
+        assert_eq!(progress.get_total(), 5);
+        assert_eq!(progress.get_checked(), 5);
+        assert_eq!(progress.get_passed(), 2);
+        assert!(progress.is_done());
+
+        let checked: Vec<u32> = updates.iter().map(|update| update.get_checked()).collect();
+        assert_eq!(checked, vec![1, 2, 3, 4, 5]);
+        assert!(updates[..4].iter().all(|update| !update.is_done()));
+        assert_eq!(updates.last(), Some(&progress));
+    }
+
+    #[test]
+    fn thread_count_does_not_change_the_result() {
+        let mut single = storage();
+        let single_progress = recheck(&mut single, 1, |_| ()).unwrap();
+        for threads in &[0, 2, 4] {
+            let mut storage = storage();
+            let mut updates = 0;
+            let progress = recheck(&mut storage, *threads, |_| updates += 1).unwrap();
+            assert_eq!(progress, single_progress);
+            assert_eq!(pieces(&storage), pieces(&single));
+            assert_eq!(updates, 5);
+        }
+    }
+}
@@ -1,13 +1,24 @@
 use crate::bencoding::{BDict, BInt, BList, BString};
 
-use rand::Rng;
+use rand::seq::SliceRandom;
+use std::collections::HashSet;
 use std::convert::TryInto;
 
+/// Longest file name most file systems accept, in bytes.
+const MAX_NAME_LENGTH: usize = 255;
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
 #[derive(Clone, Debug)]
 pub struct SingleFileMetaInfo {
     info: SingleFileInfo,
     announce: String,
+    announce_list: Vec<Vec<String>>,
     pieces: Vec<u8>,
+    priorities: Vec<FilePriority>,
+    warnings: Vec<String>,
 }
 
 #[derive(Clone, Debug)]
@@ -28,10 +39,14 @@ impl SingleFileMetaInfo {
 pub struct MultiFileMetaInfo {
     info: MultiFileInfo,
     announce: String,
+    announce_list: Vec<Vec<String>>,
     pieces: Vec<u8>,
+    priorities: Vec<FilePriority>,
+    warnings: Vec<String>,
 }
 
 impl MultiFileMetaInfo {
+    /// Files holding data of the piece, in torrent order.
     pub fn get_files(&self, piece_index: u32) -> Vec<File> {
         let mut files = Vec::new();
         for file in self.info.files.to_vec() {
@@ -45,6 +60,10 @@ impl MultiFileMetaInfo {
     pub fn get_name(&self) -> &str {
         &self.info.name
     }
+
+    pub fn get_file_list(&self) -> &Vec<File> {
+        &self.info.files
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -59,6 +78,7 @@ struct MultiFileInfo {
 pub struct File {
     length: i64,
     path: Vec<String>,
+    offset: u64,
     piece_ext: (u32, u32),
 }
 
@@ -67,6 +87,16 @@ impl File {
         self.length
     }
 
+    /// Absolute position of the first byte of the file in the torrent.
+    pub fn get_offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Absolute position one past the last byte of the file.
+    pub fn get_end(&self) -> u64 {
+        self.offset + self.length as u64
+    }
+
     pub fn get_path(&self) -> Vec<String> {
         self.path.to_vec()
     }
@@ -80,6 +110,26 @@ impl File {
     }
 }
 
+/// How eagerly the pieces of a file are downloaded, `Skip` leaves the file
+/// out entirely.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FilePriority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// A contiguous part of a piece or block stored in a single file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileSpan {
+    /// Index in the file list, always 0 for single file torrents.
+    pub file_index: usize,
+    pub file_offset: u64,
+    pub length: u64,
+}
+
 #[derive(Clone, Debug)]
 pub enum Torrent {
     SingleFileTorrent(SingleFileMetaInfo),
@@ -96,6 +146,31 @@ impl Torrent {
         String::from(announce)
     }
 
+    /// BEP 12 tiers of trackers, a single tier holding `announce` when the
+    /// torrent has no `announce-list`. Empty for trackerless torrents.
+    pub fn get_announce_list(&self) -> &Vec<Vec<String>> {
+        match self {
+            Torrent::SingleFileTorrent(meta_data) => &meta_data.announce_list,
+            Torrent::MultiFileTorrent(meta_data) => &meta_data.announce_list,
+        }
+    }
+
+    pub fn get_announce_list_mut(&mut self) -> &mut Vec<Vec<String>> {
+        match self {
+            Torrent::SingleFileTorrent(meta_data) => &mut meta_data.announce_list,
+            Torrent::MultiFileTorrent(meta_data) => &mut meta_data.announce_list,
+        }
+    }
+
+    /// Problems fixed up while parsing the metainfo, such as unsafe or
+    /// duplicate file paths that were renamed.
+    pub fn get_warnings(&self) -> &Vec<String> {
+        match self {
+            Torrent::SingleFileTorrent(meta_data) => &meta_data.warnings,
+            Torrent::MultiFileTorrent(meta_data) => &meta_data.warnings,
+        }
+    }
+
     pub fn get_length(&self) -> i64 {
         match self {
             Torrent::SingleFileTorrent(meta_data) => meta_data.info.length,
@@ -123,6 +198,69 @@ impl Torrent {
         }
     }
 
+    pub fn get_piece_count(&self) -> u32 {
+        match self {
+            Torrent::MultiFileTorrent(meta_data) => meta_data.info.pieces.len() as u32,
+            Torrent::SingleFileTorrent(meta_data) => meta_data.info.pieces.len() as u32,
+        }
+    }
+
+    /// Size of the piece, only the last piece can be shorter than the piece
+    /// length.
+    pub fn get_piece_size(&self, index: u32) -> u64 {
+        let piece_length = self.get_piece_length() as u64;
+        let start = index as u64 * piece_length;
+        (self.get_length() as u64)
+            .saturating_sub(start)
+            .min(piece_length)
+    }
+
+    /// Spans of the files a whole piece is stored in.
+    pub fn map_piece(&self, index: u32) -> Vec<FileSpan> {
+        self.map_block(index, 0, self.get_piece_size(index))
+    }
+
+    /// Spans of the files the `length` bytes at `begin` in piece `index` are
+    /// stored in, cut at the end of the torrent. Empty files never show up.
+    pub fn map_block(&self, index: u32, begin: u64, length: u64) -> Vec<FileSpan> {
+        let total_length = self.get_length() as u64;
+        let start = (index as u64 * self.get_piece_length() as u64)
+            .saturating_add(begin)
+            .min(total_length);
+        let end = start.saturating_add(length).min(total_length);
+        if start == end {
+            return Vec::new();
+        }
+
+        let files = match self {
+            Torrent::SingleFileTorrent(_) => {
+                return vec![FileSpan {
+                    file_index: 0,
+                    file_offset: start,
+                    length: end - start,
+                }]
+            }
+            Torrent::MultiFileTorrent(meta_data) => &meta_data.info.files,
+        };
+
+        let first = files.partition_point(|file| file.get_end() <= start);
+        files[first..]
+            .iter()
+            .enumerate()
+            .take_while(|(_, file)| file.offset < end)
+            .filter(|(_, file)| file.length > 0)
+            .map(|(i, file)| {
+                let span_start = start.max(file.offset);
+                let span_end = end.min(file.get_end());
+                FileSpan {
+                    file_index: first + i,
+                    file_offset: span_start - file.offset,
+                    length: span_end - span_start,
+                }
+            })
+            .collect()
+    }
+
     pub fn set_piece(&mut self, index: u32) {
         match self {
             Torrent::MultiFileTorrent(meta_data) => meta_data.pieces[index as usize] = 1,
@@ -131,6 +269,46 @@ impl Torrent {
         }
     }
 
+    pub fn clear_piece(&mut self, index: u32) {
+        match self {
+            Torrent::MultiFileTorrent(meta_data) => meta_data.pieces[index as usize] = 0,
+
+            Torrent::SingleFileTorrent(meta_data) => meta_data.pieces[index as usize] = 0,
+        }
+    }
+
+    /// Number of files, 1 for single file torrents.
+    pub fn get_file_count(&self) -> usize {
+        match self {
+            Torrent::MultiFileTorrent(meta_data) => meta_data.info.files.len(),
+            Torrent::SingleFileTorrent(_) => 1,
+        }
+    }
+
+    pub fn get_file_priority(&self, file_index: usize) -> FilePriority {
+        match self {
+            Torrent::MultiFileTorrent(meta_data) => meta_data.priorities[file_index],
+            Torrent::SingleFileTorrent(meta_data) => meta_data.priorities[file_index],
+        }
+    }
+
+    pub fn set_file_priority(&mut self, file_index: usize, priority: FilePriority) {
+        match self {
+            Torrent::MultiFileTorrent(meta_data) => meta_data.priorities[file_index] = priority,
+            Torrent::SingleFileTorrent(meta_data) => meta_data.priorities[file_index] = priority,
+        }
+    }
+
+    /// Highest priority of the files the piece is stored in, a piece is only
+    /// skipped when all of its files are.
+    pub fn get_piece_priority(&self, index: u32) -> FilePriority {
+        self.map_piece(index)
+            .iter()
+            .map(|span| self.get_file_priority(span.file_index))
+            .max()
+            .unwrap_or(FilePriority::Skip)
+    }
+
     pub fn get_piece(&self, index: u32) -> u8 {
         match self {
             Torrent::MultiFileTorrent(meta_data) => meta_data.pieces[index as usize],
@@ -138,17 +316,34 @@ impl Torrent {
         }
     }
 
-    pub fn generate_piece_index(&self) -> u32 {
-        let total_length = match self {
-            Torrent::MultiFileTorrent(meta_data) => meta_data.pieces.len(),
-            Torrent::SingleFileTorrent(meta_data) => meta_data.pieces.len(),
-        };
-        let mut piece_index = rand::thread_rng().gen_range(0, total_length as u32);
-        if self.get_piece(piece_index) == 1 {
-            piece_index = self.generate_piece_index();
+    /// Picks a random missing piece among the ones with the highest
+    /// priority, None once every wanted piece is downloaded.
+    pub fn generate_piece_index(&self) -> Option<u32> {
+        let mut candidates = Vec::new();
+        let mut best = FilePriority::Skip;
+        for index in 0..self.get_piece_count() {
+            if self.get_piece(index) == 1 {
+                continue;
+            }
+            let priority = self.get_piece_priority(index);
+            if priority == FilePriority::Skip || priority < best {
+                continue;
+            }
+            if priority > best {
+                best = priority;
+                candidates.clear();
+            }
+            candidates.push(index);
         }
 
-        piece_index
+        candidates.choose(&mut rand::thread_rng()).cloned()
+    }
+
+    /// Whether every piece that isn't skipped is downloaded.
+    pub fn is_finished(&self) -> bool {
+        (0..self.get_piece_count()).all(|index| {
+            self.get_piece(index) == 1 || self.get_piece_priority(index) == FilePriority::Skip
+        })
     }
 
     pub fn is_completed(&self) -> bool {
@@ -169,51 +364,69 @@ impl Torrent {
 }
 
 pub fn parse_torrent_data(torrent_meta_data: &BDict) -> Torrent {
-    let announce = torrent_meta_data
-        .get::<BString>("announce")
-        .unwrap()
-        .into_string()
-        .unwrap();
+    let announce_list = parse_announce_list(torrent_meta_data);
+    // trackerless torrents have neither, their announce stays empty
+    let announce = match torrent_meta_data.get::<BString>("announce") {
+        Some(announce) => announce.into_string().unwrap_or_default(),
+        None => announce_list
+            .first()
+            .and_then(|tier| tier.first())
+            .cloned()
+            .unwrap_or_default(),
+    };
+    let announce_list = if announce_list.is_empty() && !announce.is_empty() {
+        vec![vec![announce.clone()]]
+    } else {
+        announce_list
+    };
     let info = torrent_meta_data.get::<BDict>("info").unwrap();
 
-    let name = info.get::<BString>("name").unwrap().into_string().unwrap();
+    let name = sanitize_name(&String::from_utf8_lossy(
+        info.get::<BString>("name").unwrap(),
+    ));
     let piece_length = info.get::<BInt>("piece length").unwrap().into_int();
     let pieces = make_pieces(&info.get::<BString>("pieces").unwrap().to_vec());
 
+    let mut warnings = Vec::new();
     let torrent: Torrent;
     match info.get::<BList>("files") {
         Some(file_list) => {
             let file_list = file_list.get();
             let mut files: Vec<File> = Vec::new();
-            let mut piece_start = 0_f32;
+            let piece_length_u64 = piece_length as u64;
+            let mut offset = 0u64;
+            let mut used_paths = UsedPaths::default();
             for file in file_list {
                 let file = file.as_any().downcast_ref::<BDict>().unwrap();
                 let length = file.get::<BInt>("length").unwrap().into_int();
                 let path_list = file.get::<BList>("path").unwrap().get().clone();
                 let mut path: Vec<String> = Vec::new();
                 for paths in path_list {
-                    let paths = paths
-                        .as_any()
-                        .downcast_ref::<BString>()
-                        .unwrap()
-                        .into_string()
-                        .unwrap();
-                    path.push(paths);
+                    let paths = paths.as_any().downcast_ref::<BString>().unwrap();
+                    path.push(String::from_utf8_lossy(paths).into_owned());
                 }
-                let piece_end = length as f32 / piece_length as f32;
-
+                let path = used_paths.add(sanitize_path(&path, &mut warnings), &mut warnings);
+                // an empty file sits in the piece its offset falls in
+                let last_byte = (offset + length as u64).saturating_sub(1).max(offset);
                 files.push(File {
                     path,
                     length,
-                    piece_ext: (piece_start as u32, (piece_end + piece_start) as u32),
+                    offset,
+                    piece_ext: (
+                        (offset / piece_length_u64) as u32,
+                        (last_byte / piece_length_u64) as u32,
+                    ),
                 });
 
-                piece_start += piece_end;
+                offset += length as u64;
             }
 
             torrent = Torrent::MultiFileTorrent(MultiFileMetaInfo {
                 announce,
+                announce_list,
                 pieces: vec![0; pieces.len()],
+                priorities: vec![FilePriority::default(); files.len()],
+                warnings,
                 info: MultiFileInfo {
                     name,
                     files,
@@ -228,7 +441,10 @@ pub fn parse_torrent_data(torrent_meta_data: &BDict) -> Torrent {
 
             torrent = Torrent::SingleFileTorrent(SingleFileMetaInfo {
                 announce,
+                announce_list,
                 pieces: vec![0; pieces.len()],
+                priorities: vec![FilePriority::default()],
+                warnings,
                 info: SingleFileInfo {
                     name,
                     length,
@@ -242,6 +458,164 @@ pub fn parse_torrent_data(torrent_meta_data: &BDict) -> Torrent {
     torrent
 }
 
+/// Parses the `announce-list` tiers, shuffling the trackers within each tier
+/// as BEP 12 requires. Empty tiers are dropped.
+fn parse_announce_list(torrent_meta_data: &BDict) -> Vec<Vec<String>> {
+    let mut announce_list = Vec::new();
+    let tiers = match torrent_meta_data.get::<BList>("announce-list") {
+        Some(tiers) => tiers.get(),
+        None => return announce_list,
+    };
+
+    for tier in tiers {
+        let tier = match tier.as_any().downcast_ref::<BList>() {
+            Some(tier) => tier.get(),
+            None => continue,
+        };
+        let mut trackers: Vec<String> = tier
+            .iter()
+            .filter_map(|tracker| tracker.as_any().downcast_ref::<BString>())
+            .filter_map(|tracker| tracker.into_string())
+            .collect();
+        if trackers.is_empty() {
+            continue;
+        }
+        trackers.shuffle(&mut rand::thread_rng());
+        announce_list.push(trackers);
+    }
+
+    announce_list
+}
+
+/// Turns a path from the metainfo into one that stays inside the torrent
+/// directory: `.` and `..` components are dropped and every remaining
+/// component is made a safe file name. An empty path becomes `_`.
+fn sanitize_path(path: &[String], warnings: &mut Vec<String>) -> Vec<String> {
+    let mut sanitized: Vec<String> = path
+        .iter()
+        .filter(|component| !matches!(component.as_str(), "" | "." | ".."))
+        .map(|component| sanitize_name(component))
+        .collect();
+    if sanitized.is_empty() {
+        sanitized.push(String::from("_"));
+    }
+    if sanitized != path {
+        warnings.push(format!(
+            "Renamed unsafe path {:?} to {:?}",
+            path.join("/"),
+            sanitized.join("/")
+        ));
+    }
+    sanitized
+}
+
+/// Makes a single path component safe to create on any platform. Path
+/// separators, NUL and other control or reserved characters become `_`,
+/// trailing dots and spaces are dropped, reserved device names get a `_`
+/// prefix and names are cut to `MAX_NAME_LENGTH` bytes keeping the
+/// extension.
+fn sanitize_name(name: &str) -> String {
+    let mut name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    while name.ends_with('.') || name.ends_with(' ') {
+        name.pop();
+    }
+    if matches!(name.as_str(), "" | "." | "..") {
+        return String::from("_");
+    }
+
+    let stem = name.split('.').next().unwrap_or("").trim_end();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+    {
+        name.insert(0, '_');
+    }
+    truncate_name(&name, MAX_NAME_LENGTH)
+}
+
+/// Cuts a name to at most `max_length` bytes on a character boundary,
+/// keeping a short extension.
+fn truncate_name(name: &str, max_length: usize) -> String {
+    if name.len() <= max_length {
+        return String::from(name);
+    }
+    let extension = match name.rfind('.') {
+        Some(dot) if dot > 0 && name.len() - dot <= 16 => &name[dot..],
+        _ => "",
+    };
+    let mut end = max_length - extension.len();
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &name[..end], extension)
+}
+
+/// Paths already taken by the files of a torrent, renames files that
+/// would collide with an earlier file or directory. Paths are compared
+/// case-folded since they may end up on a case-insensitive file system.
+#[derive(Default)]
+struct UsedPaths {
+    files: HashSet<Vec<String>>,
+    dirs: HashSet<Vec<String>>,
+}
+
+impl UsedPaths {
+    fn add(&mut self, mut path: Vec<String>, warnings: &mut Vec<String>) -> Vec<String> {
+        let original = path.clone();
+        let mut counter = 1;
+        loop {
+            let key = fold_case(&path);
+            // a directory of the path may be an earlier file
+            let conflict = (1..key.len())
+                .find(|end| self.files.contains(&key[..*end]))
+                .map(|end| end - 1);
+            let conflict = match conflict {
+                Some(component) => component,
+                None if self.files.contains(&key) || self.dirs.contains(&key) => key.len() - 1,
+                None => break,
+            };
+            path[conflict] = numbered_name(&original[conflict], counter);
+            counter += 1;
+        }
+        if path != original {
+            warnings.push(format!(
+                "Renamed duplicate path {:?} to {:?}",
+                original.join("/"),
+                path.join("/")
+            ));
+        }
+
+        let key = fold_case(&path);
+        for end in 1..key.len() {
+            self.dirs.insert(key[..end].to_vec());
+        }
+        self.files.insert(key);
+        path
+    }
+}
+
+fn fold_case(path: &[String]) -> Vec<String> {
+    path.iter()
+        .map(|component| component.to_lowercase())
+        .collect()
+}
+
+/// `name.ext` becomes `name.<counter>.ext`.
+fn numbered_name(name: &str, counter: u32) -> String {
+    let numbered = match name.rfind('.') {
+        Some(dot) if dot > 0 => format!("{}.{}{}", &name[..dot], counter, &name[dot..]),
+        _ => format!("{}.{}", name, counter),
+    };
+    truncate_name(&numbered, MAX_NAME_LENGTH)
+}
+
 fn make_pieces(pieces: &Vec<u8>) -> Vec<[u8; 20]> {
     let mut pieces_array = Vec::new();
 
@@ -254,3 +628,276 @@ fn make_pieces(pieces: &Vec<u8>) -> Vec<[u8; 20]> {
 
     pieces_array
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencoding;
+
+    fn parse(metainfo: &[u8]) -> Torrent {
+        let (metainfo, _) = bencoding::try_decode(metainfo).unwrap();
+        parse_torrent_data(metainfo.as_any().downcast_ref::<BDict>().unwrap())
+    }
+
+    /// A single file torrent of `length` bytes with the given top level
+    /// tracker keys.
+    fn single_file(trackers: &str, length: u64, piece_length: u64) -> Vec<u8> {
+        let piece_count = length.div_ceil(piece_length).max(1) as usize;
+        let mut metainfo = format!(
+            "d{}4:infod6:lengthi{}e4:name4:file12:piece lengthi{}e6:pieces{}:",
+            trackers,
+            length,
+            piece_length,
+            piece_count * 20
+        )
+        .into_bytes();
+        metainfo.extend(vec![0u8; piece_count * 20]);
+        metainfo.extend_from_slice(b"ee");
+        metainfo
+    }
+
+    #[test]
+    fn trackerless_torrent() {
+        let torrent = parse(&single_file("", 10, 16));
+        assert_eq!(torrent.get_announce(), "");
+        assert!(torrent.get_announce_list().is_empty());
+    }
+
+    #[test]
+    fn announce_list_without_announce() {
+        let trackers = "13:announce-listll14:http://a/annouee";
+        let torrent = parse(&single_file(trackers, 10, 16));
+        assert_eq!(torrent.get_announce(), "http://a/annou");
+        assert_eq!(
+            torrent.get_announce_list(),
+            &vec![vec!["http://a/annou".to_string()]]
+        );
+    }
+
+    #[test]
+    fn announce_without_announce_list() {
+        let torrent = parse(&single_file("8:announce8:http://a", 10, 16));
+        assert_eq!(torrent.get_announce(), "http://a");
+        assert_eq!(
+            torrent.get_announce_list(),
+            &vec![vec!["http://a".to_string()]]
+        );
+    }
+
+    /// A multi file torrent with one file per entry of `lengths`, named
+    /// `0`, `1`, ...
+    fn multi_file(lengths: &[u64], piece_length: u64) -> Torrent {
+        let total: u64 = lengths.iter().sum();
+        let piece_count = total.div_ceil(piece_length).max(1) as usize;
+        let mut metainfo = b"d4:infod5:filesl".to_vec();
+        for (i, length) in lengths.iter().enumerate() {
+            let name = i.to_string();
+            metainfo.extend(
+                format!("d6:lengthi{}e4:pathl{}:{}ee", length, name.len(), name).into_bytes(),
+            );
+        }
+        metainfo.extend(
+            format!(
+                "e4:name4:root12:piece lengthi{}e6:pieces{}:",
+                piece_length,
+                piece_count * 20
+            )
+            .into_bytes(),
+        );
+        metainfo.extend(vec![0u8; piece_count * 20]);
+        metainfo.extend_from_slice(b"ee");
+        parse(&metainfo)
+    }
+
+    fn span(file_index: usize, file_offset: u64, length: u64) -> FileSpan {
+        FileSpan {
+            file_index,
+            file_offset,
+            length,
+        }
+    }
+
+    #[test]
+    fn block_within_one_file() {
+        let torrent = multi_file(&[20, 20], 8);
+        assert_eq!(torrent.map_block(0, 2, 4), vec![span(0, 2, 4)]);
+        assert_eq!(torrent.map_block(3, 0, 8), vec![span(1, 4, 8)]);
+    }
+
+    #[test]
+    fn block_crossing_several_files() {
+        let torrent = multi_file(&[5, 3, 0, 4, 10], 8);
+        assert_eq!(
+            torrent.map_block(0, 2, 16),
+            vec![span(0, 2, 3), span(1, 0, 3), span(3, 0, 4), span(4, 0, 6)]
+        );
+        assert_eq!(torrent.map_piece(0), vec![span(0, 0, 5), span(1, 0, 3)]);
+        assert_eq!(torrent.map_piece(1), vec![span(3, 0, 4), span(4, 0, 4)]);
+    }
+
+    #[test]
+    fn zero_length_files_never_show_up() {
+        let torrent = multi_file(&[0, 4, 0, 0, 4, 0], 4);
+        assert_eq!(torrent.map_piece(0), vec![span(1, 0, 4)]);
+        assert_eq!(torrent.map_piece(1), vec![span(4, 0, 4)]);
+        assert_eq!(
+            torrent.map_block(0, 2, 4),
+            vec![span(1, 2, 2), span(4, 0, 2)]
+        );
+
+        let torrent = multi_file(&[0, 0], 4);
+        assert!(torrent.map_piece(0).is_empty());
+    }
+
+    #[test]
+    fn last_piece_is_short() {
+        let torrent = multi_file(&[5, 3, 0, 4, 10], 8);
+        assert_eq!(torrent.get_piece_count(), 3);
+        assert_eq!(torrent.get_piece_size(2), 6);
+        assert_eq!(torrent.map_piece(2), vec![span(4, 4, 6)]);
+
+        let torrent = parse(&single_file("", 20, 8));
+        assert_eq!(torrent.get_piece_size(2), 4);
+        assert_eq!(torrent.map_piece(2), vec![span(0, 16, 4)]);
+    }
+
+    #[test]
+    fn out_of_range_blocks_are_cut() {
+        let torrent = multi_file(&[5, 3, 0, 4, 10], 8);
+        // runs past the end of the torrent
+        assert_eq!(torrent.map_block(2, 4, 100), vec![span(4, 8, 2)]);
+        assert_eq!(torrent.map_block(2, 0, u64::MAX), vec![span(4, 4, 6)]);
+        // starts at or past the end
+        assert!(torrent.map_block(2, 6, 4).is_empty());
+        assert!(torrent.map_block(2, 100, 4).is_empty());
+        assert!(torrent.map_block(7, 0, 8).is_empty());
+        assert!(torrent.map_block(u32::MAX, u64::MAX, 8).is_empty());
+        assert!(torrent.map_block(0, 0, 0).is_empty());
+
+        let torrent = parse(&single_file("", 20, 8));
+        assert_eq!(torrent.map_block(2, 2, 8), vec![span(0, 18, 2)]);
+        assert!(torrent.map_block(3, 0, 8).is_empty());
+    }
+
+    fn path(components: &[&str]) -> Vec<String> {
+        components.iter().map(|c| c.to_string()).collect()
+    }
+
+    /// A multi file torrent with a 1 byte file at each path.
+    fn with_paths(paths: &[&[&str]]) -> Torrent {
+        let mut metainfo = b"d4:infod5:filesl".to_vec();
+        for components in paths {
+            metainfo.extend_from_slice(b"d6:lengthi1e4:pathl");
+            for component in *components {
+                metainfo.extend(format!("{}:{}", component.len(), component).into_bytes());
+            }
+            metainfo.extend_from_slice(b"ee");
+        }
+        metainfo.extend_from_slice(b"e4:name4:root12:piece lengthi16e6:pieces20:");
+        metainfo.extend(vec![0u8; 20]);
+        metainfo.extend_from_slice(b"ee");
+        parse(&metainfo)
+    }
+
+    fn file_paths(torrent: &Torrent) -> Vec<Vec<String>> {
+        match torrent {
+            Torrent::MultiFileTorrent(meta_data) => meta_data
+                .get_file_list()
+                .iter()
+                .map(|file| file.get_path())
+                .collect(),
+            Torrent::SingleFileTorrent(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn dot_components_are_dropped() {
+        let mut warnings = Vec::new();
+        assert_eq!(
+            sanitize_path(&path(&["..", "..", "etc", ".", "passwd"]), &mut warnings),
+            path(&["etc", "passwd"])
+        );
+        assert_eq!(
+            sanitize_path(&path(&["..", "."]), &mut warnings),
+            path(&["_"])
+        );
+        assert_eq!(sanitize_path(&path(&[]), &mut warnings), path(&["_"]));
+        assert_eq!(warnings.len(), 3);
+
+        assert_eq!(
+            sanitize_path(&path(&["a", "b"]), &mut warnings),
+            path(&["a", "b"])
+        );
+        assert_eq!(warnings.len(), 3);
+    }
+
+    #[test]
+    fn absolute_paths_and_separators_stay_in_one_component() {
+        assert_eq!(sanitize_name("/etc/passwd"), "_etc_passwd");
+        assert_eq!(sanitize_name("..\\..\\boot.ini"), ".._.._boot.ini");
+        assert_eq!(sanitize_name("C:\\Windows"), "C__Windows");
+        assert_eq!(sanitize_name("C:"), "C_");
+        assert_eq!(sanitize_name("a\0b\nc"), "a_b_c");
+        assert_eq!(sanitize_name("what?<*>|\""), "what______");
+
+        let torrent = with_paths(&[&["/", "etc", "passwd"], &["C:", "x"]]);
+        assert_eq!(
+            file_paths(&torrent),
+            vec![path(&["_", "etc", "passwd"]), path(&["C_", "x"])]
+        );
+        assert_eq!(torrent.get_warnings().len(), 2);
+    }
+
+    #[test]
+    fn reserved_and_empty_names() {
+        assert_eq!(sanitize_name("CON"), "_CON");
+        assert_eq!(sanitize_name("nul.txt"), "_nul.txt");
+        assert_eq!(sanitize_name("Com1 .tar.gz"), "_Com1 .tar.gz");
+        assert_eq!(sanitize_name("CONSOLE"), "CONSOLE");
+        assert_eq!(sanitize_name("LPT10"), "LPT10");
+        assert_eq!(sanitize_name(""), "_");
+        assert_eq!(sanitize_name("..."), "_");
+        assert_eq!(sanitize_name(" . "), "_");
+        assert_eq!(sanitize_name("name. . "), "name");
+    }
+
+    #[test]
+    fn long_names_are_truncated() {
+        let name = sanitize_name(&"a".repeat(300));
+        assert_eq!(name, "a".repeat(MAX_NAME_LENGTH));
+
+        let name = sanitize_name(&format!("{}.mkv", "b".repeat(300)));
+        assert_eq!(name.len(), MAX_NAME_LENGTH);
+        assert!(name.ends_with("b.mkv"));
+
+        // never cut inside a character
+        let name = sanitize_name(&"\u{e9}".repeat(200));
+        assert_eq!(name, "\u{e9}".repeat(127));
+    }
+
+    #[test]
+    fn duplicate_paths_are_numbered() {
+        let torrent = with_paths(&[
+            &["a.txt"],
+            &["a.txt"],
+            &["A.TXT"],
+            &["dir"],
+            &["dir", "file"],
+            &["x", "y"],
+            &["X"],
+        ]);
+        assert_eq!(
+            file_paths(&torrent),
+            vec![
+                path(&["a.txt"]),
+                path(&["a.1.txt"]),
+                path(&["A.2.TXT"]),
+                path(&["dir"]),
+                path(&["dir.1", "file"]),
+                path(&["x", "y"]),
+                path(&["X.1"]),
+            ]
+        );
+        assert_eq!(torrent.get_warnings().len(), 4);
+    }
+}
@@ -0,0 +1,134 @@
+use super::websocket::{NoSignaling, WebSocketTracker};
+use super::{http, udp, AnnounceRequest, ScrapeResponse, TrackerError, TrackerResponse};
+
+use tokio::task;
+use url::Url;
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock, RwLock};
+
+pub type TrackerFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, TrackerError>> + Send + 'a>>;
+
+/// An announce backend, registered for the url schemes it serves.
+pub trait Tracker: Send + Sync {
+    fn announce<'a>(
+        &'a self,
+        announce_url: &'a Url,
+        request: &'a AnnounceRequest,
+    ) -> TrackerFuture<'a, TrackerResponse>;
+
+    fn scrape<'a>(
+        &'a self,
+        announce_url: &'a Url,
+        info_hashes: &'a [Vec<u8>],
+    ) -> TrackerFuture<'a, ScrapeResponse>;
+}
+
+type Registry = RwLock<HashMap<String, Arc<dyn Tracker>>>;
+
+static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+fn registry() -> &'static Registry {
+    REGISTRY.get_or_init(|| {
+        let mut trackers: HashMap<String, Arc<dyn Tracker>> = HashMap::new();
+        match http::HttpTracker::new(&http::HttpConfig::default()) {
+            Ok(http) => {
+                let http = Arc::new(http);
+                trackers.insert(String::from("http"), http.clone());
+                trackers.insert(String::from("https"), http);
+            }
+            Err(error) => eprintln!("Error: HTTP trackers are unavailable: {}", error),
+        }
+        trackers.insert(String::from("udp"), Arc::new(SharedUdp));
+        let websocket = Arc::new(WebSocketTracker::new(Arc::new(NoSignaling)));
+        trackers.insert(String::from("ws"), websocket.clone());
+        trackers.insert(String::from("wss"), websocket);
+        RwLock::new(trackers)
+    })
+}
+
+/// Serves announce urls of `scheme` with `tracker` from now on, replacing
+/// the previous backend of the scheme if any.
+pub fn register(scheme: &str, tracker: Arc<dyn Tracker>) {
+    registry()
+        .write()
+        .unwrap()
+        .insert(scheme.to_ascii_lowercase(), tracker);
+}
+
+pub fn unregister(scheme: &str) -> Option<Arc<dyn Tracker>> {
+    registry()
+        .write()
+        .unwrap()
+        .remove(&scheme.to_ascii_lowercase())
+}
+
+/// Backend registered for the scheme of `announce_url`.
+pub fn get(announce_url: &Url) -> Result<Arc<dyn Tracker>, TrackerError> {
+    match registry().read().unwrap().get(announce_url.scheme()) {
+        Some(tracker) => Ok(Arc::clone(tracker)),
+        None => Err(TrackerError::InvalidUrl(announce_url.to_string())),
+    }
+}
+
+impl Tracker for http::HttpTracker {
+    fn announce<'a>(
+        &'a self,
+        announce_url: &'a Url,
+        request: &'a AnnounceRequest,
+    ) -> TrackerFuture<'a, TrackerResponse> {
+        Box::pin(http::HttpTracker::announce(
+            self,
+            announce_url.as_str(),
+            request,
+        ))
+    }
+
+    fn scrape<'a>(
+        &'a self,
+        announce_url: &'a Url,
+        info_hashes: &'a [Vec<u8>],
+    ) -> TrackerFuture<'a, ScrapeResponse> {
+        Box::pin(http::HttpTracker::scrape(
+            self,
+            announce_url.as_str(),
+            info_hashes,
+        ))
+    }
+}
+
+/// The shared UDP client, its blocking calls run on the blocking thread
+/// pool.
+struct SharedUdp;
+
+impl Tracker for SharedUdp {
+    fn announce<'a>(
+        &'a self,
+        announce_url: &'a Url,
+        request: &'a AnnounceRequest,
+    ) -> TrackerFuture<'a, TrackerResponse> {
+        let announce_url = announce_url.clone();
+        let request = request.clone();
+        Box::pin(async move {
+            task::spawn_blocking(move || udp::announce(announce_url, &request))
+                .await
+                .map_err(|error| TrackerError::Connection(error.to_string()))?
+        })
+    }
+
+    fn scrape<'a>(
+        &'a self,
+        announce_url: &'a Url,
+        info_hashes: &'a [Vec<u8>],
+    ) -> TrackerFuture<'a, ScrapeResponse> {
+        let announce_url = announce_url.clone();
+        let info_hashes = info_hashes.to_vec();
+        Box::pin(async move {
+            task::spawn_blocking(move || udp::scrape(announce_url, &info_hashes))
+                .await
+                .map_err(|error| TrackerError::Connection(error.to_string()))?
+        })
+    }
+}
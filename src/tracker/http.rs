@@ -1,78 +1,261 @@
-use super::{Peer, TrackerResponse};
+use super::{
+    parse_compact_peers, AnnounceRequest, Peer, ScrapeFile, ScrapeResponse, TrackerError,
+    TrackerResponse,
+};
 
 use crate::bencoding;
-use crate::bencoding::{BDict, BInt, BString};
-use crate::utility::{PeerId, PORT};
+use crate::bencoding::{BDict, BInt, BList, BString};
 
+use reqwest::redirect::Policy;
 use url::form_urlencoded;
 
 use std::borrow::Cow;
-use std::convert::TryInto;
-use std::net::Ipv4Addr;
-
-pub fn announce(
-    announce_url: &str,
-    info_hash: &Vec<u8>,
-    peer_id: &mut PeerId,
-    uploaded: i64,
-    downloaded: i64,
-    left: i64,
-    event: Option<&str>,
-) -> TrackerResponse {
-    let mut announce_url = announce_url.to_string();
-    let query = create_tracker_query(info_hash, peer_id, uploaded, downloaded, left, event);
-
-    announce_url.push('?');
-    announce_url.push_str(&query);
-
-    let (tracker_response, _) = bencoding::decode(&get(&announce_url));
-    let tracker_response = tracker_response.as_any().downcast_ref::<BDict>().unwrap();
-    parse_tracker_response(tracker_response)
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Used when no `HttpConfig` is given.
+pub const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// Announces and scrapes of http and https urls go through a client with
+/// `config` from now on, replacing the backends registered for them.
+pub fn configure(config: &HttpConfig) -> Result<(), TrackerError> {
+    let tracker = Arc::new(HttpTracker::new(config)?);
+    super::register("http", tracker.clone());
+    super::register("https", tracker);
+    Ok(())
 }
 
-#[tokio::main]
-async fn get(url: &str) -> Vec<u8> {
-    let resp = reqwest::get(url).await.unwrap();
-    if resp.status() != 200 {
-        panic!(
-            "Status Code: {}, Error: {}",
-            resp.status(),
-            resp.text().await.unwrap()
-        );
+#[derive(Clone, Debug)]
+pub struct HttpConfig {
+    /// Limit for a whole request, from connecting to reading the body.
+    pub timeout: Duration,
+    pub connect_timeout: Duration,
+    pub user_agent: String,
+    /// Proxy url every tracker request goes through, e.g. `socks5://...`.
+    pub proxy: Option<String>,
+    pub max_redirects: usize,
+}
+
+impl Default for HttpConfig {
+    fn default() -> HttpConfig {
+        HttpConfig {
+            timeout: Duration::from_secs(30),
+            connect_timeout: Duration::from_secs(10),
+            user_agent: USER_AGENT.to_string(),
+            proxy: None,
+            max_redirects: 5,
+        }
+    }
+}
+
+/// HTTP tracker client, cheap to clone as the connection pool is shared.
+#[derive(Clone, Debug)]
+pub struct HttpTracker {
+    client: reqwest::Client,
+}
+
+impl HttpTracker {
+    pub fn new(config: &HttpConfig) -> Result<HttpTracker, TrackerError> {
+        let mut builder = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .connect_timeout(config.connect_timeout)
+            .user_agent(config.user_agent.as_str())
+            .gzip(true)
+            .redirect(Policy::limited(config.max_redirects));
+        if let Some(proxy) = &config.proxy {
+            let proxy = reqwest::Proxy::all(proxy.as_str())
+                .map_err(|_| TrackerError::InvalidUrl(proxy.clone()))?;
+            builder = builder.proxy(proxy);
+        }
+
+        let client = builder
+            .build()
+            .map_err(|error| TrackerError::Connection(error.to_string()))?;
+        Ok(HttpTracker { client })
+    }
+
+    pub async fn announce(
+        &self,
+        announce_url: &str,
+        request: &AnnounceRequest,
+    ) -> Result<TrackerResponse, TrackerError> {
+        let mut announce_url = announce_url.to_string();
+        let query = create_tracker_query(request);
+
+        announce_url.push(if announce_url.contains('?') { '&' } else { '?' });
+        announce_url.push_str(&query);
+
+        let body = self.get(&announce_url).await?;
+        let (mut response, hosts) = {
+            let tracker_response = match bencoding::try_decode(&body) {
+                Some((tracker_response, _)) => tracker_response,
+                None => {
+                    return Err(TrackerError::InvalidResponse(String::from(
+                        "not valid bencoding",
+                    )))
+                }
+            };
+            match tracker_response.as_any().downcast_ref::<BDict>() {
+                Some(tracker_response) => parse_tracker_response(tracker_response)?,
+                None => {
+                    return Err(TrackerError::InvalidResponse(String::from(
+                        "not a dictionary",
+                    )))
+                }
+            }
+        };
+
+        for host in hosts {
+            if let Some(peer) = host.resolve().await {
+                response.peer_list.push(peer);
+            }
+        }
+        Ok(response)
+    }
+
+    pub async fn scrape(
+        &self,
+        announce_url: &str,
+        info_hashes: &[Vec<u8>],
+    ) -> Result<ScrapeResponse, TrackerError> {
+        let mut scrape_url = scrape_url(announce_url)?;
+        for (i, info_hash) in info_hashes.iter().enumerate() {
+            scrape_url.push(if i == 0 && !scrape_url.contains('?') {
+                '?'
+            } else {
+                '&'
+            });
+            scrape_url.push_str("info_hash=");
+            scrape_url.extend(form_urlencoded::byte_serialize(info_hash));
+        }
+
+        let body = self.get(&scrape_url).await?;
+        let scrape_response = match bencoding::try_decode(&body) {
+            Some((scrape_response, _)) => scrape_response,
+            None => {
+                return Err(TrackerError::InvalidResponse(String::from(
+                    "not valid bencoding",
+                )))
+            }
+        };
+        match scrape_response.as_any().downcast_ref::<BDict>() {
+            Some(scrape_response) => parse_scrape_response(scrape_response),
+            None => Err(TrackerError::InvalidResponse(String::from(
+                "not a dictionary",
+            ))),
+        }
+    }
+
+    async fn get(&self, url: &str) -> Result<Vec<u8>, TrackerError> {
+        let resp = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .map_err(|error| TrackerError::Connection(error.to_string()))?;
+        if resp.status() != 200 {
+            return Err(TrackerError::Connection(format!(
+                "Status Code: {}, Error: {}",
+                resp.status(),
+                resp.text().await.unwrap_or_default()
+            )));
+        }
+
+        match resp.bytes().await {
+            Ok(bytes) => Ok(bytes.to_vec()),
+            Err(error) => Err(TrackerError::Connection(error.to_string())),
+        }
+    }
+}
+
+/// The scrape url is the announce url with the `announce` at the start of
+/// its last path component replaced by `scrape`.
+pub fn scrape_url(announce_url: &str) -> Result<String, TrackerError> {
+    let (path, query) = match announce_url.find('?') {
+        Some(position) => announce_url.split_at(position),
+        None => (announce_url, ""),
+    };
+    let last_slash = match path.rfind('/') {
+        Some(position) => position + 1,
+        None => return Err(TrackerError::InvalidUrl(announce_url.to_string())),
+    };
+    if !path[last_slash..].starts_with("announce") {
+        return Err(TrackerError::InvalidUrl(announce_url.to_string()));
+    }
+
+    Ok(format!(
+        "{}scrape{}{}",
+        &path[..last_slash],
+        &path[last_slash + "announce".len()..],
+        query
+    ))
+}
+
+fn parse_scrape_response(scrape_response: &BDict) -> Result<ScrapeResponse, TrackerError> {
+    if let Some(reason) = scrape_response.get::<BString>("failure reason") {
+        return Err(TrackerError::Failure(
+            reason.into_string().unwrap_or_default(),
+        ));
+    }
+
+    let mut files = HashMap::new();
+    if let Some(file_list) = scrape_response.get::<BDict>("files") {
+        for (info_hash, stats) in file_list.iter() {
+            let stats = match stats.as_any().downcast_ref::<BDict>() {
+                Some(stats) => stats,
+                None => continue,
+            };
+            let count = |key: &str| match stats.get::<BInt>(key) {
+                Some(count) => count.into_int() as u32,
+                None => 0,
+            };
+            files.insert(
+                info_hash.to_vec(),
+                ScrapeFile {
+                    complete: count("complete"),
+                    downloaded: count("downloaded"),
+                    incomplete: count("incomplete"),
+                    name: stats
+                        .get::<BString>("name")
+                        .and_then(|name| name.into_string()),
+                },
+            );
+        }
     }
 
-    resp.bytes().await.unwrap().to_vec()
+    Ok(ScrapeResponse { files })
 }
 
-fn create_tracker_query(
-    info_hash: &Vec<u8>,
-    peer_id: &mut PeerId,
-    uploaded: i64,
-    downloaded: i64,
-    left: i64,
-    event: Option<&str>,
-) -> String {
+fn create_tracker_query(request: &AnnounceRequest) -> String {
     let mut query = form_urlencoded::Serializer::new(String::new());
     query
-        .append_pair("peer_id", &peer_id.value())
-        .append_pair("port", &PORT.to_string())
-        .append_pair("uploaded", &uploaded.to_string())
-        .append_pair("downloaded", &downloaded.to_string())
-        .append_pair("left", &left.to_string())
-        .append_pair("compact", "1");
-
-    match event {
-        Some(event_id) => {
-            match event_id {
-                "started" | "completed" | "stopped" => query.append_pair("event", event_id),
-                _ => &mut form_urlencoded::Serializer::new(String::new()),
-            };
-            ()
+        .append_pair("peer_id", request.get_peer_id())
+        .append_pair("port", &request.get_port().to_string())
+        .append_pair("uploaded", &request.get_uploaded().to_string())
+        .append_pair("downloaded", &request.get_downloaded().to_string())
+        .append_pair("left", &request.get_left().to_string())
+        .append_pair("compact", if request.is_compact() { "1" } else { "0" })
+        .append_pair("key", &format!("{:08x}", request.get_key()));
+
+    if !request.is_compact() && request.is_no_peer_id() {
+        query.append_pair("no_peer_id", "1");
+    }
+    if let Some(event) = request.get_event() {
+        if let "started" | "completed" | "stopped" = event {
+            query.append_pair("event", event);
         }
-        None => (),
+    }
+    if let Some(ip) = request.get_ip() {
+        query.append_pair("ip", &ip.to_string());
+    }
+    if let Some(numwant) = request.get_numwant() {
+        query.append_pair("numwant", &numwant.to_string());
     }
 
-    let query = query
+    let info_hash = request.get_info_hash();
+    query
         .encoding_override(Some(&|input| {
             if input != "!" {
                 Cow::Borrowed(input.as_bytes())
@@ -81,49 +264,249 @@ fn create_tracker_query(
             }
         }))
         .append_pair("info_hash", "!")
-        .finish();
-    query
+        .finish()
 }
 
-fn parse_tracker_response(tracker_response: &BDict) -> TrackerResponse {
-    match tracker_response.get::<BString>("failure reason") {
-        Some(reason) => panic!(
-            "Tracker response failure: {}",
-            reason.into_string().unwrap()
-        ),
-        None => (),
-    }
-
-    let interval = tracker_response.get::<BInt>("interval").unwrap().into_int() as u32;
-    let complete = tracker_response.get::<BInt>("complete").unwrap().into_int() as u32;
-    let incomplete = tracker_response
-        .get::<BInt>("incomplete")
-        .unwrap()
-        .into_int() as u32;
-
-    let peer_list = tracker_response.get::<BString>("peers").unwrap().to_vec();
-    TrackerResponse {
-        interval,
-        complete,
-        incomplete,
-        peer_list: parse_peers_string(peer_list),
+/// Parses an announce response, peers of the dictionary model given by
+/// hostname come back separately to be resolved.
+fn parse_tracker_response(
+    tracker_response: &BDict,
+) -> Result<(TrackerResponse, Vec<PeerHost>), TrackerError> {
+    if let Some(reason) = tracker_response.get::<BString>("failure reason") {
+        return Err(TrackerError::Failure(
+            reason.into_string().unwrap_or_default(),
+        ));
+    }
+
+    let interval = match tracker_response.get::<BInt>("interval") {
+        Some(interval) => interval.into_int() as u32,
+        None => {
+            return Err(TrackerError::InvalidResponse(String::from(
+                "missing interval",
+            )))
+        }
+    };
+    let count = |key: &str| {
+        tracker_response
+            .get::<BInt>(key)
+            .map(|n| n.into_int() as u32)
+    };
+    let min_interval = count("min interval");
+    let complete = count("complete");
+    let incomplete = count("incomplete");
+    let warning = tracker_response
+        .get::<BString>("warning message")
+        .and_then(|warning| warning.into_string());
+    let tracker_id = tracker_response
+        .get::<BString>("tracker id")
+        .map(|tracker_id| tracker_id.to_vec());
+
+    let (mut peer_list, hosts) = match tracker_response.get::<BString>("peers") {
+        Some(peers) => (parse_compact_peers(peers, false), Vec::new()),
+        None => match tracker_response.get::<BList>("peers") {
+            Some(peers) => parse_peer_dicts(peers),
+            None => (Vec::new(), Vec::new()),
+        },
+    };
+    if let Some(peers6) = tracker_response.get::<BString>("peers6") {
+        peer_list.append(&mut parse_compact_peers(peers6, true));
     }
+
+    Ok((
+        TrackerResponse {
+            interval,
+            min_interval,
+            complete,
+            incomplete,
+            warning,
+            tracker_id,
+            peer_list,
+        },
+        hosts,
+    ))
 }
 
-fn parse_peers_string(peers_string: Vec<u8>) -> Vec<Peer> {
-    if peers_string.len() % 6 != 0 {
-        panic!("Invalid Peer String");
+/// A peer of the dictionary model whose `ip` is a DNS name.
+#[derive(Debug)]
+struct PeerHost {
+    host: String,
+    port: u16,
+    peer_id: Option<Vec<u8>>,
+}
+
+impl PeerHost {
+    /// The peer at the first address the name resolves to, None if it does
+    /// not resolve.
+    async fn resolve(self) -> Option<Peer> {
+        let mut addrs = tokio::net::lookup_host((self.host.as_str(), self.port))
+            .await
+            .ok()?;
+        let mut peer = Peer::new(addrs.next()?);
+        if let Some(peer_id) = self.peer_id {
+            peer.set_peer_id(peer_id);
+        }
+        Some(peer)
     }
+}
 
+/// Parses the original dictionary model of the peer list, each peer being a
+/// dictionary with `ip`, `port` and optionally `peer id`. The `ip` may be an
+/// address or a DNS name, named peers are returned apart.
+fn parse_peer_dicts(peers: &BList) -> (Vec<Peer>, Vec<PeerHost>) {
     let mut peer_list = Vec::new();
+    let mut hosts = Vec::new();
+    for peer in peers.get() {
+        let peer = match peer.as_any().downcast_ref::<BDict>() {
+            Some(peer) => peer,
+            None => continue,
+        };
+        let ip = peer.get::<BString>("ip").and_then(|ip| ip.into_string());
+        let port = peer.get::<BInt>("port").map(|port| port.into_int());
+        let (ip, port) = match (ip, port) {
+            (Some(ip), Some(port)) if port > 0 && port <= u16::MAX as i64 => (ip, port as u16),
+            _ => continue,
+        };
+        let peer_id = peer
+            .get::<BString>("peer id")
+            .map(|peer_id| peer_id.to_vec());
+        match ip.parse::<IpAddr>() {
+            Ok(ip) => {
+                let mut parsed = Peer::new(SocketAddr::new(ip, port));
+                if let Some(peer_id) = peer_id {
+                    parsed.set_peer_id(peer_id);
+                }
+                peer_list.push(parsed);
+            }
+            Err(_) if !ip.is_empty() => hosts.push(PeerHost {
+                host: ip,
+                port,
+                peer_id,
+            }),
+            Err(_) => (),
+        }
+    }
+    (peer_list, hosts)
+}
 
-    let mut i = 0;
-    while i < peers_string.len() {
-        let ip: [u8; 4] = peers_string[i..i + 4].try_into().unwrap();
-        let ip = Ipv4Addr::from(ip);
-        let port = u16::from_be_bytes(peers_string[i + 4..i + 6].try_into().unwrap());
-        peer_list.push(Peer { ip, port });
-        i += 6;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(response: &[u8]) -> (TrackerResponse, Vec<PeerHost>) {
+        let (response, _) = bencoding::try_decode(response).unwrap();
+        parse_tracker_response(response.as_any().downcast_ref::<BDict>().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn configure_checks_the_config() {
+        let config = HttpConfig {
+            proxy: Some(String::from("not a proxy url")),
+            ..HttpConfig::default()
+        };
+        assert_eq!(
+            configure(&config).unwrap_err(),
+            TrackerError::InvalidUrl(String::from("not a proxy url"))
+        );
+    }
+
+    #[test]
+    fn dict_peers_by_address_and_name() {
+        let (response, hosts) = parse(
+            b"d8:intervali900e5:peersld2:ip8:10.0.0.24:porti6881eed2:ip9:localhost\
+              7:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti6882eed2:ip0:4:porti6883eeee",
+        );
+        assert_eq!(
+            response.get_peer_list(),
+            vec![Peer::new(SocketAddr::from(([10, 0, 0, 2], 6881)))]
+        );
+        assert_eq!(hosts.len(), 1);
+        assert_eq!(hosts[0].host, "localhost");
+        assert_eq!(hosts[0].port, 6882);
+    }
+
+    #[tokio::test]
+    async fn named_peers_are_resolved() {
+        let peer = PeerHost {
+            host: String::from("localhost"),
+            port: 6882,
+            peer_id: Some(b"aaaaaaaaaaaaaaaaaaaa".to_vec()),
+        }
+        .resolve()
+        .await
+        .unwrap();
+        assert!(peer.get_ip().is_loopback());
+        assert_eq!(peer.get_port(), 6882);
+        assert_eq!(peer.get_peer_id(), Some(&b"aaaaaaaaaaaaaaaaaaaa".to_vec()));
+
+        let unknown = PeerHost {
+            host: String::from("host.invalid"),
+            port: 6882,
+            peer_id: None,
+        };
+        assert!(unknown.resolve().await.is_none());
+    }
+
+    #[test]
+    fn scrape_url_replaces_announce() {
+        for (announce_url, expected) in &[
+            ("http://t.example/announce", "http://t.example/scrape"),
+            (
+                "http://t.example/x/announce.php",
+                "http://t.example/x/scrape.php",
+            ),
+            (
+                "https://t.example/announce?passkey=a/b",
+                "https://t.example/scrape?passkey=a/b",
+            ),
+            ("http://t.example/announcer", "http://t.example/scraper"),
+        ] {
+            assert_eq!(scrape_url(announce_url).unwrap(), *expected);
+        }
+
+        for announce_url in &[
+            "http://t.example/tracker",
+            "http://t.example/announce/",
+            "http://t.example/x/announce/y",
+            "http://t.example/tracker?path=/announce",
+            "announce",
+        ] {
+            assert_eq!(
+                scrape_url(announce_url),
+                Err(TrackerError::InvalidUrl(announce_url.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn scrape_files_are_parsed() {
+        let mut response = b"d5:filesd20:".to_vec();
+        response.extend_from_slice(&[1u8; 20]);
+        response
+            .extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10e4:name4:teste20:");
+        response.extend_from_slice(&[2u8; 20]);
+        response.extend_from_slice(b"d8:completei1ee20:");
+        response.extend_from_slice(&[3u8; 20]);
+        response.extend_from_slice(b"i7eee");
+        let (response, _) = bencoding::try_decode(&response).unwrap();
+        let response =
+            parse_scrape_response(response.as_any().downcast_ref::<BDict>().unwrap()).unwrap();
+
+        assert_eq!(response.get_files().len(), 2);
+        let file = response.get_file(&[1u8; 20]).unwrap();
+        assert_eq!(file.get_complete(), 5);
+        assert_eq!(file.get_downloaded(), 50);
+        assert_eq!(file.get_incomplete(), 10);
+        assert_eq!(file.get_name(), Some(&String::from("test")));
+        // missing counts are zero
+        let file = response.get_file(&[2u8; 20]).unwrap();
+        assert_eq!(file.get_complete(), 1);
+        assert_eq!(file.get_downloaded(), 0);
+        assert_eq!(file.get_name(), None);
+
+        let (failure, _) = bencoding::try_decode(b"d14:failure reason6:deniede").unwrap();
+        assert_eq!(
+            parse_scrape_response(failure.as_any().downcast_ref::<BDict>().unwrap()).unwrap_err(),
+            TrackerError::Failure(String::from("denied"))
+        );
     }
-    peer_list
 }
@@ -1,33 +1,298 @@
-mod http;
-mod udp;
+mod backend;
+pub mod http;
+pub mod scheduler;
+pub mod server;
+pub mod udp;
+pub mod websocket;
 
-use crate::utility::PeerId;
+pub use backend::{register, unregister, Tracker, TrackerFuture};
 
-use url::Url;
+use crate::bencoding::{BDict, BInt, BList, BString, BType};
+use crate::torrent::Torrent;
+use crate::utility::{PeerId, PORT};
 
-use std::net::{IpAddr, Ipv4Addr};
+use url::{form_urlencoded, Url};
 
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::OnceLock;
+
+static SESSION_KEY: OnceLock<u32> = OnceLock::new();
+
+/// A peer is identified by its address, the peer id is only known when the
+/// tracker used the dictionary model.
 #[derive(Clone, Debug)]
 pub struct Peer {
-    ip: Ipv4Addr,
-    port: u16,
+    addr: SocketAddr,
+    peer_id: Option<Vec<u8>>,
 }
 
 impl Peer {
+    pub fn new(addr: SocketAddr) -> Peer {
+        Peer {
+            addr,
+            peer_id: None,
+        }
+    }
+
+    pub fn get_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
     pub fn get_ip(&self) -> IpAddr {
-        IpAddr::V4(self.ip)
+        self.addr.ip()
+    }
+
+    pub fn get_port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    pub fn get_peer_id(&self) -> Option<&Vec<u8>> {
+        self.peer_id.as_ref()
+    }
+
+    pub fn set_peer_id(&mut self, peer_id: Vec<u8>) {
+        self.peer_id = Some(peer_id);
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        self.addr.is_ipv6()
+    }
+
+    /// 6 bytes for IPv4 peers and 18 bytes for IPv6 peers.
+    pub fn to_compact(&self) -> Vec<u8> {
+        let mut compact = match self.addr.ip() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        compact.append(&mut self.addr.port().to_be_bytes().to_vec());
+        compact
+    }
+}
+
+impl PartialEq for Peer {
+    fn eq(&self, other: &Peer) -> bool {
+        self.addr == other.addr
+    }
+}
+
+impl Eq for Peer {}
+
+impl Hash for Peer {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.addr.hash(state);
+    }
+}
+
+/// Parses a compact peer list, trailing bytes not making up a whole peer
+/// are ignored.
+pub(crate) fn parse_compact_peers(peers: &[u8], ipv6: bool) -> Vec<Peer> {
+    let ip_length = if ipv6 { 16 } else { 4 };
+    peers
+        .chunks_exact(ip_length + 2)
+        .map(|peer| {
+            let ip = if ipv6 {
+                let ip: [u8; 16] = peer[..16].try_into().unwrap();
+                IpAddr::V6(Ipv6Addr::from(ip))
+            } else {
+                let ip: [u8; 4] = peer[..4].try_into().unwrap();
+                IpAddr::V4(Ipv4Addr::from(ip))
+            };
+            let port = u16::from_be_bytes(peer[ip_length..].try_into().unwrap());
+            Peer::new(SocketAddr::new(ip, port))
+        })
+        .collect()
+}
+
+/// Parameters of an announce shared by the HTTP (BEP 3) and UDP (BEP 15)
+/// trackers.
+#[derive(Clone, Debug)]
+pub struct AnnounceRequest {
+    info_hash: Vec<u8>,
+    peer_id: String,
+    port: u16,
+    uploaded: i64,
+    downloaded: i64,
+    left: i64,
+    event: Option<&'static str>,
+    ip: Option<IpAddr>,
+    numwant: Option<i32>,
+    key: u32,
+    compact: bool,
+    no_peer_id: bool,
+    max_retransmits: Option<u32>,
+}
+
+impl AnnounceRequest {
+    /// A compact request on `utility::PORT` using the session key.
+    pub fn new(info_hash: &[u8], peer_id: &mut PeerId) -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: info_hash.to_vec(),
+            peer_id: peer_id.value(),
+            port: PORT as u16,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            event: None,
+            ip: None,
+            numwant: None,
+            key: session_key(),
+            compact: true,
+            no_peer_id: false,
+            max_retransmits: None,
+        }
+    }
+
+    pub fn get_info_hash(&self) -> &Vec<u8> {
+        &self.info_hash
+    }
+
+    pub fn get_peer_id(&self) -> &String {
+        &self.peer_id
     }
 
     pub fn get_port(&self) -> u16 {
         self.port
     }
+
+    pub fn set_port(&mut self, port: u16) {
+        self.port = port;
+    }
+
+    pub fn get_uploaded(&self) -> i64 {
+        self.uploaded
+    }
+
+    pub fn get_downloaded(&self) -> i64 {
+        self.downloaded
+    }
+
+    pub fn get_left(&self) -> i64 {
+        self.left
+    }
+
+    pub fn set_stats(&mut self, uploaded: i64, downloaded: i64, left: i64) {
+        self.uploaded = uploaded;
+        self.downloaded = downloaded;
+        self.left = left;
+    }
+
+    pub fn get_event(&self) -> Option<&'static str> {
+        self.event
+    }
+
+    /// One of `started`, `completed` and `stopped`, None for a regular
+    /// announce.
+    pub fn set_event(&mut self, event: Option<&'static str>) {
+        self.event = event;
+    }
+
+    pub fn get_ip(&self) -> Option<IpAddr> {
+        self.ip
+    }
+
+    /// Address to report instead of the one the request comes from.
+    pub fn set_ip(&mut self, ip: Option<IpAddr>) {
+        self.ip = ip;
+    }
+
+    pub fn get_numwant(&self) -> Option<i32> {
+        self.numwant
+    }
+
+    /// Number of peers wanted, None leaves it to the tracker.
+    pub fn set_numwant(&mut self, numwant: Option<i32>) {
+        self.numwant = numwant;
+    }
+
+    pub fn get_key(&self) -> u32 {
+        self.key
+    }
+
+    pub fn set_key(&mut self, key: u32) {
+        self.key = key;
+    }
+
+    pub fn is_compact(&self) -> bool {
+        self.compact
+    }
+
+    pub fn set_compact(&mut self, compact: bool) {
+        self.compact = compact;
+    }
+
+    pub fn is_no_peer_id(&self) -> bool {
+        self.no_peer_id
+    }
+
+    /// Asks for the dictionary model without peer ids, ignored for compact
+    /// requests.
+    pub fn set_no_peer_id(&mut self, no_peer_id: bool) {
+        self.no_peer_id = no_peer_id;
+    }
+
+    pub fn get_max_retransmits(&self) -> Option<u32> {
+        self.max_retransmits
+    }
+
+    /// Caps the retransmits of a UDP announce below the client's
+    /// configured maximum, None keeps the maximum.
+    pub fn set_max_retransmits(&mut self, max_retransmits: Option<u32>) {
+        self.max_retransmits = max_retransmits;
+    }
+}
+
+/// Random key sent with every announce of this session so trackers can
+/// recognize us when our IP changes.
+pub fn session_key() -> u32 {
+    *SESSION_KEY.get_or_init(rand::random)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TrackerError {
+    /// The tracker refused the request with a `failure reason`.
+    Failure(String),
+    InvalidUrl(String),
+    Connection(String),
+    InvalidResponse(String),
+    /// Every tracker of every tier failed, with the error of each.
+    AllFailed(Vec<(String, TrackerError)>),
+}
+
+impl fmt::Display for TrackerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrackerError::Failure(reason) => write!(f, "Tracker response failure: {}", reason),
+            TrackerError::InvalidUrl(url) => write!(f, "Invalid announce url: {}", url),
+            TrackerError::Connection(error) => write!(f, "Tracker connection error: {}", error),
+            TrackerError::InvalidResponse(error) => {
+                write!(f, "Invalid tracker response: {}", error)
+            }
+            TrackerError::AllFailed(errors) => {
+                if errors.is_empty() {
+                    return write!(f, "No trackers to announce to");
+                }
+                let errors: Vec<String> = errors
+                    .iter()
+                    .map(|(url, error)| format!("{}: {}", url, error))
+                    .collect();
+                write!(f, "{}", errors.join(", "))
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct TrackerResponse {
     interval: u32,
-    complete: u32,
-    incomplete: u32,
+    min_interval: Option<u32>,
+    complete: Option<u32>,
+    incomplete: Option<u32>,
+    warning: Option<String>,
+    tracker_id: Option<Vec<u8>>,
     peer_list: Vec<Peer>,
 }
 
@@ -35,38 +300,275 @@ impl TrackerResponse {
     pub fn get_peer_list(&self) -> Vec<Peer> {
         self.peer_list.to_vec()
     }
+
+    /// Seconds the tracker wants us to wait between regular announces.
+    pub fn get_interval(&self) -> u32 {
+        self.interval
+    }
+
+    /// Seconds the tracker wants us to wait at least between any announces.
+    pub fn get_min_interval(&self) -> Option<u32> {
+        self.min_interval
+    }
+
+    /// Number of seeders in the swarm, not every tracker reports it.
+    pub fn get_complete(&self) -> Option<u32> {
+        self.complete
+    }
+
+    /// Number of leechers in the swarm, not every tracker reports it.
+    pub fn get_incomplete(&self) -> Option<u32> {
+        self.incomplete
+    }
+
+    /// A `warning message`, the announce still succeeded.
+    pub fn get_warning(&self) -> Option<&String> {
+        self.warning.as_ref()
+    }
+
+    /// Has to be sent back as `trackerid` on the next announces.
+    pub fn get_tracker_id(&self) -> Option<&Vec<u8>> {
+        self.tracker_id.as_ref()
+    }
+
+    /// Bencodes the response as an HTTP tracker sends it, compact responses
+    /// put IPv6 peers in `peers6`.
+    pub fn encode(&self, compact: bool, no_peer_id: bool) -> Vec<u8> {
+        let mut response = BDict::new(BTreeMap::new());
+        let mut insert_int = |key: &str, value: Option<u32>| {
+            if let Some(value) = value {
+                response.insert(bstring(key), Box::new(BInt::new(value as i64)));
+            }
+        };
+        insert_int("interval", Some(self.interval));
+        insert_int("min interval", self.min_interval);
+        insert_int("complete", self.complete);
+        insert_int("incomplete", self.incomplete);
+        if let Some(warning) = &self.warning {
+            response.insert(
+                bstring("warning message"),
+                Box::new(BString::new(&warning.as_bytes().to_vec())),
+            );
+        }
+        if let Some(tracker_id) = &self.tracker_id {
+            response.insert(bstring("tracker id"), Box::new(BString::new(tracker_id)));
+        }
+
+        if compact {
+            let (peers, peers6): (Vec<&Peer>, Vec<&Peer>) =
+                self.peer_list.iter().partition(|peer| !peer.is_ipv6());
+            let peers: Vec<u8> = peers.iter().flat_map(|peer| peer.to_compact()).collect();
+            response.insert(bstring("peers"), Box::new(BString::new(&peers)));
+            if !peers6.is_empty() {
+                let peers6: Vec<u8> = peers6.iter().flat_map(|peer| peer.to_compact()).collect();
+                response.insert(bstring("peers6"), Box::new(BString::new(&peers6)));
+            }
+        } else {
+            let mut peers = BList::new(Vec::new());
+            for peer in &self.peer_list {
+                let mut entry = BDict::new(BTreeMap::new());
+                entry.insert(
+                    bstring("ip"),
+                    Box::new(BString::new(&peer.get_ip().to_string().into_bytes())),
+                );
+                entry.insert(bstring("port"), Box::new(BInt::new(peer.get_port() as i64)));
+                if let (Some(peer_id), false) = (peer.get_peer_id(), no_peer_id) {
+                    entry.insert(bstring("peer id"), Box::new(BString::new(peer_id)));
+                }
+                peers.push(Box::new(entry));
+            }
+            response.insert(bstring("peers"), Box::new(peers));
+        }
+
+        response.encode()
+    }
 }
 
-pub fn announce(
+#[derive(Clone, Debug)]
+pub struct ScrapeFile {
+    complete: u32,
+    downloaded: u32,
+    incomplete: u32,
+    name: Option<String>,
+}
+
+impl ScrapeFile {
+    /// Number of seeders.
+    pub fn get_complete(&self) -> u32 {
+        self.complete
+    }
+
+    /// Number of times the torrent was fully downloaded.
+    pub fn get_downloaded(&self) -> u32 {
+        self.downloaded
+    }
+
+    /// Number of leechers.
+    pub fn get_incomplete(&self) -> u32 {
+        self.incomplete
+    }
+
+    pub fn get_name(&self) -> Option<&String> {
+        self.name.as_ref()
+    }
+}
+
+#[derive(Debug)]
+pub struct ScrapeResponse {
+    files: HashMap<Vec<u8>, ScrapeFile>,
+}
+
+impl ScrapeResponse {
+    pub fn get_file(&self, info_hash: &[u8]) -> Option<&ScrapeFile> {
+        self.files.get(info_hash)
+    }
+
+    pub fn get_files(&self) -> &HashMap<Vec<u8>, ScrapeFile> {
+        &self.files
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut files = BDict::new(BTreeMap::new());
+        for (info_hash, file) in &self.files {
+            let mut stats = BDict::new(BTreeMap::new());
+            stats.insert(
+                bstring("complete"),
+                Box::new(BInt::new(file.complete as i64)),
+            );
+            stats.insert(
+                bstring("downloaded"),
+                Box::new(BInt::new(file.downloaded as i64)),
+            );
+            stats.insert(
+                bstring("incomplete"),
+                Box::new(BInt::new(file.incomplete as i64)),
+            );
+            if let Some(name) = &file.name {
+                stats.insert(
+                    bstring("name"),
+                    Box::new(BString::new(&name.as_bytes().to_vec())),
+                );
+            }
+            files.insert(BString::new(info_hash), Box::new(stats));
+        }
+
+        let mut response = BDict::new(BTreeMap::new());
+        response.insert(bstring("files"), Box::new(files));
+        response.encode()
+    }
+}
+
+fn bstring(value: &str) -> BString {
+    BString::new(&value.as_bytes().to_vec())
+}
+
+/// Announces to a single tracker through the backend registered for the
+/// scheme of its url.
+pub async fn announce(
     announce_url: String,
-    info_hash: &Vec<u8>,
-    peer_id: &mut PeerId,
-    uploaded: i64,
-    downloaded: i64,
-    left: i64,
-    event: Option<&str>,
-) -> TrackerResponse {
-    let announce_url = Url::parse(&announce_url).unwrap();
-
-    match announce_url.scheme() {
-        "http" | "https" => http::announce(
-            announce_url.as_str(),
-            info_hash,
-            peer_id,
-            uploaded,
-            downloaded,
-            left,
-            event,
-        ),
-        "udp" => udp::announce(
-            announce_url,
-            info_hash,
-            peer_id,
-            uploaded,
-            downloaded,
-            left,
-            event,
-        ),
-        _ => panic!("Invalid announce url"),
+    request: &AnnounceRequest,
+) -> Result<TrackerResponse, TrackerError> {
+    let announce_url =
+        Url::parse(&announce_url).map_err(|_| TrackerError::InvalidUrl(announce_url.clone()))?;
+    backend::get(&announce_url)?
+        .announce(&announce_url, request)
+        .await
+}
+
+/// The BEP 12 tiers of a torrent together with the tracker ids the trackers
+/// handed out.
+#[derive(Clone, Debug)]
+pub struct TrackerList {
+    tiers: Vec<Vec<String>>,
+    tracker_ids: HashMap<String, Vec<u8>>,
+}
+
+impl TrackerList {
+    pub fn new(tiers: Vec<Vec<String>>) -> TrackerList {
+        TrackerList {
+            tiers,
+            tracker_ids: HashMap::new(),
+        }
+    }
+
+    pub fn get_tiers(&self) -> &Vec<Vec<String>> {
+        &self.tiers
+    }
+
+    /// Writes the tiers back as the torrent's `announce-list`, so the
+    /// trackers that answered keep their place at the front of their tier.
+    pub fn save_tiers(&self, torrent: &mut Torrent) {
+        torrent.get_announce_list_mut().clone_from(&self.tiers);
     }
+
+    /// Announces following BEP 12: tiers are tried in order and trackers
+    /// within a tier in order, the first tracker that answers is moved to
+    /// the front of its tier so it is tried first next time, see
+    /// `save_tiers` to keep that order in the torrent. With more than
+    /// one tracker UDP announces give up after `udp::LIST_MAX_RETRANSMITS`
+    /// so a dead tracker does not hold up the others for hours.
+    pub async fn announce(
+        &mut self,
+        request: &AnnounceRequest,
+    ) -> Result<TrackerResponse, TrackerError> {
+        let mut request = request.clone();
+        if self.tiers.iter().map(|tier| tier.len()).sum::<usize>() > 1 {
+            let max_retransmits = match request.get_max_retransmits() {
+                Some(max_retransmits) => max_retransmits.min(udp::LIST_MAX_RETRANSMITS),
+                None => udp::LIST_MAX_RETRANSMITS,
+            };
+            request.set_max_retransmits(Some(max_retransmits));
+        }
+
+        let mut errors = Vec::new();
+        for tier in self.tiers.iter_mut() {
+            for index in 0..tier.len() {
+                let announce_url = tier[index].clone();
+                let request_url = match self.tracker_ids.get(&announce_url) {
+                    Some(tracker_id) => with_tracker_id(&announce_url, tracker_id),
+                    None => announce_url.clone(),
+                };
+
+                match announce(request_url, &request).await {
+                    Ok(response) => {
+                        if let Some(warning) = response.get_warning() {
+                            eprintln!("Warning: tracker {}: {}", announce_url, warning);
+                        }
+                        if let Some(tracker_id) = response.get_tracker_id() {
+                            self.tracker_ids
+                                .insert(announce_url.clone(), tracker_id.clone());
+                        }
+                        let tracker = tier.remove(index);
+                        tier.insert(0, tracker);
+                        return Ok(response);
+                    }
+                    Err(error) => errors.push((announce_url, error)),
+                }
+            }
+        }
+
+        Err(TrackerError::AllFailed(errors))
+    }
+}
+
+fn with_tracker_id(announce_url: &str, tracker_id: &[u8]) -> String {
+    if !announce_url.starts_with("http") {
+        return announce_url.to_string();
+    }
+    let separator = if announce_url.contains('?') { '&' } else { '?' };
+    let tracker_id: String = form_urlencoded::byte_serialize(tracker_id).collect();
+    format!("{}{}trackerid={}", announce_url, separator, tracker_id)
+}
+
+/// Asks the tracker for swarm statistics of several torrents at once
+/// without joining their swarms.
+pub async fn scrape(
+    announce_url: String,
+    info_hashes: &[Vec<u8>],
+) -> Result<ScrapeResponse, TrackerError> {
+    let announce_url =
+        Url::parse(&announce_url).map_err(|_| TrackerError::InvalidUrl(announce_url.clone()))?;
+    backend::get(&announce_url)?
+        .scrape(&announce_url, info_hashes)
+        .await
 }
@@ -0,0 +1,391 @@
+use super::{AnnounceRequest, Peer, TrackerError, TrackerList, TrackerResponse};
+use crate::torrent::Torrent;
+
+use std::time::{Duration, Instant};
+
+/// Used until a tracker tells us its interval.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+const RETRY_BASE: Duration = Duration::from_secs(15);
+const MAX_RETRY: Duration = Duration::from_secs(30 * 60);
+
+/// Keeps track of when a torrent has to announce next and with which event.
+///
+/// The owner calls `tick` from its torrent loop with the current transfer
+/// stats, `completed` once the download finishes and `stop` on shutdown.
+#[derive(Debug)]
+pub struct AnnounceScheduler {
+    trackers: TrackerList,
+    request: AnnounceRequest,
+    next_announce: Instant,
+    event: Option<&'static str>,
+    started: bool,
+    interval: Duration,
+    min_interval: Option<Duration>,
+    failures: u32,
+    last_announce: Option<Instant>,
+    last_error: Option<TrackerError>,
+    last_warning: Option<String>,
+    seeders: Option<u32>,
+    leechers: Option<u32>,
+}
+
+impl AnnounceScheduler {
+    /// `request` carries the parameters of every announce, the scheduler
+    /// fills in the transfer stats and the event.
+    pub fn new(tiers: Vec<Vec<String>>, request: AnnounceRequest) -> AnnounceScheduler {
+        AnnounceScheduler {
+            trackers: TrackerList::new(tiers),
+            request,
+            next_announce: Instant::now(),
+            event: Some("started"),
+            started: false,
+            interval: DEFAULT_INTERVAL,
+            min_interval: None,
+            failures: 0,
+            last_announce: None,
+            last_error: None,
+            last_warning: None,
+            seeders: None,
+            leechers: None,
+        }
+    }
+
+    pub fn is_due(&self) -> bool {
+        Instant::now() >= self.next_announce
+    }
+
+    pub fn time_until_next(&self) -> Duration {
+        self.next_announce.saturating_duration_since(Instant::now())
+    }
+
+    /// Announces if it is time to, returns the peers received.
+    pub async fn tick(
+        &mut self,
+        uploaded: i64,
+        downloaded: i64,
+        left: i64,
+    ) -> Option<Result<Vec<Peer>, TrackerError>> {
+        if !self.is_due() {
+            return None;
+        }
+        Some(self.announce(uploaded, downloaded, left).await)
+    }
+
+    /// Queues a `completed` announce, sent on the next tick regardless of
+    /// the tracker interval.
+    pub fn completed(&mut self) {
+        if self.event == Some("started") {
+            // never got through to a tracker, it will learn from `left`
+            return;
+        }
+        self.event = Some("completed");
+        self.next_announce = Instant::now();
+    }
+
+    /// Asks for an early regular announce, honoring `min interval`.
+    pub fn force_reannounce(&mut self) {
+        let earliest = match (self.last_announce, self.min_interval) {
+            (Some(last_announce), Some(min_interval)) => last_announce + min_interval,
+            _ => Instant::now(),
+        };
+        self.next_announce = self.next_announce.min(earliest.max(Instant::now()));
+    }
+
+    /// Sends `stopped` if the tracker knows about us, meant for shutdown.
+    pub async fn stop(
+        &mut self,
+        uploaded: i64,
+        downloaded: i64,
+        left: i64,
+    ) -> Result<(), TrackerError> {
+        if !self.started {
+            return Ok(());
+        }
+        self.event = Some("stopped");
+        self.announce(uploaded, downloaded, left).await?;
+        self.started = false;
+        Ok(())
+    }
+
+    pub fn get_last_announce(&self) -> Option<Instant> {
+        self.last_announce
+    }
+
+    /// Error of the last announce, None if it succeeded.
+    pub fn get_last_error(&self) -> Option<&TrackerError> {
+        self.last_error.as_ref()
+    }
+
+    /// Warning message of the last successful announce.
+    pub fn get_last_warning(&self) -> Option<&String> {
+        self.last_warning.as_ref()
+    }
+
+    pub fn get_seeders(&self) -> Option<u32> {
+        self.seeders
+    }
+
+    pub fn get_leechers(&self) -> Option<u32> {
+        self.leechers
+    }
+
+    pub fn get_request_mut(&mut self) -> &mut AnnounceRequest {
+        &mut self.request
+    }
+
+    pub fn get_tiers(&self) -> &Vec<Vec<String>> {
+        self.trackers.get_tiers()
+    }
+
+    /// Persists the BEP 12 tier order into the torrent, meant for after
+    /// announces so the reordering survives the scheduler.
+    pub fn save_tiers(&self, torrent: &mut Torrent) {
+        self.trackers.save_tiers(torrent);
+    }
+
+    async fn announce(
+        &mut self,
+        uploaded: i64,
+        downloaded: i64,
+        left: i64,
+    ) -> Result<Vec<Peer>, TrackerError> {
+        let event = self.event;
+        self.request.set_stats(uploaded, downloaded, left);
+        self.request.set_event(event);
+        let response = self.trackers.announce(&self.request).await;
+        self.last_announce = Some(Instant::now());
+
+        match response {
+            Ok(response) => {
+                self.on_success(&response, event);
+                Ok(response.get_peer_list())
+            }
+            Err(error) => {
+                self.on_failure(&error);
+                Err(error)
+            }
+        }
+    }
+
+    fn on_success(&mut self, response: &TrackerResponse, event: Option<&'static str>) {
+        if event == Some("started") {
+            self.started = true;
+        }
+        self.event = None;
+        self.failures = 0;
+        self.last_error = None;
+        self.last_warning = response.get_warning().cloned();
+        self.seeders = response.get_complete();
+        self.leechers = response.get_incomplete();
+
+        if response.get_interval() > 0 {
+            self.interval = Duration::from_secs(response.get_interval() as u64);
+        }
+        self.min_interval = response
+            .get_min_interval()
+            .map(|min_interval| Duration::from_secs(min_interval as u64));
+        let interval = match self.min_interval {
+            Some(min_interval) => self.interval.max(min_interval),
+            None => self.interval,
+        };
+        self.next_announce = Instant::now() + interval;
+    }
+
+    /// Retries after 15s, 30s, 60s, ... up to 30 minutes, the pending event
+    /// is kept so it is delivered once a tracker answers.
+    fn on_failure(&mut self, error: &TrackerError) {
+        let backoff = RETRY_BASE * 2u32.pow(self.failures.min(7));
+        self.failures += 1;
+        self.last_error = Some(error.clone());
+        self.next_announce = Instant::now() + backoff.min(MAX_RETRY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencoding::{self, BDict};
+    use crate::torrent::parse_torrent_data;
+    use crate::tracker::{register, unregister, ScrapeResponse, Tracker, TrackerFuture};
+    use crate::utility::PeerId;
+
+    use url::Url;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    /// Answers every announce with the given intervals and records the
+    /// events, or fails while `failing` is set.
+    struct FakeTracker {
+        interval: u32,
+        min_interval: Option<u32>,
+        failing: AtomicBool,
+        events: Mutex<Vec<Option<&'static str>>>,
+    }
+
+    impl Tracker for FakeTracker {
+        fn announce<'a>(
+            &'a self,
+            _announce_url: &'a Url,
+            request: &'a AnnounceRequest,
+        ) -> TrackerFuture<'a, TrackerResponse> {
+            Box::pin(async move {
+                self.events.lock().unwrap().push(request.get_event());
+                if self.failing.load(Ordering::SeqCst) {
+                    return Err(TrackerError::Connection(String::from("down")));
+                }
+                Ok(TrackerResponse {
+                    interval: self.interval,
+                    min_interval: self.min_interval,
+                    complete: Some(3),
+                    incomplete: Some(4),
+                    warning: None,
+                    tracker_id: None,
+                    peer_list: Vec::new(),
+                })
+            })
+        }
+
+        fn scrape<'a>(
+            &'a self,
+            announce_url: &'a Url,
+            _info_hashes: &'a [Vec<u8>],
+        ) -> TrackerFuture<'a, ScrapeResponse> {
+            Box::pin(async move { Err(TrackerError::InvalidUrl(announce_url.to_string())) })
+        }
+    }
+
+    /// A scheduler announcing to a fake tracker registered for `scheme`,
+    /// every test uses its own scheme as the registry is shared.
+    fn fake_scheduler(
+        scheme: &str,
+        interval: u32,
+        min_interval: Option<u32>,
+    ) -> (AnnounceScheduler, Arc<FakeTracker>) {
+        let tracker = Arc::new(FakeTracker {
+            interval,
+            min_interval,
+            failing: AtomicBool::new(false),
+            events: Mutex::new(Vec::new()),
+        });
+        register(scheme, tracker.clone());
+        let scheduler = AnnounceScheduler::new(
+            vec![vec![format!("{}://tracker/announce", scheme)]],
+            AnnounceRequest::new(&[1u8; 20], &mut PeerId::new()),
+        );
+        (scheduler, tracker)
+    }
+
+    fn assert_about(actual: Duration, expected: Duration) {
+        assert!(
+            actual <= expected && actual + Duration::from_secs(1) > expected,
+            "{:?} is not about {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[tokio::test]
+    async fn announces_follow_the_interval() {
+        let (mut scheduler, _) = fake_scheduler("fake-interval", 600, Some(900));
+        assert!(scheduler.is_due());
+        assert!(scheduler.tick(0, 0, 10).await.unwrap().is_ok());
+        assert_eq!(scheduler.get_seeders(), Some(3));
+        assert_eq!(scheduler.get_leechers(), Some(4));
+        // min interval wins when it is longer
+        assert_about(scheduler.time_until_next(), Duration::from_secs(900));
+        assert!(scheduler.tick(0, 0, 10).await.is_none());
+
+        let (mut scheduler, _) = fake_scheduler("fake-min-interval", 1200, Some(60));
+        scheduler.tick(0, 0, 10).await.unwrap().unwrap();
+        assert_about(scheduler.time_until_next(), Duration::from_secs(1200));
+        unregister("fake-interval");
+        unregister("fake-min-interval");
+    }
+
+    #[tokio::test]
+    async fn failures_back_off() {
+        let (mut scheduler, tracker) = fake_scheduler("fake-backoff", 600, None);
+        tracker.failing.store(true, Ordering::SeqCst);
+        for backoff in &[15, 30, 60, 120] {
+            assert!(scheduler.tick(0, 0, 10).await.unwrap().is_err());
+            assert!(scheduler.get_last_error().is_some());
+            assert_about(scheduler.time_until_next(), Duration::from_secs(*backoff));
+            scheduler.force_reannounce();
+        }
+
+        tracker.failing.store(false, Ordering::SeqCst);
+        assert!(scheduler.tick(0, 0, 10).await.unwrap().is_ok());
+        assert!(scheduler.get_last_error().is_none());
+        assert_about(scheduler.time_until_next(), Duration::from_secs(600));
+        // the started event is kept until a tracker answers
+        assert_eq!(*tracker.events.lock().unwrap(), vec![Some("started"); 5]);
+        unregister("fake-backoff");
+    }
+
+    #[tokio::test]
+    async fn forced_reannounces_honor_min_interval() {
+        let (mut scheduler, _) = fake_scheduler("fake-force-min", 1800, Some(600));
+        scheduler.tick(0, 0, 10).await.unwrap().unwrap();
+        scheduler.force_reannounce();
+        assert_about(scheduler.time_until_next(), Duration::from_secs(600));
+
+        let (mut scheduler, _) = fake_scheduler("fake-force", 1800, None);
+        scheduler.tick(0, 0, 10).await.unwrap().unwrap();
+        assert!(!scheduler.is_due());
+        scheduler.force_reannounce();
+        assert!(scheduler.is_due());
+        unregister("fake-force-min");
+        unregister("fake-force");
+    }
+
+    #[tokio::test]
+    async fn events_are_sent_in_order() {
+        let (mut scheduler, tracker) = fake_scheduler("fake-events", 1800, None);
+        // nothing to complete or stop before the tracker knows about us
+        scheduler.completed();
+        scheduler.stop(0, 0, 10).await.unwrap();
+        assert!(tracker.events.lock().unwrap().is_empty());
+
+        scheduler.tick(0, 0, 10).await.unwrap().unwrap();
+        scheduler.completed();
+        assert!(scheduler.is_due());
+        scheduler.tick(0, 10, 0).await.unwrap().unwrap();
+        scheduler.force_reannounce();
+        scheduler.tick(0, 10, 0).await.unwrap().unwrap();
+        scheduler.stop(0, 10, 0).await.unwrap();
+        // stopped is only sent once
+        scheduler.stop(0, 10, 0).await.unwrap();
+
+        assert_eq!(
+            *tracker.events.lock().unwrap(),
+            vec![Some("started"), Some("completed"), None, Some("stopped")]
+        );
+        unregister("fake-events");
+    }
+
+    #[tokio::test]
+    async fn answering_trackers_are_saved_first_in_their_tier() {
+        fake_scheduler("fake-tiers", 1800, None);
+        let metainfo = b"d8:announce16:fake-dead://dead13:announce-listll16:fake-dead://dead\
+            29:fake-tiers://tracker/announceee4:infod6:lengthi1e4:name4:file\
+            12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let (metainfo, _) = bencoding::try_decode(metainfo).unwrap();
+        let mut torrent = parse_torrent_data(metainfo.as_any().downcast_ref::<BDict>().unwrap());
+        let mut scheduler = AnnounceScheduler::new(
+            torrent.get_announce_list().clone(),
+            AnnounceRequest::new(&[1u8; 20], &mut PeerId::new()),
+        );
+
+        scheduler.tick(0, 0, 10).await.unwrap().unwrap();
+        scheduler.save_tiers(&mut torrent);
+        assert_eq!(
+            *torrent.get_announce_list(),
+            vec![vec![
+                String::from("fake-tiers://tracker/announce"),
+                String::from("fake-dead://dead"),
+            ]]
+        );
+        unregister("fake-tiers");
+    }
+}
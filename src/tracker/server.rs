@@ -0,0 +1,833 @@
+use super::{AnnounceRequest, Peer, ScrapeFile, ScrapeResponse, TrackerError, TrackerResponse};
+
+use crate::bencoding::{BDict, BString, BType};
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::str;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+const MAX_REQUEST_SIZE: usize = 8192;
+/// Clients may use a connection id for a minute, accept it a bit longer.
+const CONNECTION_ID_TTL: Duration = Duration::from_secs(2 * 60);
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+#[derive(Clone, Debug)]
+pub struct TrackerServerConfig {
+    /// Serves `/announce` and `/scrape` over HTTP, None to disable.
+    pub http_addr: Option<SocketAddr>,
+    /// Serves BEP 15 over UDP, None to disable.
+    pub udp_addr: Option<SocketAddr>,
+    pub interval: u32,
+    pub min_interval: u32,
+    /// Peers that did not announce for this long are dropped.
+    pub peer_timeout: Duration,
+    /// Most peers returned, whatever `numwant` asks for.
+    pub max_peers: usize,
+    /// Only these torrents are tracked when set.
+    pub allowlist: Option<HashSet<Vec<u8>>>,
+    /// Registers peers at the `ip` they report instead of the address
+    /// their request comes from. Anyone could then point the swarm at a
+    /// third party, only enable it behind a proxy or on a trusted network.
+    pub trust_ip: bool,
+    /// Most HTTP requests handled at once, connections beyond it are
+    /// turned away.
+    pub max_connections: usize,
+}
+
+impl Default for TrackerServerConfig {
+    fn default() -> TrackerServerConfig {
+        TrackerServerConfig {
+            http_addr: Some(SocketAddr::from(([0, 0, 0, 0], 6969))),
+            udp_addr: Some(SocketAddr::from(([0, 0, 0, 0], 6969))),
+            interval: 30 * 60,
+            min_interval: 60,
+            peer_timeout: Duration::from_secs(2 * 30 * 60 + 60),
+            max_peers: 50,
+            allowlist: None,
+            trust_ip: false,
+            max_connections: 64,
+        }
+    }
+}
+
+struct SwarmPeer {
+    peer_id: Vec<u8>,
+    left: i64,
+    last_seen: Instant,
+}
+
+#[derive(Default)]
+struct Swarm {
+    peers: HashMap<SocketAddr, SwarmPeer>,
+    downloaded: u32,
+}
+
+impl Swarm {
+    fn stats(&self) -> ScrapeFile {
+        let complete = self.peers.values().filter(|peer| peer.left == 0).count() as u32;
+        ScrapeFile {
+            complete,
+            downloaded: self.downloaded,
+            incomplete: self.peers.len() as u32 - complete,
+            name: None,
+        }
+    }
+}
+
+/// Minimal tracker keeping every swarm in memory, meant for private
+/// distribution and for testing the tracker clients.
+pub struct TrackerServer {
+    inner: Arc<Inner>,
+    http_addr: Option<SocketAddr>,
+    udp_addr: Option<SocketAddr>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+struct Inner {
+    config: TrackerServerConfig,
+    allowlist: Mutex<Option<HashSet<Vec<u8>>>>,
+    swarms: Mutex<HashMap<Vec<u8>, Swarm>>,
+    connections: Mutex<HashMap<u64, (SocketAddr, Instant)>>,
+    http_connections: AtomicUsize,
+    running: AtomicBool,
+}
+
+impl TrackerServer {
+    pub fn start(config: TrackerServerConfig) -> io::Result<TrackerServer> {
+        let listener = match config.http_addr {
+            Some(http_addr) => {
+                let listener = TcpListener::bind(http_addr)?;
+                listener.set_nonblocking(true)?;
+                Some(listener)
+            }
+            None => None,
+        };
+        let socket = match config.udp_addr {
+            Some(udp_addr) => {
+                let socket = UdpSocket::bind(udp_addr)?;
+                socket.set_read_timeout(Some(POLL_INTERVAL))?;
+                Some(socket)
+            }
+            None => None,
+        };
+        let http_addr = listener
+            .as_ref()
+            .map(|listener| listener.local_addr())
+            .transpose()?;
+        let udp_addr = socket
+            .as_ref()
+            .map(|socket| socket.local_addr())
+            .transpose()?;
+
+        let inner = Arc::new(Inner {
+            allowlist: Mutex::new(config.allowlist.clone()),
+            config,
+            swarms: Mutex::new(HashMap::new()),
+            connections: Mutex::new(HashMap::new()),
+            http_connections: AtomicUsize::new(0),
+            running: AtomicBool::new(true),
+        });
+
+        let mut handles = Vec::new();
+        if let Some(listener) = listener {
+            let server = Arc::clone(&inner);
+            handles.push(thread::spawn(move || server.accept_loop(listener)));
+        }
+        if let Some(socket) = socket {
+            let server = Arc::clone(&inner);
+            handles.push(thread::spawn(move || server.udp_loop(socket)));
+        }
+
+        Ok(TrackerServer {
+            inner,
+            http_addr,
+            udp_addr,
+            handles,
+        })
+    }
+
+    /// Address the HTTP tracker listens on, the announce url being
+    /// `http://<addr>/announce`.
+    pub fn http_addr(&self) -> Option<SocketAddr> {
+        self.http_addr
+    }
+
+    /// Address the UDP tracker listens on, the announce url being
+    /// `udp://<addr>`.
+    pub fn udp_addr(&self) -> Option<SocketAddr> {
+        self.udp_addr
+    }
+
+    /// Adds a torrent to the allowlist, enabling it if there was none.
+    pub fn allow(&self, info_hash: &[u8]) {
+        self.inner
+            .allowlist
+            .lock()
+            .unwrap()
+            .get_or_insert_with(HashSet::new)
+            .insert(info_hash.to_vec());
+    }
+
+    pub fn disallow(&self, info_hash: &[u8]) {
+        if let Some(allowlist) = self.inner.allowlist.lock().unwrap().as_mut() {
+            allowlist.remove(info_hash);
+        }
+        self.inner.swarms.lock().unwrap().remove(info_hash);
+    }
+
+    /// Current statistics of a swarm, None if nobody announced it.
+    pub fn get_stats(&self, info_hash: &[u8]) -> Option<ScrapeFile> {
+        self.inner
+            .swarms
+            .lock()
+            .unwrap()
+            .get(info_hash)
+            .map(|swarm| swarm.stats())
+    }
+}
+
+impl Drop for TrackerServer {
+    fn drop(&mut self) {
+        self.inner.running.store(false, Ordering::SeqCst);
+        for handle in self.handles.drain(..) {
+            handle.join().unwrap();
+        }
+    }
+}
+
+impl Inner {
+    fn is_allowed(&self, info_hash: &[u8]) -> bool {
+        match &*self.allowlist.lock().unwrap() {
+            Some(allowlist) => allowlist.contains(info_hash),
+            None => true,
+        }
+    }
+
+    /// Updates the swarm with the announcing peer and picks the peers
+    /// returned to it, of the same address family as `addr`.
+    fn announce(
+        &self,
+        request: &AnnounceRequest,
+        addr: SocketAddr,
+    ) -> Result<TrackerResponse, TrackerError> {
+        if request.info_hash.len() != 20 {
+            return Err(TrackerError::Failure(String::from("invalid info_hash")));
+        }
+        if !self.is_allowed(&request.info_hash) {
+            return Err(TrackerError::Failure(String::from(
+                "torrent not allowed on this tracker",
+            )));
+        }
+        if request.port == 0 {
+            return Err(TrackerError::Failure(String::from("invalid port")));
+        }
+
+        let ip = match request.ip {
+            Some(ip) if self.config.trust_ip => ip,
+            _ => addr.ip(),
+        };
+        let peer_addr = SocketAddr::new(ip, request.port);
+        let mut swarms = self.swarms.lock().unwrap();
+        let swarm = swarms.entry(request.info_hash.clone()).or_default();
+        let peer_timeout = self.config.peer_timeout;
+        swarm
+            .peers
+            .retain(|_, peer| peer.last_seen.elapsed() < peer_timeout);
+
+        if request.event == Some("stopped") {
+            swarm.peers.remove(&peer_addr);
+        } else {
+            if request.event == Some("completed") {
+                swarm.downloaded += 1;
+            }
+            swarm.peers.insert(
+                peer_addr,
+                SwarmPeer {
+                    peer_id: request.peer_id.as_bytes().to_vec(),
+                    left: request.left,
+                    last_seen: Instant::now(),
+                },
+            );
+        }
+
+        let numwant = match request.numwant {
+            Some(numwant) if numwant >= 0 => (numwant as usize).min(self.config.max_peers),
+            _ => self.config.max_peers,
+        };
+        let candidates: Vec<Peer> = swarm
+            .peers
+            .iter()
+            .filter(|(candidate, _)| {
+                **candidate != peer_addr && candidate.is_ipv6() == peer_addr.is_ipv6()
+            })
+            // seeders have no use for other seeders
+            .filter(|(_, peer)| request.left != 0 || peer.left != 0)
+            .map(|(candidate, peer)| {
+                let mut candidate = Peer::new(*candidate);
+                candidate.set_peer_id(peer.peer_id.clone());
+                candidate
+            })
+            .collect();
+        let peer_list = candidates
+            .choose_multiple(&mut rand::thread_rng(), numwant)
+            .cloned()
+            .collect();
+
+        let stats = swarm.stats();
+        Ok(TrackerResponse {
+            interval: self.config.interval,
+            min_interval: Some(self.config.min_interval),
+            complete: Some(stats.complete),
+            incomplete: Some(stats.incomplete),
+            warning: None,
+            tracker_id: None,
+            peer_list,
+        })
+    }
+
+    /// Statistics of the requested torrents, of every torrent when none is
+    /// given.
+    fn scrape(&self, info_hashes: &[Vec<u8>]) -> ScrapeResponse {
+        let swarms = self.swarms.lock().unwrap();
+        let mut files = HashMap::new();
+        if info_hashes.is_empty() {
+            for (info_hash, swarm) in swarms.iter() {
+                files.insert(info_hash.clone(), swarm.stats());
+            }
+        }
+        for info_hash in info_hashes {
+            let stats = match swarms.get(info_hash) {
+                Some(swarm) => swarm.stats(),
+                None => Swarm::default().stats(),
+            };
+            files.insert(info_hash.clone(), stats);
+        }
+        ScrapeResponse { files }
+    }
+
+    fn accept_loop(self: Arc<Self>, listener: TcpListener) {
+        while self.running.load(Ordering::SeqCst) {
+            match listener.accept() {
+                Ok((mut stream, addr)) => {
+                    if self.http_connections.fetch_add(1, Ordering::SeqCst)
+                        >= self.config.max_connections
+                    {
+                        self.http_connections.fetch_sub(1, Ordering::SeqCst);
+                        let _ = stream
+                            .set_nonblocking(false)
+                            .and_then(|_| write_http(&mut stream, "503 Service Unavailable", b""));
+                        continue;
+                    }
+                    let server = Arc::clone(&self);
+                    thread::spawn(move || {
+                        if let Err(error) = server.handle_http(stream, addr) {
+                            eprintln!("Error: tracker request from {} failed: {}", addr, error);
+                        }
+                        server.http_connections.fetch_sub(1, Ordering::SeqCst);
+                    });
+                }
+                Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(POLL_INTERVAL / 10)
+                }
+                Err(error) => eprintln!("Error: tracker accept failed: {}", error),
+            }
+        }
+    }
+
+    fn handle_http(&self, mut stream: TcpStream, addr: SocketAddr) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+
+        let mut request = Vec::new();
+        let mut buffer = [0u8; 1024];
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+            let length = stream.read(&mut buffer)?;
+            if length == 0 || request.len() + length > MAX_REQUEST_SIZE {
+                return write_http(&mut stream, "400 Bad Request", b"");
+            }
+            request.extend_from_slice(&buffer[..length]);
+        }
+
+        let line = request
+            .split(|&byte| byte == b'\r')
+            .next()
+            .unwrap_or_default();
+        let target = match str::from_utf8(line).map(|line| line.split(' ').collect::<Vec<_>>()) {
+            Ok(parts) if parts.len() == 3 && parts[0] == "GET" => parts[1].to_string(),
+            _ => return write_http(&mut stream, "400 Bad Request", b""),
+        };
+        let (path, query) = match target.find('?') {
+            Some(position) => (&target[..position], &target[position + 1..]),
+            None => (&target[..], ""),
+        };
+        let params = parse_query(query);
+
+        let body = match path {
+            "/announce" => match parse_http_announce(&params) {
+                Ok(request) => match self.announce(&request, addr) {
+                    Ok(response) => response.encode(request.compact, request.no_peer_id),
+                    Err(error) => failure_response(&error),
+                },
+                Err(error) => failure_response(&error),
+            },
+            "/scrape" => {
+                let info_hashes: Vec<Vec<u8>> = params
+                    .iter()
+                    .filter(|(key, _)| key == "info_hash")
+                    .map(|(_, value)| value.clone())
+                    .filter(|info_hash| self.is_allowed(info_hash))
+                    .collect();
+                self.scrape(&info_hashes).encode()
+            }
+            _ => return write_http(&mut stream, "404 Not Found", b""),
+        };
+        write_http(&mut stream, "200 OK", &body)
+    }
+
+    fn udp_loop(&self, socket: UdpSocket) {
+        let mut buffer = [0u8; 2048];
+        while self.running.load(Ordering::SeqCst) {
+            let (length, addr) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(_) => continue,
+            };
+            if let Some(response) = self.handle_udp(&buffer[..length], addr) {
+                if let Err(error) = socket.send_to(&response, addr) {
+                    eprintln!("Error: tracker send to {} failed: {}", addr, error);
+                }
+            }
+        }
+    }
+
+    fn handle_udp(&self, packet: &[u8], addr: SocketAddr) -> Option<Vec<u8>> {
+        if packet.len() < 16 {
+            return None;
+        }
+        let connection_id = u64::from_be_bytes(packet[0..8].try_into().unwrap());
+        let action = u32::from_be_bytes(packet[8..12].try_into().unwrap());
+        let transaction_id = u32::from_be_bytes(packet[12..16].try_into().unwrap());
+
+        if action == 0 {
+            if connection_id != PROTOCOL_ID {
+                return None;
+            }
+            let connection_id = self.new_connection(addr);
+            let mut response = udp_header(0, transaction_id);
+            response.extend_from_slice(&connection_id.to_be_bytes());
+            return Some(response);
+        }
+        if !self.is_connected(connection_id, addr) {
+            return Some(udp_error(transaction_id, "invalid connection id"));
+        }
+
+        match action {
+            1 => {
+                let request = match parse_udp_announce(packet) {
+                    Some(request) => request,
+                    None => return Some(udp_error(transaction_id, "invalid announce")),
+                };
+                let response = match self.announce(&request, addr) {
+                    Ok(response) => response,
+                    Err(error) => return Some(udp_error(transaction_id, &failure_reason(&error))),
+                };
+                let mut packet = udp_header(1, transaction_id);
+                packet.extend_from_slice(&response.interval.to_be_bytes());
+                packet.extend_from_slice(&response.incomplete.unwrap_or(0).to_be_bytes());
+                packet.extend_from_slice(&response.complete.unwrap_or(0).to_be_bytes());
+                for peer in &response.peer_list {
+                    packet.append(&mut peer.to_compact());
+                }
+                Some(packet)
+            }
+            2 => {
+                let info_hashes: Vec<Vec<u8>> = packet[16..]
+                    .chunks_exact(20)
+                    .map(|info_hash| info_hash.to_vec())
+                    .collect();
+                let response = self.scrape(&info_hashes);
+                let mut packet = udp_header(2, transaction_id);
+                for info_hash in &info_hashes {
+                    let file = match (self.is_allowed(info_hash), response.files.get(info_hash)) {
+                        (true, Some(file)) => file.clone(),
+                        _ => Swarm::default().stats(),
+                    };
+                    packet.extend_from_slice(&file.complete.to_be_bytes());
+                    packet.extend_from_slice(&file.downloaded.to_be_bytes());
+                    packet.extend_from_slice(&file.incomplete.to_be_bytes());
+                }
+                Some(packet)
+            }
+            _ => Some(udp_error(transaction_id, "unknown action")),
+        }
+    }
+
+    fn new_connection(&self, addr: SocketAddr) -> u64 {
+        let mut connections = self.connections.lock().unwrap();
+        connections.retain(|_, (_, issued)| issued.elapsed() < CONNECTION_ID_TTL);
+        let connection_id = rand::thread_rng().gen();
+        connections.insert(connection_id, (addr, Instant::now()));
+        connection_id
+    }
+
+    fn is_connected(&self, connection_id: u64, addr: SocketAddr) -> bool {
+        match self.connections.lock().unwrap().get(&connection_id) {
+            Some((issued_to, issued)) => *issued_to == addr && issued.elapsed() < CONNECTION_ID_TTL,
+            None => false,
+        }
+    }
+}
+
+fn parse_http_announce(params: &[(String, Vec<u8>)]) -> Result<AnnounceRequest, TrackerError> {
+    let get = |key: &str| {
+        params
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.clone())
+    };
+    let get_str = |key: &str| get(key).and_then(|value| String::from_utf8(value).ok());
+    let get_int = |key: &str| get_str(key).and_then(|value| value.parse::<i64>().ok());
+    let missing = |key: &str| TrackerError::Failure(format!("missing or invalid {}", key));
+
+    Ok(AnnounceRequest {
+        info_hash: get("info_hash").ok_or_else(|| missing("info_hash"))?,
+        peer_id: get("peer_id")
+            .map(|peer_id| String::from_utf8_lossy(&peer_id).into_owned())
+            .ok_or_else(|| missing("peer_id"))?,
+        port: get_str("port")
+            .and_then(|port| port.parse::<u16>().ok())
+            .ok_or_else(|| missing("port"))?,
+        uploaded: get_int("uploaded").unwrap_or(0),
+        downloaded: get_int("downloaded").unwrap_or(0),
+        left: get_int("left").ok_or_else(|| missing("left"))?,
+        event: match get_str("event").as_deref() {
+            Some("started") => Some("started"),
+            Some("completed") => Some("completed"),
+            Some("stopped") => Some("stopped"),
+            _ => None,
+        },
+        ip: get_str("ip").and_then(|ip| ip.parse::<IpAddr>().ok()),
+        numwant: get_int("numwant").map(|numwant| numwant as i32),
+        key: get_str("key")
+            .and_then(|key| u32::from_str_radix(&key, 16).ok())
+            .unwrap_or(0),
+        compact: get_str("compact").as_deref() != Some("0"),
+        no_peer_id: get_str("no_peer_id").as_deref() == Some("1"),
+        max_retransmits: None,
+    })
+}
+
+fn parse_udp_announce(packet: &[u8]) -> Option<AnnounceRequest> {
+    if packet.len() < 98 {
+        return None;
+    }
+    let int64 = |start: usize| i64::from_be_bytes(packet[start..start + 8].try_into().unwrap());
+    let int32 = |start: usize| u32::from_be_bytes(packet[start..start + 4].try_into().unwrap());
+
+    let ip = match int32(84) {
+        0 => None,
+        ip => Some(IpAddr::V4(Ipv4Addr::from(ip))),
+    };
+    Some(AnnounceRequest {
+        info_hash: packet[16..36].to_vec(),
+        peer_id: String::from_utf8_lossy(&packet[36..56]).into_owned(),
+        downloaded: int64(56),
+        left: int64(64),
+        uploaded: int64(72),
+        event: match int32(80) {
+            1 => Some("completed"),
+            2 => Some("started"),
+            3 => Some("stopped"),
+            _ => None,
+        },
+        ip,
+        key: int32(88),
+        numwant: Some(int32(92) as i32),
+        port: u16::from_be_bytes(packet[96..98].try_into().unwrap()),
+        compact: true,
+        no_peer_id: false,
+        max_retransmits: None,
+    })
+}
+
+/// Splits a query string into its percent decoded parameters, values stay
+/// raw bytes as info hashes are binary.
+fn parse_query(query: &str) -> Vec<(String, Vec<u8>)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = match pair.find('=') {
+                Some(position) => (&pair[..position], &pair[position + 1..]),
+                None => (pair, ""),
+            };
+            (
+                String::from_utf8_lossy(&percent_decode(key)).into_owned(),
+                percent_decode(value),
+            )
+        })
+        .collect()
+}
+
+fn percent_decode(input: &str) -> Vec<u8> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (escaped, bytes[i]) {
+            (Some(byte), _) => {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+            (None, b'+') => decoded.push(b' '),
+            (None, byte) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    decoded
+}
+
+fn failure_reason(error: &TrackerError) -> String {
+    match error {
+        TrackerError::Failure(reason) => reason.clone(),
+        error => error.to_string(),
+    }
+}
+
+fn failure_response(error: &TrackerError) -> Vec<u8> {
+    let reason = failure_reason(error);
+    let mut response = BDict::new(BTreeMap::new());
+    response.insert(
+        BString::new(&b"failure reason".to_vec()),
+        Box::new(BString::new(&reason.into_bytes())),
+    );
+    response.encode()
+}
+
+fn write_http(stream: &mut TcpStream, status: &str, body: &[u8]) -> io::Result<()> {
+    let header = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    stream.write_all(header.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+fn udp_header(action: u32, transaction_id: u32) -> Vec<u8> {
+    let mut header = action.to_be_bytes().to_vec();
+    header.extend_from_slice(&transaction_id.to_be_bytes());
+    header
+}
+
+fn udp_error(transaction_id: u32, message: &str) -> Vec<u8> {
+    let mut packet = udp_header(3, transaction_id);
+    packet.extend_from_slice(message.as_bytes());
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracker::{http, udp};
+    use crate::utility::PeerId;
+
+    use url::Url;
+
+    fn start(trust_ip: bool) -> TrackerServer {
+        TrackerServer::start(TrackerServerConfig {
+            http_addr: None,
+            udp_addr: None,
+            trust_ip,
+            ..TrackerServerConfig::default()
+        })
+        .unwrap()
+    }
+
+    fn request(ip: Option<IpAddr>) -> AnnounceRequest {
+        let mut request = AnnounceRequest::new(&[1u8; 20], &mut PeerId::new());
+        request.set_port(6881);
+        request.set_ip(ip);
+        request
+    }
+
+    #[test]
+    fn reported_ip_is_ignored_by_default() {
+        let server = start(false);
+        let spoofed = Some(IpAddr::from([203, 0, 113, 7]));
+        server
+            .inner
+            .announce(&request(spoofed), SocketAddr::from(([10, 0, 0, 1], 50000)))
+            .unwrap();
+
+        let swarms = server.inner.swarms.lock().unwrap();
+        let peers: Vec<&SocketAddr> = swarms[&vec![1u8; 20]].peers.keys().collect();
+        assert_eq!(peers, vec![&SocketAddr::from(([10, 0, 0, 1], 6881))]);
+    }
+
+    #[test]
+    fn reported_ip_is_used_when_trusted() {
+        let server = start(true);
+        let reported = Some(IpAddr::from([203, 0, 113, 7]));
+        server
+            .inner
+            .announce(&request(reported), SocketAddr::from(([10, 0, 0, 1], 50000)))
+            .unwrap();
+
+        let swarms = server.inner.swarms.lock().unwrap();
+        let peers: Vec<&SocketAddr> = swarms[&vec![1u8; 20]].peers.keys().collect();
+        assert_eq!(peers, vec![&SocketAddr::from(([203, 0, 113, 7], 6881))]);
+    }
+
+    #[test]
+    fn connections_over_the_limit_are_turned_away() {
+        let server = TrackerServer::start(TrackerServerConfig {
+            http_addr: Some(SocketAddr::from(([127, 0, 0, 1], 0))),
+            udp_addr: None,
+            max_connections: 1,
+            ..TrackerServerConfig::default()
+        })
+        .unwrap();
+        let http_addr = server.http_addr().unwrap();
+
+        // never finishes its request, keeping the only slot busy
+        let _idle = TcpStream::connect(http_addr).unwrap();
+        thread::sleep(POLL_INTERVAL);
+
+        let mut second = TcpStream::connect(http_addr).unwrap();
+        second
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut response = String::new();
+        second.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503"));
+    }
+
+    fn start_listening() -> TrackerServer {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let server = TrackerServer::start(TrackerServerConfig {
+            http_addr: Some(addr),
+            udp_addr: Some(addr),
+            ..TrackerServerConfig::default()
+        })
+        .unwrap();
+        server.allow(&[1u8; 20]);
+        server
+    }
+
+    fn peer_request(port: u16, left: i64, event: &'static str) -> AnnounceRequest {
+        let mut request = request(None);
+        request.set_port(port);
+        request.set_stats(0, 0, left);
+        request.set_event(Some(event));
+        request
+    }
+
+    fn local_peer(port: u16) -> Peer {
+        Peer::new(SocketAddr::from(([127, 0, 0, 1], port)))
+    }
+
+    #[tokio::test]
+    async fn http_clients_announce_and_scrape() {
+        let server = start_listening();
+        let announce_url = format!("http://{}/announce", server.http_addr().unwrap());
+        let client = http::HttpTracker::new(&http::HttpConfig::default()).unwrap();
+
+        let leecher = client
+            .announce(&announce_url, &peer_request(6881, 10, "started"))
+            .await
+            .unwrap();
+        assert!(leecher.get_peer_list().is_empty());
+        let seeder = client
+            .announce(&announce_url, &peer_request(6882, 0, "completed"))
+            .await
+            .unwrap();
+        assert_eq!(seeder.get_peer_list(), vec![local_peer(6881)]);
+        assert_eq!(seeder.get_interval(), 30 * 60);
+        assert_eq!(seeder.get_min_interval(), Some(60));
+        assert_eq!(seeder.get_complete(), Some(1));
+        assert_eq!(seeder.get_incomplete(), Some(1));
+
+        let scrape = client
+            .scrape(&announce_url, &[vec![1u8; 20], vec![2u8; 20]])
+            .await
+            .unwrap();
+        let stats = scrape.get_file(&[1u8; 20]).unwrap();
+        assert_eq!(
+            (stats.complete, stats.downloaded, stats.incomplete),
+            (1, 1, 1)
+        );
+        // not on the allowlist
+        assert!(scrape.get_file(&[2u8; 20]).is_none());
+
+        server.disallow(&[1u8; 20]);
+        assert_eq!(
+            client
+                .announce(&announce_url, &peer_request(6881, 10, "stopped"))
+                .await
+                .unwrap_err(),
+            TrackerError::Failure(String::from("torrent not allowed on this tracker"))
+        );
+    }
+
+    #[test]
+    fn udp_clients_announce_and_scrape() {
+        let server = start_listening();
+        let announce_url =
+            Url::parse(&format!("udp://{}/announce", server.udp_addr().unwrap())).unwrap();
+        let client = udp::UdpTracker::start(udp::UdpConfig {
+            timeout: Duration::from_secs(1),
+            max_retransmits: 1,
+            ..udp::UdpConfig::default()
+        })
+        .unwrap();
+
+        let leecher = client
+            .announce(&announce_url, &peer_request(6881, 10, "started"))
+            .unwrap();
+        assert!(leecher.get_peer_list().is_empty());
+        let seeder = client
+            .announce(&announce_url, &peer_request(6882, 0, "completed"))
+            .unwrap();
+        assert_eq!(seeder.get_peer_list(), vec![local_peer(6881)]);
+        assert_eq!(seeder.get_interval(), 30 * 60);
+        assert_eq!(seeder.get_complete(), Some(1));
+        assert_eq!(seeder.get_incomplete(), Some(1));
+
+        let scrape = client
+            .scrape(&announce_url, &[vec![1u8; 20], vec![2u8; 20]])
+            .unwrap();
+        let stats = scrape.get_file(&[1u8; 20]).unwrap();
+        assert_eq!(
+            (stats.complete, stats.downloaded, stats.incomplete),
+            (1, 1, 1)
+        );
+        // UDP answers in request order, so torrents not on the allowlist
+        // come back empty
+        assert_eq!(scrape.get_file(&[2u8; 20]).unwrap().complete, 0);
+
+        server.disallow(&[1u8; 20]);
+        assert_eq!(
+            client
+                .announce(&announce_url, &peer_request(6881, 10, "stopped"))
+                .unwrap_err(),
+            TrackerError::Failure(String::from("torrent not allowed on this tracker"))
+        );
+    }
+}
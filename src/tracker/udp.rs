@@ -1,150 +1,660 @@
-use super::{Peer, TrackerResponse};
-use crate::utility::{PeerId, PORT};
+use super::{
+    parse_compact_peers, AnnounceRequest, ScrapeFile, ScrapeResponse, TrackerError, TrackerResponse,
+};
 
 use rand::Rng;
 use url::Url;
 
+use std::collections::HashMap;
 use std::convert::TryInto;
-use std::net::ToSocketAddrs;
-use std::net::{Ipv4Addr, UdpSocket};
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Most info hashes that fit in a single scrape request.
+const MAX_SCRAPE_HASHES: usize = 74;
+const PROTOCOL_ID: u64 = 0x41727101980;
+/// BEP 15: a connection id may be used for one minute after it was received.
+pub const CONNECTION_ID_TTL: Duration = Duration::from_secs(60);
+pub const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(15);
+pub const MAX_RETRANSMITS: u32 = 8;
+/// Retransmits of an announce when other trackers can be tried instead,
+/// gives up after 15 + 30 + 60 seconds.
+pub const LIST_MAX_RETRANSMITS: u32 = 2;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+static SHARED: OnceLock<Result<UdpTracker, String>> = OnceLock::new();
 
 pub fn announce(
     announce_url: Url,
-    info_hash: &Vec<u8>,
-    peer_id: &mut PeerId,
-    uploaded: i64,
-    downloaded: i64,
-    left: i64,
-    event: Option<&str>,
-) -> TrackerResponse {
-    let mut socket = UdpSocket::bind("0.0.0.0:0").unwrap();
-
-    let ip = (
-        announce_url.host_str().unwrap(),
-        announce_url.port().unwrap(),
-    );
-    socket.connect(ip).unwrap();
-
-    let transaction_id = rand::thread_rng().gen();
-    socket.send(&build_connect_req(transaction_id)).unwrap();
-
-    let mut resp_buffer = [0u8; 65535];
-    let length = socket.recv(&mut resp_buffer).unwrap();
-
-    let connection_id = parse_connect_resp(&resp_buffer, length, transaction_id);
-
-    let transaction_id = rand::thread_rng().gen();
-    socket
-        .send(&build_announce_req(
-            connection_id,
-            transaction_id,
-            info_hash,
-            peer_id,
-            uploaded,
-            downloaded,
-            left,
-            event,
-        ))
-        .unwrap();
-
-    let length = socket.recv(&mut resp_buffer).unwrap();
-    parse_announce_resp(&resp_buffer, length, transaction_id)
+    request: &AnnounceRequest,
+) -> Result<TrackerResponse, TrackerError> {
+    shared()?.announce(&announce_url, request)
+}
+
+pub fn scrape(announce_url: Url, info_hashes: &[Vec<u8>]) -> Result<ScrapeResponse, TrackerError> {
+    shared()?.scrape(&announce_url, info_hashes)
+}
+
+/// The client every torrent announces through, started on first use.
+pub fn shared() -> Result<&'static UdpTracker, TrackerError> {
+    let shared = SHARED
+        .get_or_init(|| UdpTracker::start(UdpConfig::default()).map_err(|error| error.to_string()));
+    shared
+        .as_ref()
+        .map_err(|error| TrackerError::Connection(error.clone()))
+}
+
+#[derive(Clone, Debug)]
+pub struct UdpConfig {
+    /// A request is retransmitted after `timeout * 2^n` without an answer.
+    pub timeout: Duration,
+    pub max_retransmits: u32,
+    pub connection_id_ttl: Duration,
+}
+
+impl Default for UdpConfig {
+    fn default() -> UdpConfig {
+        UdpConfig {
+            timeout: RETRANSMIT_TIMEOUT,
+            max_retransmits: MAX_RETRANSMITS,
+            connection_id_ttl: CONNECTION_ID_TTL,
+        }
+    }
+}
+
+/// UDP tracker client (BEP 15) multiplexing the transactions of every
+/// torrent over one socket per address family.
+pub struct UdpTracker {
+    inner: Arc<Inner>,
+    receivers: Vec<JoinHandle<()>>,
+}
+
+struct Inner {
+    socket_v4: UdpSocket,
+    socket_v6: Option<UdpSocket>,
+    config: UdpConfig,
+    pending: Mutex<HashMap<u32, Waiting>>,
+    connections: Mutex<HashMap<SocketAddr, (u64, Instant)>>,
+    running: AtomicBool,
+}
+
+/// The tracker a transaction was sent to and where its answer goes.
+type Waiting = (SocketAddr, Sender<Vec<u8>>);
+
+impl UdpTracker {
+    pub fn start(config: UdpConfig) -> io::Result<UdpTracker> {
+        let socket_v4 = UdpSocket::bind("0.0.0.0:0")?;
+        socket_v4.set_read_timeout(Some(POLL_INTERVAL))?;
+        // hosts without IPv6 still get to use IPv4 trackers
+        let socket_v6 = UdpSocket::bind("[::]:0").ok();
+        if let Some(socket_v6) = &socket_v6 {
+            socket_v6.set_read_timeout(Some(POLL_INTERVAL))?;
+        }
+
+        let inner = Arc::new(Inner {
+            socket_v4,
+            socket_v6,
+            config,
+            pending: Mutex::new(HashMap::new()),
+            connections: Mutex::new(HashMap::new()),
+            running: AtomicBool::new(true),
+        });
+
+        let mut receivers = Vec::new();
+        let receiver = Arc::clone(&inner);
+        receivers.push(thread::spawn(move || {
+            receiver.receive_loop(&receiver.socket_v4)
+        }));
+        if inner.socket_v6.is_some() {
+            let receiver = Arc::clone(&inner);
+            receivers.push(thread::spawn(move || {
+                if let Some(socket) = &receiver.socket_v6 {
+                    receiver.receive_loop(socket);
+                }
+            }));
+        }
+
+        Ok(UdpTracker { inner, receivers })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.socket_v4.local_addr()
+    }
+
+    pub fn announce(
+        &self,
+        announce_url: &Url,
+        request: &AnnounceRequest,
+    ) -> Result<TrackerResponse, TrackerError> {
+        let max_retransmits = match request.get_max_retransmits() {
+            Some(max_retransmits) => max_retransmits.min(self.inner.config.max_retransmits),
+            None => self.inner.config.max_retransmits,
+        };
+        each_addr(announce_url, |tracker_addr| {
+            let response = self.inner.request(
+                tracker_addr,
+                ACTION_ANNOUNCE,
+                max_retransmits,
+                |connection_id, transaction_id| {
+                    build_announce_req(connection_id, transaction_id, request)
+                },
+            )?;
+            parse_announce_resp(&response, tracker_addr.is_ipv6())
+        })
+    }
+
+    pub fn scrape(
+        &self,
+        announce_url: &Url,
+        info_hashes: &[Vec<u8>],
+    ) -> Result<ScrapeResponse, TrackerError> {
+        each_addr(announce_url, |tracker_addr| {
+            let mut files = HashMap::new();
+            for info_hashes in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+                let response = self.inner.request(
+                    tracker_addr,
+                    ACTION_SCRAPE,
+                    self.inner.config.max_retransmits,
+                    |connection_id, transaction_id| {
+                        build_scrape_req(connection_id, transaction_id, info_hashes)
+                    },
+                )?;
+                let stats = parse_scrape_resp(&response)?;
+                for (info_hash, file) in info_hashes.iter().zip(stats) {
+                    files.insert(info_hash.clone(), file);
+                }
+            }
+
+            Ok(ScrapeResponse { files })
+        })
+    }
+}
+
+impl Drop for UdpTracker {
+    fn drop(&mut self) {
+        self.inner.running.store(false, Ordering::SeqCst);
+        for receiver in self.receivers.drain(..) {
+            receiver.join().unwrap();
+        }
+    }
+}
+
+impl Inner {
+    /// Sends a request built from a connection id and a transaction id,
+    /// connecting first when no valid connection id is cached, and
+    /// retransmits after 15 * 2^n seconds as BEP 15 describes, up to
+    /// `max_retransmits` times.
+    fn request<F>(
+        &self,
+        tracker_addr: SocketAddr,
+        action: u32,
+        max_retransmits: u32,
+        mut build: F,
+    ) -> Result<Vec<u8>, TrackerError>
+    where
+        F: FnMut(u64, u32) -> Vec<u8>,
+    {
+        for attempt in 0..=max_retransmits {
+            let timeout = self.config.timeout * 2u32.pow(attempt);
+            let connection_id = match self.connection_id(tracker_addr) {
+                Some(connection_id) => connection_id,
+                None => {
+                    match self.transact(tracker_addr, ACTION_CONNECT, timeout, build_connect_req)? {
+                        Some(response) => {
+                            let connection_id = parse_connect_resp(&response)?;
+                            self.connections
+                                .lock()
+                                .unwrap()
+                                .insert(tracker_addr, (connection_id, Instant::now()));
+                            connection_id
+                        }
+                        None => continue,
+                    }
+                }
+            };
+
+            let result = self.transact(tracker_addr, action, timeout, |transaction_id| {
+                build(connection_id, transaction_id)
+            });
+            match result {
+                Ok(Some(response)) => return Ok(response),
+                Ok(None) => continue,
+                Err(error) => {
+                    // the tracker may have dropped our connection id early
+                    self.connections.lock().unwrap().remove(&tracker_addr);
+                    return Err(error);
+                }
+            }
+        }
+
+        Err(TrackerError::Connection(format!(
+            "Tracker {} did not answer",
+            tracker_addr
+        )))
+    }
+
+    fn connection_id(&self, tracker_addr: SocketAddr) -> Option<u64> {
+        let mut connections = self.connections.lock().unwrap();
+        match connections.get(&tracker_addr) {
+            Some((connection_id, received))
+                if received.elapsed() < self.config.connection_id_ttl =>
+            {
+                Some(*connection_id)
+            }
+            Some(_) => {
+                connections.remove(&tracker_addr);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// One request and its answer, None if none came within `timeout`.
+    fn transact<F>(
+        &self,
+        tracker_addr: SocketAddr,
+        action: u32,
+        timeout: Duration,
+        build: F,
+    ) -> Result<Option<Vec<u8>>, TrackerError>
+    where
+        F: FnOnce(u32) -> Vec<u8>,
+    {
+        let socket = match (tracker_addr, &self.socket_v6) {
+            (SocketAddr::V4(_), _) => &self.socket_v4,
+            (SocketAddr::V6(_), Some(socket_v6)) => socket_v6,
+            (SocketAddr::V6(_), None) => {
+                return Err(TrackerError::Connection(String::from(
+                    "IPv6 is not available",
+                )))
+            }
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        let mut transaction_id = rand::thread_rng().gen();
+        {
+            let mut pending = self.pending.lock().unwrap();
+            while pending.contains_key(&transaction_id) {
+                transaction_id = rand::thread_rng().gen();
+            }
+            pending.insert(transaction_id, (tracker_addr, sender));
+        }
+
+        let sent = socket.send_to(&build(transaction_id), tracker_addr);
+        let received = match sent {
+            Ok(_) => receiver.recv_timeout(timeout).ok(),
+            Err(_) => None,
+        };
+        self.pending.lock().unwrap().remove(&transaction_id);
+        if let Err(error) = sent {
+            return Err(TrackerError::Connection(error.to_string()));
+        }
+
+        let response = match received {
+            Some(response) => response,
+            None => return Ok(None),
+        };
+        match u32::from_be_bytes(response[0..4].try_into().unwrap()) {
+            ACTION_ERROR => Err(TrackerError::Failure(
+                String::from_utf8_lossy(&response[8..]).into_owned(),
+            )),
+            received_action if received_action == action => Ok(Some(response)),
+            _ => Err(TrackerError::InvalidResponse(String::from(
+                "Wrong action value recieved",
+            ))),
+        }
+    }
+
+    /// Hands every answer to the request waiting on its transaction id.
+    fn receive_loop(&self, socket: &UdpSocket) {
+        let mut buffer = [0u8; 65535];
+        while self.running.load(Ordering::SeqCst) {
+            let (length, addr) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(_) => continue,
+            };
+            if length < 8 {
+                continue;
+            }
+
+            let transaction_id = u32::from_be_bytes(buffer[4..8].try_into().unwrap());
+            let mut pending = self.pending.lock().unwrap();
+            if pending
+                .get(&transaction_id)
+                .is_some_and(|(tracker_addr, _)| *tracker_addr == addr)
+            {
+                let (_, sender) = pending.remove(&transaction_id).unwrap();
+                sender.send(buffer[..length].to_vec()).unwrap_or(());
+            }
+        }
+    }
+}
+
+fn resolve(announce_url: &Url) -> Result<Vec<SocketAddr>, TrackerError> {
+    let host = match (announce_url.host_str(), announce_url.port()) {
+        (Some(host), Some(port)) => (host, port),
+        _ => return Err(TrackerError::InvalidUrl(announce_url.to_string())),
+    };
+    let addrs: Vec<SocketAddr> = host
+        .to_socket_addrs()
+        .map_err(|error| TrackerError::Connection(error.to_string()))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(TrackerError::InvalidUrl(announce_url.to_string()));
+    }
+    Ok(addrs)
+}
+
+/// Runs `request` against every address the tracker resolves to until one
+/// answers. Only connection errors move on to the next address, an answer
+/// from the tracker is final.
+fn each_addr<T, F>(announce_url: &Url, mut request: F) -> Result<T, TrackerError>
+where
+    F: FnMut(SocketAddr) -> Result<T, TrackerError>,
+{
+    let mut last_error = None;
+    for tracker_addr in resolve(announce_url)? {
+        match request(tracker_addr) {
+            Err(TrackerError::Connection(error)) => {
+                last_error = Some(TrackerError::Connection(error))
+            }
+            result => return result,
+        }
+    }
+    Err(last_error.unwrap())
+}
+
+fn build_scrape_req(connection_id: u64, transaction_id: u32, info_hashes: &[Vec<u8>]) -> Vec<u8> {
+    let mut buffer = vec![];
+
+    buffer.append(&mut connection_id.to_be_bytes().to_vec());
+    buffer.append(&mut ACTION_SCRAPE.to_be_bytes().to_vec()); //action
+    buffer.append(&mut transaction_id.to_be_bytes().to_vec()); //transaction id
+    for info_hash in info_hashes {
+        buffer.append(&mut info_hash.to_vec());
+    }
+
+    buffer
+}
+
+fn parse_scrape_resp(buffer: &[u8]) -> Result<Vec<ScrapeFile>, TrackerError> {
+    let files = buffer[8..]
+        .chunks_exact(12)
+        .map(|stats| ScrapeFile {
+            complete: u32::from_be_bytes(stats[0..4].try_into().unwrap()),
+            downloaded: u32::from_be_bytes(stats[4..8].try_into().unwrap()),
+            incomplete: u32::from_be_bytes(stats[8..12].try_into().unwrap()),
+            name: None,
+        })
+        .collect();
+    Ok(files)
 }
 
 fn build_announce_req(
     connection_id: u64,
     transaction_id: u32,
-    info_hash: &Vec<u8>,
-    peer_id: &mut PeerId,
-    uploaded: i64,
-    downloaded: i64,
-    left: i64,
-    event: Option<&str>,
+    request: &AnnounceRequest,
 ) -> Vec<u8> {
-    let event = match event {
+    let event = match request.get_event() {
         Some("completed") => 1u32,
         Some("started") => 2,
         Some("stopped") => 3,
         _ => 0,
     };
+    // only an IPv4 address fits, the tracker uses the sender address otherwise
+    let ip = match request.get_ip() {
+        Some(IpAddr::V4(ip)) => u32::from(ip),
+        _ => 0,
+    };
     let mut buffer = vec![];
 
     buffer.append(&mut connection_id.to_be_bytes().to_vec());
-    buffer.append(&mut 1u32.to_be_bytes().to_vec()); //action
+    buffer.append(&mut ACTION_ANNOUNCE.to_be_bytes().to_vec()); //action
     buffer.append(&mut transaction_id.to_be_bytes().to_vec()); //transaction id
-    buffer.append(&mut info_hash.to_vec());
-    buffer.append(&mut peer_id.value().as_bytes().to_vec());
-    buffer.append(&mut downloaded.to_be_bytes().to_vec());
-    buffer.append(&mut left.to_be_bytes().to_vec());
-    buffer.append(&mut uploaded.to_be_bytes().to_vec());
+    buffer.append(&mut request.get_info_hash().to_vec());
+    buffer.append(&mut request.get_peer_id().as_bytes().to_vec());
+    buffer.append(&mut request.get_downloaded().to_be_bytes().to_vec());
+    buffer.append(&mut request.get_left().to_be_bytes().to_vec());
+    buffer.append(&mut request.get_uploaded().to_be_bytes().to_vec());
     buffer.append(&mut event.to_be_bytes().to_vec());
-    buffer.append(&mut 0u32.to_be_bytes().to_vec()); //Ip Address
-    buffer.append(&mut rand::thread_rng().gen::<u32>().to_be_bytes().to_vec()); //key
-    buffer.append(&mut (-1i32).to_be_bytes().to_vec());
-    buffer.append(&mut (PORT as u16).to_be_bytes().to_vec());
+    buffer.append(&mut ip.to_be_bytes().to_vec()); //Ip Address
+    buffer.append(&mut request.get_key().to_be_bytes().to_vec());
+    buffer.append(&mut request.get_numwant().unwrap_or(-1).to_be_bytes().to_vec());
+    buffer.append(&mut request.get_port().to_be_bytes().to_vec());
 
     buffer
 }
 
-fn parse_announce_resp(buffer: &[u8], length: usize, transaction_id: u32) -> TrackerResponse {
-    if length < 20 {
-        panic!("Length of bytes recieved is less than 20 bytes (must be aleast 20 bytes)");
-    }
-    let action = u32::from_be_bytes(buffer[0..4].try_into().unwrap());
-    if action != 1 {
-        panic!("Wrong action value recieved");
-    }
-    let r_transaction_id = u32::from_be_bytes(buffer[4..8].try_into().unwrap());
-    if r_transaction_id != transaction_id {
-        panic!("Wrong transaction id recieved");
+/// Peers are 18 bytes each instead of 6 when the announce went over IPv6.
+fn parse_announce_resp(buffer: &[u8], ipv6: bool) -> Result<TrackerResponse, TrackerError> {
+    if buffer.len() < 20 {
+        return Err(TrackerError::InvalidResponse(String::from(
+            "Length of bytes recieved is less than 20 bytes (must be aleast 20 bytes)",
+        )));
     }
 
     let interval = u32::from_be_bytes(buffer[8..12].try_into().unwrap());
     let incomplete = u32::from_be_bytes(buffer[12..16].try_into().unwrap());
     let complete = u32::from_be_bytes(buffer[16..20].try_into().unwrap());
 
-    let length = length - 20;
-    let buffer = &buffer[20..];
-
-    let mut i = 0;
-    let mut peer_list = Vec::new();
+    let peer_list = parse_compact_peers(&buffer[20..], ipv6);
 
-    while i < length {
-        let ip: [u8; 4] = buffer[i..i + 4].try_into().unwrap();
-        let ip = Ipv4Addr::from(ip);
-        let port = u16::from_be_bytes(buffer[i + 4..i + 6].try_into().unwrap());
-        peer_list.push(Peer { ip, port });
-        i += 6;
-    }
-
-    TrackerResponse {
+    Ok(TrackerResponse {
         interval,
-        complete,
-        incomplete,
+        min_interval: None,
+        complete: Some(complete),
+        incomplete: Some(incomplete),
+        warning: None,
+        tracker_id: None,
         peer_list,
-    }
+    })
 }
 
 fn build_connect_req(transaction_id: u32) -> Vec<u8> {
     let mut buffer = vec![];
-    buffer.append(&mut 0x41727101980u64.to_be_bytes().to_vec());
-    buffer.append(&mut 0u32.to_be_bytes().to_vec()); //action
+    buffer.append(&mut PROTOCOL_ID.to_be_bytes().to_vec());
+    buffer.append(&mut ACTION_CONNECT.to_be_bytes().to_vec()); //action
     buffer.append(&mut transaction_id.to_be_bytes().to_vec()); //transaction id
     buffer
 }
 
-fn parse_connect_resp(buffer: &[u8], length: usize, transaction_id: u32) -> u64 {
-    if length < 16 {
-        panic!("Length of bytes recieved is less than 16 bytes (must be atleast 16 bytes)")
+fn parse_connect_resp(buffer: &[u8]) -> Result<u64, TrackerError> {
+    if buffer.len() < 16 {
+        return Err(TrackerError::InvalidResponse(String::from(
+            "Length of bytes recieved is less than 16 bytes (must be atleast 16 bytes)",
+        )));
     }
-    let action = u32::from_be_bytes(buffer[0..4].try_into().unwrap());
-    if action != 0 {
-        panic!("Wrong action value recieved");
+    let connection_id = u64::from_be_bytes(buffer[8..16].try_into().unwrap());
+    Ok(connection_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracker::Peer;
+    use crate::utility::PeerId;
+
+    /// A tracker on localhost that ignores the first `drop` packets, then
+    /// answers connects, announces and scrapes, or every request with
+    /// `error`. Scraped torrents have as many seeders as the first byte of
+    /// their info hash, 100 more downloads and one leecher.
+    /// Returns its url and the actions it received.
+    fn fake_tracker(drop: usize, error: Option<&'static str>) -> (Url, Arc<Mutex<Vec<u32>>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(3)))
+            .unwrap();
+        let url = Url::parse(&format!("udp://{}/announce", socket.local_addr().unwrap())).unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let actions = Arc::clone(&received);
+        thread::spawn(move || {
+            let mut buffer = [0u8; 1500];
+            while let Ok((length, addr)) = socket.recv_from(&mut buffer) {
+                let packet = &buffer[..length];
+                let action = u32::from_be_bytes(packet[8..12].try_into().unwrap());
+                actions.lock().unwrap().push(action);
+                if actions.lock().unwrap().len() <= drop {
+                    continue;
+                }
+
+                let mut response = Vec::new();
+                match error {
+                    Some(error) => {
+                        response.extend_from_slice(&ACTION_ERROR.to_be_bytes());
+                        response.extend_from_slice(&packet[12..16]);
+                        response.extend_from_slice(error.as_bytes());
+                    }
+                    None => {
+                        response.extend_from_slice(&action.to_be_bytes());
+                        response.extend_from_slice(&packet[12..16]);
+                        if action == ACTION_CONNECT {
+                            response.extend_from_slice(&42u64.to_be_bytes());
+                        } else if action == ACTION_SCRAPE {
+                            for info_hash in packet[16..].chunks_exact(20) {
+                                let seeders = info_hash[0] as u32;
+                                response.extend_from_slice(&seeders.to_be_bytes());
+                                response.extend_from_slice(&(seeders + 100).to_be_bytes());
+                                response.extend_from_slice(&1u32.to_be_bytes());
+                            }
+                        } else {
+                            response.extend_from_slice(&900u32.to_be_bytes());
+                            response.extend_from_slice(&3u32.to_be_bytes());
+                            response.extend_from_slice(&5u32.to_be_bytes());
+                            response.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+                        }
+                    }
+                }
+                socket.send_to(&response, addr).unwrap();
+            }
+        });
+        (url, received)
     }
-    let r_transaction_id = u32::from_be_bytes(buffer[4..8].try_into().unwrap());
-    if r_transaction_id != transaction_id {
-        panic!("Wrong transaction id recieved");
+
+    fn start_client() -> UdpTracker {
+        UdpTracker::start(UdpConfig {
+            timeout: Duration::from_millis(100),
+            max_retransmits: 3,
+            connection_id_ttl: CONNECTION_ID_TTL,
+        })
+        .unwrap()
+    }
+
+    fn announce_request() -> AnnounceRequest {
+        AnnounceRequest::new(&[1u8; 20], &mut PeerId::new())
+    }
+
+    #[test]
+    fn announce_to_fake_tracker() {
+        let (url, received) = fake_tracker(0, None);
+        let client = start_client();
+
+        let response = client.announce(&url, &announce_request()).unwrap();
+        assert_eq!(response.get_interval(), 900);
+        assert_eq!(
+            response.get_peer_list(),
+            vec![Peer::new(SocketAddr::from(([10, 0, 0, 1], 6881)))]
+        );
+
+        // the connection id is reused for the second announce
+        client.announce(&url, &announce_request()).unwrap();
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![ACTION_CONNECT, ACTION_ANNOUNCE, ACTION_ANNOUNCE]
+        );
+    }
+
+    #[test]
+    fn lost_packets_are_retransmitted() {
+        let (url, received) = fake_tracker(2, None);
+        let client = start_client();
+
+        client.announce(&url, &announce_request()).unwrap();
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![
+                ACTION_CONNECT,
+                ACTION_CONNECT,
+                ACTION_CONNECT,
+                ACTION_ANNOUNCE
+            ]
+        );
+    }
+
+    #[test]
+    fn announce_gives_up_after_max_retransmits() {
+        let (url, received) = fake_tracker(usize::MAX, None);
+        let client = start_client();
+        let mut request = announce_request();
+        request.set_max_retransmits(Some(1));
+
+        match client.announce(&url, &request) {
+            Err(TrackerError::Connection(_)) => (),
+            result => panic!("unexpected result {:?}", result.map(|_| ())),
+        }
+        assert_eq!(received.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn tracker_errors_are_reported() {
+        let (url, _) = fake_tracker(0, Some("torrent not registered"));
+        let client = start_client();
+
+        match client.announce(&url, &announce_request()) {
+            Err(TrackerError::Failure(reason)) => assert_eq!(reason, "torrent not registered"),
+            result => panic!("unexpected result {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn every_resolved_address_is_tried() {
+        let url = Url::parse("udp://localhost:6969/announce").unwrap();
+        let mut tried = Vec::new();
+        let result = each_addr(&url, |tracker_addr| {
+            tried.push(tracker_addr);
+            Err::<(), _>(TrackerError::Connection(String::from("timed out")))
+        });
+        assert!(matches!(result, Err(TrackerError::Connection(_))));
+        assert_eq!(tried, resolve(&url).unwrap());
+
+        let mut tried = 0;
+        let result = each_addr(&url, |_| {
+            tried += 1;
+            Err::<(), _>(TrackerError::Failure(String::from("denied")))
+        });
+        assert!(matches!(result, Err(TrackerError::Failure(_))));
+        assert_eq!(tried, 1);
+    }
+
+    #[test]
+    fn scrape_fake_tracker() {
+        let (url, received) = fake_tracker(0, None);
+        let client = start_client();
+        // one more than fits in a request
+        let info_hashes: Vec<Vec<u8>> =
+            (0..=MAX_SCRAPE_HASHES).map(|i| vec![i as u8; 20]).collect();
+
+        let response = client.scrape(&url, &info_hashes).unwrap();
+        assert_eq!(response.get_files().len(), MAX_SCRAPE_HASHES + 1);
+        for (i, info_hash) in info_hashes.iter().enumerate() {
+            let file = response.get_file(info_hash).unwrap();
+            assert_eq!(file.get_complete(), i as u32);
+            assert_eq!(file.get_downloaded(), i as u32 + 100);
+            assert_eq!(file.get_incomplete(), 1);
+        }
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![ACTION_CONNECT, ACTION_SCRAPE, ACTION_SCRAPE]
+        );
     }
-    let connection_id = u64::from_be_bytes(buffer[8..16].try_into().unwrap());
-    connection_id
 }
@@ -0,0 +1,734 @@
+use super::{
+    AnnounceRequest, ScrapeFile, ScrapeResponse, Tracker, TrackerError, TrackerFuture,
+    TrackerResponse,
+};
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Map, Value};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot, Mutex as AsyncMutex};
+use tokio::time;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use url::Url;
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+/// WebTorrent trackers do not send an interval with every answer.
+pub const DEFAULT_INTERVAL: u32 = 120;
+pub const RESPONSE_TIMEOUT: Duration = Duration::from_secs(15);
+/// Offers sent with an announce when the request has no `numwant`.
+pub const DEFAULT_OFFERS: usize = 10;
+
+/// A WebRTC offer sent along with an announce, the tracker forwards it to
+/// other peers of the swarm.
+#[derive(Clone, Debug)]
+pub struct Offer {
+    pub offer_id: Vec<u8>,
+    /// The session description, `{"type": "offer", "sdp": ...}`.
+    pub offer: Value,
+}
+
+/// Does the WebRTC side of the signaling, the tracker only relays offers and
+/// answers between peers.
+pub trait SignalingHandler: Send + Sync {
+    fn create_offers(&self, info_hash: &[u8], count: usize) -> Vec<Offer>;
+
+    /// Another peer of the swarm sent us an offer, returns our answer or
+    /// None to ignore it.
+    fn handle_offer(
+        &self,
+        info_hash: &[u8],
+        peer_id: &[u8],
+        offer_id: &[u8],
+        offer: &Value,
+    ) -> Option<Value>;
+
+    fn handle_answer(&self, info_hash: &[u8], peer_id: &[u8], offer_id: &[u8], answer: &Value);
+}
+
+/// Creates no offers and ignores the ones received, enough for peer counts
+/// and the announce lifecycle.
+pub struct NoSignaling;
+
+impl SignalingHandler for NoSignaling {
+    fn create_offers(&self, _info_hash: &[u8], _count: usize) -> Vec<Offer> {
+        Vec::new()
+    }
+
+    fn handle_offer(
+        &self,
+        _info_hash: &[u8],
+        _peer_id: &[u8],
+        _offer_id: &[u8],
+        _offer: &Value,
+    ) -> Option<Value> {
+        None
+    }
+
+    fn handle_answer(&self, _info_hash: &[u8], _peer_id: &[u8], _offer_id: &[u8], _answer: &Value) {
+    }
+}
+
+/// WebTorrent tracker client, keeps one connection per tracker open so
+/// offers of other peers can arrive between announces.
+pub struct WebSocketTracker {
+    handler: Arc<dyn SignalingHandler>,
+    connections: Arc<Connections>,
+    /// Held while connecting to a url so concurrent requests share the new
+    /// connection.
+    connecting: Mutex<HashMap<String, Arc<AsyncMutex<()>>>>,
+}
+
+/// Open connections by announce url, a connection removes itself once its
+/// socket closes or can no longer be written to.
+type Connections = Mutex<HashMap<String, Connection>>;
+
+#[derive(Clone)]
+struct Connection {
+    outgoing: mpsc::UnboundedSender<Message>,
+    state: Arc<State>,
+}
+
+type PendingKey = (&'static str, Vec<u8>);
+/// A request waiting for its answer, by request id.
+type Waiter = (u64, oneshot::Sender<Value>);
+
+/// Answers carry no request id, so requests with the same key wait in the
+/// order they were sent and each answer goes to the oldest one.
+struct State {
+    pending: Mutex<HashMap<PendingKey, VecDeque<Waiter>>>,
+    next_request: AtomicU64,
+    peer_id: Mutex<Vec<u8>>,
+    closed: AtomicBool,
+    /// Where the connection unregisters itself once closed.
+    key: String,
+    connections: Weak<Connections>,
+}
+
+impl WebSocketTracker {
+    pub fn new(handler: Arc<dyn SignalingHandler>) -> WebSocketTracker {
+        WebSocketTracker {
+            handler,
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            connecting: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn announce(
+        &self,
+        announce_url: &Url,
+        request: &AnnounceRequest,
+    ) -> Result<TrackerResponse, TrackerError> {
+        let connection = self.connection(announce_url).await?;
+        *connection.state.peer_id.lock().unwrap() = request.get_peer_id().as_bytes().to_vec();
+
+        let mut message = json!({
+            "action": "announce",
+            "info_hash": to_binary_string(request.get_info_hash()),
+            "peer_id": to_binary_string(request.get_peer_id().as_bytes()),
+            "uploaded": request.get_uploaded(),
+            "downloaded": request.get_downloaded(),
+            "left": request.get_left(),
+        });
+        if let Some(event) = request.get_event() {
+            message["event"] = json!(event);
+        }
+        if request.get_event() != Some("stopped") {
+            let count = match request.get_numwant() {
+                Some(numwant) => numwant.max(0) as usize,
+                None => DEFAULT_OFFERS,
+            };
+            let offers: Vec<Value> = self
+                .handler
+                .create_offers(request.get_info_hash(), count)
+                .into_iter()
+                .map(|offer| {
+                    json!({
+                        "offer_id": to_binary_string(&offer.offer_id),
+                        "offer": offer.offer,
+                    })
+                })
+                .collect();
+            message["numwant"] = json!(offers.len());
+            message["offers"] = Value::Array(offers);
+        }
+
+        let response = connection
+            .request(("announce", request.get_info_hash().clone()), message)
+            .await?;
+        parse_announce_response(&response)
+    }
+
+    pub async fn scrape(
+        &self,
+        announce_url: &Url,
+        info_hashes: &[Vec<u8>],
+    ) -> Result<ScrapeResponse, TrackerError> {
+        let connection = self.connection(announce_url).await?;
+        let info_hash: Vec<Value> = info_hashes
+            .iter()
+            .map(|info_hash| json!(to_binary_string(info_hash)))
+            .collect();
+        let message = json!({
+            "action": "scrape",
+            "info_hash": info_hash,
+        });
+
+        let response = connection.request(("scrape", Vec::new()), message).await?;
+        parse_scrape_response(&response)
+    }
+
+    async fn connection(&self, announce_url: &Url) -> Result<Connection, TrackerError> {
+        let key = announce_url.to_string();
+        if let Some(connection) = self.open_connection(&key) {
+            return Ok(connection);
+        }
+
+        let connecting = Arc::clone(
+            self.connecting
+                .lock()
+                .unwrap()
+                .entry(key.clone())
+                .or_default(),
+        );
+        let _connecting = connecting.lock().await;
+        // another request may have connected while we waited
+        if let Some(connection) = self.open_connection(&key) {
+            return Ok(connection);
+        }
+        let connection = connect(
+            announce_url,
+            Arc::clone(&self.handler),
+            Arc::downgrade(&self.connections),
+        )
+        .await?;
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(key, connection.clone());
+        Ok(connection)
+    }
+
+    fn open_connection(&self, key: &str) -> Option<Connection> {
+        self.connections
+            .lock()
+            .unwrap()
+            .get(key)
+            .filter(|connection| !connection.state.closed.load(Ordering::SeqCst))
+            .cloned()
+    }
+}
+
+impl Tracker for WebSocketTracker {
+    fn announce<'a>(
+        &'a self,
+        announce_url: &'a Url,
+        request: &'a AnnounceRequest,
+    ) -> TrackerFuture<'a, TrackerResponse> {
+        Box::pin(WebSocketTracker::announce(self, announce_url, request))
+    }
+
+    fn scrape<'a>(
+        &'a self,
+        announce_url: &'a Url,
+        info_hashes: &'a [Vec<u8>],
+    ) -> TrackerFuture<'a, ScrapeResponse> {
+        Box::pin(WebSocketTracker::scrape(self, announce_url, info_hashes))
+    }
+}
+
+impl Connection {
+    /// Sends `message` and waits for the answer matching `key`.
+    async fn request(&self, key: PendingKey, message: Value) -> Result<Value, TrackerError> {
+        let (sender, receiver) = oneshot::channel();
+        let id = self.state.next_request.fetch_add(1, Ordering::SeqCst);
+        self.state
+            .pending
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .push_back((id, sender));
+        if self
+            .outgoing
+            .send(Message::Text(message.to_string()))
+            .is_err()
+        {
+            // the socket task is gone, later requests need a new connection
+            self.close();
+            return Err(TrackerError::Connection(String::from(
+                "WebSocket connection closed",
+            )));
+        }
+
+        match time::timeout(RESPONSE_TIMEOUT, receiver).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(TrackerError::Connection(String::from(
+                "WebSocket connection closed",
+            ))),
+            Err(_) => {
+                self.remove_pending(&key, id);
+                Err(TrackerError::Connection(String::from(
+                    "WebSocket tracker did not answer",
+                )))
+            }
+        }
+    }
+
+    /// Fails the waiting requests and unregisters the connection.
+    fn close(&self) {
+        self.state.closed.store(true, Ordering::SeqCst);
+        // dropping the senders fails the requests still waiting
+        self.state.pending.lock().unwrap().clear();
+        if let Some(connections) = self.state.connections.upgrade() {
+            let mut connections = connections.lock().unwrap();
+            if connections
+                .get(&self.state.key)
+                .is_some_and(|open| Arc::ptr_eq(&open.state, &self.state))
+            {
+                connections.remove(&self.state.key);
+            }
+        }
+    }
+
+    fn remove_pending(&self, key: &PendingKey, id: u64) {
+        let mut pending = self.state.pending.lock().unwrap();
+        if let Some(waiting) = pending.get_mut(key) {
+            waiting.retain(|(waiting_id, _)| *waiting_id != id);
+            if waiting.is_empty() {
+                pending.remove(key);
+            }
+        }
+    }
+
+    /// Hands an answer to the oldest request waiting for `key`.
+    fn answer(&self, key: &PendingKey, response: Value) {
+        let mut pending = self.state.pending.lock().unwrap();
+        if let Some(waiting) = pending.get_mut(key) {
+            if let Some((_, sender)) = waiting.pop_front() {
+                sender.send(response).unwrap_or(());
+            }
+            if waiting.is_empty() {
+                pending.remove(key);
+            }
+        }
+    }
+}
+
+async fn connect(
+    announce_url: &Url,
+    handler: Arc<dyn SignalingHandler>,
+    connections: Weak<Connections>,
+) -> Result<Connection, TrackerError> {
+    let host = match (
+        announce_url.host_str(),
+        announce_url.port_or_known_default(),
+    ) {
+        (Some(host), Some(port)) => (host, port),
+        _ => return Err(TrackerError::InvalidUrl(announce_url.to_string())),
+    };
+    let stream = TcpStream::connect(host)
+        .await
+        .map_err(|error| TrackerError::Connection(error.to_string()))?;
+
+    match announce_url.scheme() {
+        "wss" => {
+            let connector = native_tls::TlsConnector::new()
+                .map_err(|error| TrackerError::Connection(error.to_string()))?;
+            let stream = tokio_tls::TlsConnector::from(connector)
+                .connect(host.0, stream)
+                .await
+                .map_err(|error| TrackerError::Connection(error.to_string()))?;
+            handshake(announce_url, stream, handler, connections).await
+        }
+        _ => handshake(announce_url, stream, handler, connections).await,
+    }
+}
+
+async fn handshake<S>(
+    announce_url: &Url,
+    stream: S,
+    handler: Arc<dyn SignalingHandler>,
+    connections: Weak<Connections>,
+) -> Result<Connection, TrackerError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (socket, _) = tokio_tungstenite::client_async(announce_url.as_str(), stream)
+        .await
+        .map_err(|error| TrackerError::Connection(error.to_string()))?;
+
+    let (outgoing, receiver) = mpsc::unbounded_channel();
+    let state = Arc::new(State {
+        pending: Mutex::new(HashMap::new()),
+        next_request: AtomicU64::new(0),
+        peer_id: Mutex::new(Vec::new()),
+        closed: AtomicBool::new(false),
+        key: announce_url.to_string(),
+        connections,
+    });
+    let connection = Connection { outgoing, state };
+
+    tokio::spawn(run(socket, receiver, connection.clone(), handler));
+    Ok(connection)
+}
+
+/// Writes queued messages and dispatches the received ones until either
+/// side closes the connection.
+async fn run<S>(
+    socket: WebSocketStream<S>,
+    mut receiver: mpsc::UnboundedReceiver<Message>,
+    connection: Connection,
+    handler: Arc<dyn SignalingHandler>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sink, mut stream) = socket.split();
+    // ends with the close message queued below or when the socket fails
+    tokio::spawn(async move {
+        while let Some(message) = receiver.recv().await {
+            let close = matches!(message, Message::Close(_));
+            if sink.send(message).await.is_err() || close {
+                break;
+            }
+        }
+    });
+
+    while let Some(Ok(message)) = stream.next().await {
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        if let Ok(Value::Object(message)) = serde_json::from_str::<Value>(&text) {
+            handle_message(&connection, handler.as_ref(), message);
+        }
+    }
+
+    connection.close();
+    connection.outgoing.send(Message::Close(None)).unwrap_or(());
+}
+
+fn handle_message(
+    connection: &Connection,
+    handler: &dyn SignalingHandler,
+    message: Map<String, Value>,
+) {
+    let binary = |key: &str| {
+        message
+            .get(key)
+            .and_then(Value::as_str)
+            .and_then(from_binary_string)
+    };
+    let info_hash = binary("info_hash").unwrap_or_default();
+
+    if let Some(offer) = message.get("offer") {
+        let (peer_id, offer_id) = match (binary("peer_id"), binary("offer_id")) {
+            (Some(peer_id), Some(offer_id)) => (peer_id, offer_id),
+            _ => return,
+        };
+        if let Some(answer) = handler.handle_offer(&info_hash, &peer_id, &offer_id, offer) {
+            let own_peer_id = connection.state.peer_id.lock().unwrap().clone();
+            let answer = json!({
+                "action": "announce",
+                "info_hash": to_binary_string(&info_hash),
+                "peer_id": to_binary_string(&own_peer_id),
+                "to_peer_id": to_binary_string(&peer_id),
+                "offer_id": to_binary_string(&offer_id),
+                "answer": answer,
+            });
+            connection
+                .outgoing
+                .send(Message::Text(answer.to_string()))
+                .unwrap_or(());
+        }
+        return;
+    }
+    if let Some(answer) = message.get("answer") {
+        if let (Some(peer_id), Some(offer_id)) = (binary("peer_id"), binary("offer_id")) {
+            handler.handle_answer(&info_hash, &peer_id, &offer_id, answer);
+        }
+        return;
+    }
+
+    let key = match message.get("action").and_then(Value::as_str) {
+        Some("scrape") => ("scrape", Vec::new()),
+        _ => ("announce", info_hash),
+    };
+    connection.answer(&key, Value::Object(message));
+}
+
+fn parse_announce_response(response: &Value) -> Result<TrackerResponse, TrackerError> {
+    if let Some(reason) = response.get("failure reason").and_then(Value::as_str) {
+        return Err(TrackerError::Failure(reason.to_string()));
+    }
+    let count = |key: &str| response.get(key).and_then(Value::as_u64).map(|n| n as u32);
+
+    Ok(TrackerResponse {
+        interval: count("interval").unwrap_or(DEFAULT_INTERVAL),
+        min_interval: count("min interval"),
+        complete: count("complete"),
+        incomplete: count("incomplete"),
+        warning: response
+            .get("warning message")
+            .and_then(Value::as_str)
+            .map(|warning| warning.to_string()),
+        tracker_id: None,
+        // browser peers are reached through the signaling handler
+        peer_list: Vec::new(),
+    })
+}
+
+fn parse_scrape_response(response: &Value) -> Result<ScrapeResponse, TrackerError> {
+    if let Some(reason) = response.get("failure reason").and_then(Value::as_str) {
+        return Err(TrackerError::Failure(reason.to_string()));
+    }
+
+    let mut files = HashMap::new();
+    if let Some(file_list) = response.get("files").and_then(Value::as_object) {
+        for (info_hash, stats) in file_list {
+            let info_hash = match from_binary_string(info_hash) {
+                Some(info_hash) => info_hash,
+                None => continue,
+            };
+            let count = |key: &str| stats.get(key).and_then(Value::as_u64).unwrap_or(0) as u32;
+            files.insert(
+                info_hash,
+                ScrapeFile {
+                    complete: count("complete"),
+                    downloaded: count("downloaded"),
+                    incomplete: count("incomplete"),
+                    name: None,
+                },
+            );
+        }
+    }
+
+    Ok(ScrapeResponse { files })
+}
+
+/// WebTorrent sends binary values as strings with one character per byte.
+fn to_binary_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|&byte| byte as char).collect()
+}
+
+fn from_binary_string(string: &str) -> Option<Vec<u8>> {
+    string
+        .chars()
+        .map(|c| {
+            if (c as u32) < 256 {
+                Some(c as u8)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utility::PeerId;
+
+    use tokio::net::TcpListener;
+
+    async fn listen() -> (TcpListener, Url) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("ws://{}/announce", listener.local_addr().unwrap())).unwrap();
+        (listener, url)
+    }
+
+    async fn accept(listener: &mut TcpListener) -> WebSocketStream<TcpStream> {
+        let (stream, _) = listener.accept().await.unwrap();
+        tokio_tungstenite::accept_async(stream).await.unwrap()
+    }
+
+    async fn next_request(socket: &mut WebSocketStream<TcpStream>) -> Value {
+        loop {
+            if let Message::Text(text) = socket.next().await.unwrap().unwrap() {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    /// An announce answer echoing the request's info hash.
+    fn announce_answer(request: &Value, complete: u32) -> Message {
+        let answer = json!({
+            "action": "announce",
+            "info_hash": request["info_hash"],
+            "interval": 300,
+            "complete": complete,
+            "incomplete": 1,
+        });
+        Message::Text(answer.to_string())
+    }
+
+    fn announce_request() -> AnnounceRequest {
+        AnnounceRequest::new(&[0xfe; 20], &mut PeerId::new())
+    }
+
+    #[tokio::test]
+    async fn announce_and_scrape() {
+        let (mut listener, url) = listen().await;
+        tokio::spawn(async move {
+            let mut socket = accept(&mut listener).await;
+            let request = next_request(&mut socket).await;
+            assert_eq!(request["action"], "announce");
+            assert_eq!(request["info_hash"], to_binary_string(&[0xfe; 20]));
+            socket.send(announce_answer(&request, 4)).await.unwrap();
+
+            let request = next_request(&mut socket).await;
+            assert_eq!(request["action"], "scrape");
+            let answer = json!({
+                "action": "scrape",
+                "files": {
+                    to_binary_string(&[0xfe; 20]): {"complete": 4, "downloaded": 9, "incomplete": 1},
+                },
+            });
+            socket
+                .send(Message::Text(answer.to_string()))
+                .await
+                .unwrap();
+            while socket.next().await.is_some() {}
+        });
+
+        let tracker = WebSocketTracker::new(Arc::new(NoSignaling));
+        let response = tracker.announce(&url, &announce_request()).await.unwrap();
+        assert_eq!(response.get_interval(), 300);
+        assert_eq!(response.complete, Some(4));
+        assert_eq!(response.incomplete, Some(1));
+
+        let scrape = tracker.scrape(&url, &[vec![0xfe; 20]]).await.unwrap();
+        assert_eq!(scrape.files[&vec![0xfe; 20]].downloaded, 9);
+    }
+
+    #[tokio::test]
+    async fn concurrent_announces_get_their_own_answer() {
+        let (mut listener, url) = listen().await;
+        tokio::spawn(async move {
+            let mut socket = accept(&mut listener).await;
+            let first = next_request(&mut socket).await;
+            let second = next_request(&mut socket).await;
+            socket.send(announce_answer(&first, 1)).await.unwrap();
+            socket.send(announce_answer(&second, 2)).await.unwrap();
+            while socket.next().await.is_some() {}
+        });
+
+        let tracker = WebSocketTracker::new(Arc::new(NoSignaling));
+        let request = announce_request();
+        // connect first so both announces share the connection
+        tracker.connection(&url).await.unwrap();
+        let (first, second) = futures_util::future::join(
+            tracker.announce(&url, &request),
+            tracker.announce(&url, &request),
+        )
+        .await;
+        assert_eq!(first.unwrap().complete, Some(1));
+        assert_eq!(second.unwrap().complete, Some(2));
+    }
+
+    #[tokio::test]
+    async fn closed_connections_are_dropped() {
+        let (mut listener, url) = listen().await;
+        tokio::spawn(async move {
+            let mut socket = accept(&mut listener).await;
+            let request = next_request(&mut socket).await;
+            socket.send(announce_answer(&request, 1)).await.unwrap();
+            socket.close(None).await.unwrap();
+
+            let mut socket = accept(&mut listener).await;
+            let request = next_request(&mut socket).await;
+            socket.send(announce_answer(&request, 2)).await.unwrap();
+            while socket.next().await.is_some() {}
+        });
+
+        let tracker = WebSocketTracker::new(Arc::new(NoSignaling));
+        let response = tracker.announce(&url, &announce_request()).await.unwrap();
+        assert_eq!(response.complete, Some(1));
+
+        let mut waited = Duration::from_secs(0);
+        while !tracker.connections.lock().unwrap().is_empty() {
+            assert!(waited < Duration::from_secs(5));
+            time::delay_for(Duration::from_millis(20)).await;
+            waited += Duration::from_millis(20);
+        }
+
+        let response = tracker.announce(&url, &announce_request()).await.unwrap();
+        assert_eq!(response.complete, Some(2));
+    }
+
+    /// Accepts connections one after another, answering every announce.
+    async fn serve_announces(mut listener: TcpListener, accepted: Arc<AtomicU64>) {
+        loop {
+            let mut socket = accept(&mut listener).await;
+            let complete = accepted.fetch_add(1, Ordering::SeqCst) as u32 + 1;
+            tokio::spawn(async move {
+                while let Some(Ok(Message::Text(text))) = socket.next().await {
+                    let request: Value = serde_json::from_str(&text).unwrap();
+                    if socket
+                        .send(announce_answer(&request, complete))
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            });
+        }
+    }
+
+    #[tokio::test]
+    async fn connections_of_a_stopped_runtime_are_replaced() {
+        let (listener, url) = listen().await;
+        let accepted = Arc::new(AtomicU64::new(0));
+        tokio::spawn(serve_announces(listener, Arc::clone(&accepted)));
+
+        let tracker = Arc::new(WebSocketTracker::new(Arc::new(NoSignaling)));
+        let (other_tracker, other_url) = (Arc::clone(&tracker), url.clone());
+        // the socket task dies with the runtime it was spawned on
+        tokio::task::spawn_blocking(move || {
+            let mut runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async {
+                let request = announce_request();
+                let response = other_tracker.announce(&other_url, &request).await;
+                assert_eq!(response.unwrap().complete, Some(1));
+            });
+        })
+        .await
+        .unwrap();
+        assert_eq!(tracker.connections.lock().unwrap().len(), 1);
+
+        assert!(tracker.announce(&url, &announce_request()).await.is_err());
+        assert!(tracker.connections.lock().unwrap().is_empty());
+        let response = tracker.announce(&url, &announce_request()).await.unwrap();
+        assert_eq!(response.complete, Some(2));
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_share_a_new_connection() {
+        let (listener, url) = listen().await;
+        let accepted = Arc::new(AtomicU64::new(0));
+        tokio::spawn(serve_announces(listener, Arc::clone(&accepted)));
+
+        let tracker = WebSocketTracker::new(Arc::new(NoSignaling));
+        let (first, second) =
+            futures_util::future::join(tracker.connection(&url), tracker.connection(&url)).await;
+        assert!(Arc::ptr_eq(&first.unwrap().state, &second.unwrap().state));
+
+        let request = announce_request();
+        let (first, second) = futures_util::future::join(
+            tracker.announce(&url, &request),
+            tracker.announce(&url, &request),
+        )
+        .await;
+        assert_eq!(first.unwrap().complete, Some(1));
+        assert_eq!(second.unwrap().complete, Some(1));
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+}
@@ -31,6 +31,7 @@ pub fn to_vec<T: Clone>(data: &[T]) -> Vec<T> {
     data.iter().cloned().collect()
 }
 
+#[derive(Clone, Debug)]
 pub struct PeerId(Option<String>);
 
 impl PeerId {